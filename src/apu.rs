@@ -0,0 +1,423 @@
+mod envelope;
+mod length;
+mod noise;
+mod registers;
+mod square;
+mod wave;
+
+use crate::cpu::{CPU_CLOCK_SPEED, CycleType};
+use crate::error::{Error, Result};
+use crate::memory::Memory;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use self::noise::NoiseChannel;
+use self::registers::*;
+use self::square::SquareChannel;
+use self::wave::WaveChannel;
+
+/// The default output sample rate, in Hz.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The frame sequencer is clocked at 512 Hz (4194304 Hz / 8192).
+const FRAME_SEQUENCER_PERIOD: CycleType = 8192;
+
+/// The maximum duration of audio buffered while waiting for the host to
+/// retrieve it, in seconds. Older samples are dropped past this limit.
+const MAX_BUFFERED_SECONDS: usize = 1;
+
+/// The structure holding and emulating the Audio Processing Unit state.
+///
+/// The APU mixes 4 channels: 2 square channels (the first one with a
+/// frequency sweep), a wave channel and a noise channel. They are then routed
+/// to the left and/or right output terminals according to NR51, and scaled by
+/// the master volume of NR50.
+///
+/// Time durations are expressed in CPU clock cycles with a CPU clock speed of
+/// 4194304 Hz.
+///
+/// See: https://gbdev.io/pandocs/Audio.html
+pub struct Apu {
+    /// Bit 7 of NR52: when cleared, all sound circuits are powered off.
+    enabled: bool,
+    /// Channel 1: square wave with frequency sweep.
+    channel1: SquareChannel,
+    /// Channel 2: square wave.
+    channel2: SquareChannel,
+    /// Channel 3: custom wave.
+    channel3: WaveChannel,
+    /// Channel 4: noise.
+    channel4: NoiseChannel,
+    /// Master volume and Vin panning (NR50).
+    master_volume: u8,
+    /// Sound panning (NR51).
+    panning: u8,
+    /// Clock cycles spent since the last frame sequencer step.
+    frame_sequencer_clock: CycleType,
+    /// The next frame sequencer step (0-7).
+    frame_sequencer_step: u8,
+    /// The output sample rate, in Hz.
+    sample_rate: u32,
+    /// Fractional sample clock: a sample is output each time it reaches the
+    /// CPU clock speed, being incremented by `sample_rate` every clock cycle.
+    sample_clock: u64,
+    /// High-pass filter state for the left and right terminals, emulating
+    /// the capacitors removing the DC offset of the DACs.
+    capacitors: [f32; 2],
+    /// The output samples, interleaved as left/right pairs.
+    buffer: Vec<f32>,
+}
+
+impl Apu {
+    /// Create and return a new 'Apu' instance, outputting stereo samples at
+    /// the given rate.
+    pub fn new(sample_rate: u32) -> Apu {
+        Apu {
+            enabled: false,
+            channel1: SquareChannel::new(true),
+            channel2: SquareChannel::new(false),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            master_volume: 0x00,
+            panning: 0x00,
+            frame_sequencer_clock: 0,
+            frame_sequencer_step: 0,
+            sample_rate,
+            sample_clock: 0,
+            capacitors: [0.0; 2],
+            buffer: Vec::new(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Change the output sample rate, discarding any pending sample.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_clock = 0;
+        self.buffer.clear();
+    }
+
+    /// Return all the samples produced since the last call, interleaved as
+    /// left/right pairs in the [-1.0, 1.0] range.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Advance the APU simulation forward by the given amount of clock ticks.
+    pub fn step(&mut self, ticks: CycleType) {
+        let mut ticks = ticks;
+        while ticks > 0 {
+            // advance up to the next output sample
+            let clock_speed = CPU_CLOCK_SPEED as u64;
+            let rate = self.sample_rate.max(1) as u64;
+            let until_sample = (clock_speed - self.sample_clock).div_ceil(rate);
+            let chunk = ticks.min(until_sample.max(1));
+            ticks -= chunk;
+
+            if self.enabled {
+                self.channel1.step(chunk);
+                self.channel2.step(chunk);
+                self.channel3.step(chunk);
+                self.channel4.step(chunk);
+                self.frame_sequencer_clock += chunk;
+                while self.frame_sequencer_clock >= FRAME_SEQUENCER_PERIOD {
+                    self.frame_sequencer_clock -= FRAME_SEQUENCER_PERIOD;
+                    self.step_frame_sequencer();
+                }
+            }
+
+            self.sample_clock += chunk * rate;
+            if self.sample_clock >= clock_speed {
+                self.sample_clock -= clock_speed;
+                self.output_sample();
+            }
+        }
+    }
+
+    /// Clock the length counters at 256 Hz, the sweep unit at 128 Hz and the
+    /// volume envelopes at 64 Hz.
+    fn step_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        if step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// Mix the channels and push the resulting stereo sample to the buffer.
+    fn output_sample(&mut self) {
+        let (mut left, mut right) = (0.0, 0.0);
+        if self.enabled {
+            let outputs = [
+                dac_output(self.channel1.dac_enabled(), self.channel1.output()),
+                dac_output(self.channel2.dac_enabled(), self.channel2.output()),
+                dac_output(self.channel3.dac_enabled(), self.channel3.output()),
+                dac_output(self.channel4.dac_enabled(), self.channel4.output()),
+            ];
+            for (i, output) in outputs.iter().enumerate() {
+                if self.panning & (0x10 << i) != 0 {
+                    left += output;
+                }
+                if self.panning & (0x01 << i) != 0 {
+                    right += output;
+                }
+            }
+            // NR50 volumes go from 0 (1/8) to 7 (8/8)
+            let left_volume = (((self.master_volume >> 4) & 0x07) + 1) as f32 / 8.0;
+            let right_volume = ((self.master_volume & 0x07) + 1) as f32 / 8.0;
+            left *= left_volume / 4.0;
+            right *= right_volume / 4.0;
+        }
+
+        let charge_factor = 0.999958f32.powf(CPU_CLOCK_SPEED as f32 / self.sample_rate as f32);
+        let left = self.high_pass(0, left, charge_factor);
+        let right = self.high_pass(1, right, charge_factor);
+
+        // drop the oldest half of the buffer at once to keep this cheap
+        let max_samples = self.sample_rate as usize * 2 * MAX_BUFFERED_SECONDS;
+        if self.buffer.len() >= max_samples {
+            // (keeping the left/right pairs aligned)
+            self.buffer.drain(..(max_samples / 2) & !0x01);
+        }
+        self.buffer.push(left);
+        self.buffer.push(right);
+    }
+
+    fn high_pass(&mut self, terminal: usize, input: f32, charge_factor: f32) -> f32 {
+        let output = input - self.capacitors[terminal];
+        self.capacitors[terminal] = input - output * charge_factor;
+        output
+    }
+
+    /// Power the APU on or off. When powered off, all the sound registers
+    /// (except the wave RAM) are cleared and become read-only.
+    fn set_enabled(&mut self, enabled: bool) {
+        if self.enabled && !enabled {
+            for address in NR10..NR52 {
+                self.write_register(address, 0x00);
+            }
+        } else if !self.enabled && enabled {
+            self.frame_sequencer_clock = 0;
+            self.frame_sequencer_step = 0;
+        }
+        self.enabled = enabled;
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            NR10..=NR14 => self.channel1.write_register(address - NR10, value),
+            NR20..=NR24 => self.channel2.write_register(address - NR20, value),
+            NR30..=NR34 => self.channel3.write_register(address - NR30, value),
+            NR40..=NR44 => self.channel4.write_register(address - NR40, value),
+            NR50 => self.master_volume = value,
+            NR51 => self.panning = value,
+            _ => {}
+        }
+    }
+}
+
+/// Convert the digital output (0-15) of a channel into an analog value in the
+/// [-1.0, 1.0] range. A disabled DAC outputs nothing.
+fn dac_output(dac_enabled: bool, output: u8) -> f32 {
+    if dac_enabled {
+        (output as f32 / 7.5) - 1.0
+    } else {
+        0.0
+    }
+}
+
+impl Memory for Apu {
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let value = match address {
+            NR10..=NR14 => self.channel1.read_register(address - NR10),
+            NR20..=NR24 => self.channel2.read_register(address - NR20),
+            NR30..=NR34 => self.channel3.read_register(address - NR30),
+            NR40..=NR44 => self.channel4.read_register(address - NR40),
+            NR50 => self.master_volume,
+            NR51 => self.panning,
+            NR52 => {
+                (self.enabled as u8) << 7
+                    | (self.channel4.enabled() as u8) << 3
+                    | (self.channel3.enabled() as u8) << 2
+                    | (self.channel2.enabled() as u8) << 1
+                    | (self.channel1.enabled() as u8)
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                return self.channel3.read_ram((address - WAVE_RAM_START) as usize);
+            }
+            0xFF27..=0xFF2F => 0x00,
+            _ => unreachable!("Apu.read_byte(address={:0>4X}) read overflow", address),
+        };
+        value | READ_MASKS[(address - NR10) as usize]
+    }
+
+    fn write_byte(&mut self, address: u16, byte: u8) {
+        match address {
+            NR52 => self.set_enabled(byte & 0x80 == 0x80),
+            WAVE_RAM_START..=WAVE_RAM_END => self
+                .channel3
+                .write_ram((address - WAVE_RAM_START) as usize, byte),
+            // the other registers are read-only while powered off
+            _ if !self.enabled => {}
+            NR10..=NR51 => self.write_register(address, byte),
+            0xFF27..=0xFF2F => {}
+            _ => unreachable!(
+                "Apu.write_byte(address={:0>4X} byte={:0>2X}) write overflow",
+                address, byte
+            ),
+        }
+    }
+}
+
+/// The output sample rate depends on the host and is not part of the state,
+/// while the samples not yet retrieved are discarded on load.
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.panning);
+        writer.write_u64(self.frame_sequencer_clock);
+        writer.write_u8(self.frame_sequencer_step);
+        writer.write_u64(self.sample_clock);
+        writer.write_f32(self.capacitors[0]);
+        writer.write_f32(self.capacitors[1]);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.master_volume = reader.read_u8()?;
+        self.panning = reader.read_u8()?;
+        self.frame_sequencer_clock = reader.read_u64()?;
+        self.frame_sequencer_step = reader.read_u8()? % 8;
+        self.sample_clock = reader.read_u64()?;
+        if self.sample_clock >= CPU_CLOCK_SPEED as u64 {
+            return Err(Error::BadSaveState(format!(
+                "invalid APU sample clock {}",
+                self.sample_clock
+            )));
+        }
+        self.capacitors[0] = reader.read_f32()?;
+        self.capacitors[1] = reader.read_f32()?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::registers::*;
+    use super::{Apu, DEFAULT_SAMPLE_RATE, FRAME_SEQUENCER_PERIOD};
+    use crate::cpu::CPU_CLOCK_SPEED;
+    use crate::memory::Memory;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new(DEFAULT_SAMPLE_RATE);
+        apu.write_byte(NR52, 0x80);
+        apu
+    }
+
+    #[test]
+    fn test_apu_register_read_masks() {
+        let mut apu = powered_apu();
+        for address in NR10..=0xFF2F {
+            if address != NR52 {
+                apu.write_byte(address, 0x00);
+            }
+        }
+        assert_eq!(apu.read_byte(NR10), 0x80);
+        assert_eq!(apu.read_byte(0xFF11), 0x3F);
+        assert_eq!(apu.read_byte(0xFF13), 0xFF);
+        assert_eq!(apu.read_byte(NR20), 0xFF);
+        assert_eq!(apu.read_byte(NR30), 0x7F);
+        assert_eq!(apu.read_byte(NR34), 0xBF);
+        assert_eq!(apu.read_byte(NR52), 0xF0);
+        assert_eq!(apu.read_byte(0xFF27), 0xFF);
+    }
+
+    #[test]
+    fn test_apu_power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.write_byte(NR50, 0x77);
+        apu.write_byte(NR51, 0xF3);
+        apu.write_byte(WAVE_RAM_START, 0xAB);
+        apu.write_byte(NR52, 0x00);
+        assert_eq!(apu.read_byte(NR50), 0x00);
+        assert_eq!(apu.read_byte(NR51), 0x00);
+        assert_eq!(apu.read_byte(NR52), 0x70);
+        // writes are ignored while powered off, except for the wave RAM
+        apu.write_byte(NR50, 0x77);
+        assert_eq!(apu.read_byte(NR50), 0x00);
+        assert_eq!(apu.read_byte(WAVE_RAM_START), 0xAB);
+    }
+
+    #[test]
+    fn test_apu_channel_status() {
+        let mut apu = powered_apu();
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(NR14, 0x80);
+        apu.write_byte(NR30, 0x80);
+        apu.write_byte(NR34, 0x80);
+        assert_eq!(apu.read_byte(NR52), 0xF5);
+    }
+
+    #[test]
+    fn test_apu_length_counter_disables_channel() {
+        let mut apu = powered_apu();
+        apu.write_byte(0xFF21, 0xF0);
+        apu.write_byte(0xFF20, 0x3E); // length of 2
+        apu.write_byte(NR44, 0xC0);
+        assert_eq!(apu.read_byte(NR52) & 0x08, 0x08);
+        // the length counters are clocked every other frame sequencer step
+        apu.step(FRAME_SEQUENCER_PERIOD * 2);
+        assert_eq!(apu.read_byte(NR52) & 0x08, 0x08);
+        apu.step(FRAME_SEQUENCER_PERIOD);
+        assert_eq!(apu.read_byte(NR52) & 0x08, 0x00);
+    }
+
+    #[test]
+    fn test_apu_sample_rate() {
+        let mut apu = powered_apu();
+        apu.step(CPU_CLOCK_SPEED as u64);
+        assert_eq!(apu.take_samples().len(), 2 * DEFAULT_SAMPLE_RATE as usize);
+        assert!(apu.take_samples().is_empty());
+
+        apu.set_sample_rate(48_000);
+        apu.step(CPU_CLOCK_SPEED as u64);
+        assert_eq!(apu.take_samples().len(), 2 * 48_000);
+    }
+
+    #[test]
+    fn test_apu_panning() {
+        let mut apu = powered_apu();
+        apu.write_byte(NR50, 0x77);
+        apu.write_byte(NR51, 0x10); // channel 1 on the left terminal only
+        apu.write_byte(0xFF11, 0xC0); // 75% duty
+        apu.write_byte(0xFF12, 0xF0);
+        apu.write_byte(NR14, 0x87);
+        apu.step(CPU_CLOCK_SPEED as u64 / 100);
+        let samples = apu.take_samples();
+        assert!(samples.chunks(2).any(|pair| pair[0] != 0.0));
+        assert!(samples.chunks(2).all(|pair| pair[1] == 0.0));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/// Volume envelope shared by the square and noise channels, controlled by
+/// their NRx2 register:
+///
+/// - Bits 7-4 : initial volume (0-15)
+/// - Bit  3   : direction (0 = decrease, 1 = increase)
+/// - Bits 2-0 : period in 64 Hz frame sequencer ticks (0 = stopped)
+#[derive(Clone, Default)]
+pub struct VolumeEnvelope {
+    /// The raw NRx2 value.
+    raw: u8,
+    /// The current volume (0-15).
+    volume: u8,
+    /// Frame sequencer ticks left before the next volume change.
+    timer: u8,
+}
+
+impl VolumeEnvelope {
+    pub fn raw(&self) -> u8 {
+        self.raw
+    }
+    pub fn set(&mut self, value: u8) {
+        self.raw = value;
+    }
+
+    /// The channel's DAC is powered as long as the upper 5 bits of NRx2 are
+    /// not all cleared.
+    pub fn dac_enabled(&self) -> bool {
+        self.raw & 0xF8 != 0x00
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    fn period(&self) -> u8 {
+        self.raw & 0x07
+    }
+
+    /// Must be called when the associated channel is triggered.
+    pub fn trigger(&mut self) {
+        self.volume = self.raw >> 4;
+        self.timer = self.period();
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period();
+            let increase = self.raw & 0x08 == 0x08;
+            if increase && self.volume < 0x0F {
+                self.volume += 1;
+            } else if !increase && self.volume > 0x00 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+impl SaveState for VolumeEnvelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.raw);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.raw = reader.read_u8()?;
+        self.volume = reader.read_u8()? & 0x0F;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::VolumeEnvelope;
+
+    #[test]
+    fn test_envelope_dac_enabled() {
+        let mut envelope = VolumeEnvelope::default();
+        assert!(!envelope.dac_enabled());
+        envelope.set(0x07);
+        assert!(!envelope.dac_enabled());
+        envelope.set(0x08);
+        assert!(envelope.dac_enabled());
+        envelope.set(0x10);
+        assert!(envelope.dac_enabled());
+    }
+
+    #[test]
+    fn test_envelope_decrease() {
+        let mut envelope = VolumeEnvelope::default();
+        envelope.set(0x21); // volume 2, decrease, period 1
+        envelope.trigger();
+        assert_eq!(envelope.volume(), 2);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 1);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 0);
+    }
+
+    #[test]
+    fn test_envelope_increase_with_period() {
+        let mut envelope = VolumeEnvelope::default();
+        envelope.set(0xEA); // volume 14, increase, period 2
+        envelope.trigger();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 14);
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+    }
+}
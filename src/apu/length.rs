@@ -0,0 +1,104 @@
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/// Length counter, disabling its channel once it reaches zero when enabled.
+///
+/// Clocked at 256 Hz by the frame sequencer.
+#[derive(Clone)]
+pub struct LengthCounter {
+    /// The maximum length: 64 for all channels except the wave one (256).
+    max: u16,
+    /// The remaining length.
+    counter: u16,
+    /// Bit 6 of the NRx4 register.
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    /// Reload the counter from the length data written to NRx1.
+    pub fn load(&mut self, length_data: u8) {
+        self.counter = self.max - (length_data as u16 & (self.max - 1));
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// Must be called when the associated channel is triggered.
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    /// Return true if the counter just expired, meaning that the channel
+    /// must be disabled.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+/// The maximum length is fixed by the channel, and so not part of the state.
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.counter as usize);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.counter = reader.read_usize(self.max as usize + 1)? as u16;
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LengthCounter;
+
+    #[test]
+    fn test_length_counter_expiry() {
+        let mut length = LengthCounter::new(64);
+        length.load(62);
+        length.set_enabled(true);
+        assert!(!length.clock());
+        assert!(length.clock());
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn test_length_counter_disabled_does_not_count() {
+        let mut length = LengthCounter::new(64);
+        length.load(63);
+        assert!(!length.clock());
+        length.set_enabled(true);
+        assert!(length.clock());
+    }
+
+    #[test]
+    fn test_length_counter_trigger_reloads_when_zero() {
+        let mut length = LengthCounter::new(256);
+        length.set_enabled(true);
+        length.trigger();
+        for _ in 0..255 {
+            assert!(!length.clock());
+        }
+        assert!(length.clock());
+    }
+}
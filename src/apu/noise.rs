@@ -0,0 +1,188 @@
+use crate::cpu::CycleType;
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::envelope::VolumeEnvelope;
+use super::length::LengthCounter;
+
+/// The base divisors selected with bits 2-0 of NR43.
+const DIVISORS: [CycleType; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Noise channel (channel 4), outputting the state of a linear feedback
+/// shift register (LFSR).
+///
+/// The NR41 to NR44 registers are mapped as following:
+///
+/// - NR41 : bits 5-0 length data (write-only)
+/// - NR42 : volume envelope
+/// - NR43 : bits 7-4 clock shift, bit 3 width mode (1 = 7 bits), bits 2-0 divisor code
+/// - NR44 : bit 7 trigger, bit 6 length enable
+pub struct NoiseChannel {
+    /// Is the channel currently playing ?
+    enabled: bool,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+    /// The raw NR43 value.
+    polynomial: u8,
+    /// The 15-bit linear feedback shift register.
+    lfsr: u16,
+    /// Clock cycles spent since the last LFSR shift.
+    timer: CycleType,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::default(),
+            polynomial: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Read the NR4N register where N is the given index (0-4).
+    /// Write-only bits are handled by the caller.
+    pub fn read_register(&self, index: u16) -> u8 {
+        match index {
+            0 | 1 => 0x00,
+            2 => self.envelope.raw(),
+            3 => self.polynomial,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => unreachable!("apu::NoiseChannel.read_register({}) overflow", index),
+        }
+    }
+
+    /// Write to the NR4N register where N is the given index (0-4).
+    pub fn write_register(&mut self, index: u16, value: u8) {
+        match index {
+            0 => {}
+            1 => self.length.load(value & 0x3F),
+            2 => {
+                self.envelope.set(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.polynomial = value,
+            4 => {
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                    self.timer = 0;
+                }
+            }
+            _ => unreachable!("apu::NoiseChannel.write_register({}) overflow", index),
+        }
+    }
+
+    /// Advance the channel's frequency timer by the given amount of clock cycles.
+    pub fn step(&mut self, ticks: CycleType) {
+        let period = DIVISORS[(self.polynomial & 0x07) as usize] << (self.polynomial >> 4);
+        self.timer += ticks;
+        while self.timer >= period {
+            self.timer -= period;
+            self.shift_lfsr();
+        }
+    }
+
+    fn shift_lfsr(&mut self) {
+        let bit = (self.lfsr & 0x01) ^ ((self.lfsr >> 1) & 0x01);
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        // 7-bit width mode
+        if self.polynomial & 0x08 == 0x08 {
+            self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+        }
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// The current digital output (0-15).
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 0x01 == 0x01 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.polynomial);
+        writer.write_u16(self.lfsr);
+        writer.write_u64(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.polynomial = reader.read_u8()?;
+        self.lfsr = reader.read_u16()? & 0x7FFF;
+        self.timer = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::NoiseChannel;
+
+    #[test]
+    fn test_noise_lfsr_sequence() {
+        let mut channel = NoiseChannel::new();
+        channel.write_register(2, 0xF0);
+        channel.write_register(4, 0x80);
+        assert_eq!(channel.lfsr, 0x7FFF);
+        // 0x7FFF: bits 0 and 1 are equal so a 0 is shifted in at bit 14
+        channel.step(8);
+        assert_eq!(channel.lfsr, 0x3FFF);
+        channel.step(8);
+        assert_eq!(channel.lfsr, 0x1FFF);
+    }
+
+    #[test]
+    fn test_noise_lfsr_width_mode() {
+        let mut channel = NoiseChannel::new();
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0x08);
+        channel.write_register(4, 0x80);
+        channel.step(8);
+        assert_eq!(channel.lfsr, 0x3FBF);
+    }
+
+    #[test]
+    fn test_noise_output_follows_lfsr() {
+        let mut channel = NoiseChannel::new();
+        channel.write_register(2, 0xA0);
+        channel.write_register(4, 0x80);
+        // bit 0 of the LFSR is set: the output is inverted
+        assert_eq!(channel.output(), 0);
+        channel.lfsr = 0x7FFE;
+        assert_eq!(channel.output(), 0x0A);
+    }
+}
@@ -0,0 +1,26 @@
+pub const NR10: u16 = 0xFF10; // Channel 1 Sweep
+pub const NR14: u16 = 0xFF14; // Channel 1 Frequency high
+pub const NR20: u16 = 0xFF15; // not used
+pub const NR24: u16 = 0xFF19; // Channel 2 Frequency high
+pub const NR30: u16 = 0xFF1A; // Channel 3 DAC enable
+pub const NR34: u16 = 0xFF1E; // Channel 3 Frequency high
+pub const NR40: u16 = 0xFF1F; // not used
+pub const NR44: u16 = 0xFF23; // Channel 4 Counter/consecutive ; Initial
+pub const NR50: u16 = 0xFF24; // Channel control / ON-OFF / Volume
+pub const NR51: u16 = 0xFF25; // Selection of Sound output terminal
+pub const NR52: u16 = 0xFF26; // Sound on/off
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+/// The bits always read as 1 for each register in 0xFF10...0xFF2F, since
+/// most of the sound registers are (at least partially) write-only.
+///
+/// Source: https://gbdev.gg8.se/wiki/articles/Gameboy_sound_hardware#Register_Reading
+pub const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, // unused
+];
@@ -0,0 +1,344 @@
+use crate::cpu::CycleType;
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::envelope::VolumeEnvelope;
+use super::length::LengthCounter;
+
+/// The 4 waveforms a square channel can play, selected with bits 7-6 of NRx1.
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+/// Frequency sweep unit, only available on channel 1 through NR10:
+///
+/// - Bits 6-4 : sweep period in 128 Hz frame sequencer ticks
+/// - Bit  3   : direction (0 = increase, 1 = decrease)
+/// - Bits 2-0 : number of sweep shifts
+#[derive(Clone, Default)]
+pub struct Sweep {
+    /// The raw NR10 value.
+    raw: u8,
+    /// Internal enabled flag, set on trigger.
+    enabled: bool,
+    /// Frame sequencer ticks left before the next sweep iteration.
+    timer: u8,
+    /// Copy of the channel frequency the sweep operates on.
+    shadow_frequency: u16,
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.raw >> 4) & 0x07
+    }
+    fn negate(&self) -> bool {
+        self.raw & 0x08 == 0x08
+    }
+    fn shift(&self) -> u8 {
+        self.raw & 0x07
+    }
+
+    /// The sweep timer treats a period of 0 as 8.
+    fn reload_timer(&mut self) {
+        self.timer = match self.period() {
+            0 => 8,
+            n => n,
+        };
+    }
+
+    /// Compute the next frequency from the shadow frequency.
+    fn next_frequency(&self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift();
+        if self.negate() {
+            self.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+/// Square wave channel, used for both channel 1 (with a frequency sweep) and
+/// channel 2 (without).
+///
+/// The NRx1 to NRx4 registers are mapped as following:
+///
+/// - NRx1 : bits 7-6 wave duty, bits 5-0 length data (write-only)
+/// - NRx2 : volume envelope
+/// - NRx3 : frequency lower 8 bits (write-only)
+/// - NRx4 : bit 7 trigger, bit 6 length enable, bits 2-0 frequency higher 3 bits
+pub struct SquareChannel {
+    /// Is the channel currently playing ?
+    enabled: bool,
+    /// The frequency sweep unit (channel 1 only).
+    sweep: Option<Sweep>,
+    /// The selected duty pattern (0-3).
+    duty: u8,
+    /// The current position in the duty pattern (0-7).
+    duty_step: usize,
+    length: LengthCounter,
+    envelope: VolumeEnvelope,
+    /// The 11-bit frequency value.
+    frequency: u16,
+    /// Clock cycles spent since the last duty step.
+    timer: CycleType,
+}
+
+impl SquareChannel {
+    pub fn new(with_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            sweep: if with_sweep {
+                Some(Sweep::default())
+            } else {
+                None
+            },
+            duty: 0,
+            duty_step: 0,
+            length: LengthCounter::new(64),
+            envelope: VolumeEnvelope::default(),
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Read the NRxN register where N is the given index (0-4).
+    /// Write-only bits are handled by the caller.
+    pub fn read_register(&self, index: u16) -> u8 {
+        match index {
+            0 => self.sweep.as_ref().map_or(0xFF, |sweep| sweep.raw),
+            1 => self.duty << 6,
+            2 => self.envelope.raw(),
+            3 => 0x00,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => unreachable!("apu::SquareChannel.read_register({}) overflow", index),
+        }
+    }
+
+    /// Write to the NRxN register where N is the given index (0-4).
+    pub fn write_register(&mut self, index: u16, value: u8) {
+        match index {
+            0 => {
+                if let Some(ref mut sweep) = self.sweep {
+                    sweep.raw = value;
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.set(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.trigger();
+                }
+            }
+            _ => unreachable!("apu::SquareChannel.write_register({}) overflow", index),
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = 0;
+        let frequency = self.frequency;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.shadow_frequency = frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // overflow check
+            if sweep.shift() != 0 && sweep.next_frequency() > 0x07FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Advance the channel's frequency timer by the given amount of clock cycles.
+    pub fn step(&mut self, ticks: CycleType) {
+        let period = (0x0800 - self.frequency as CycleType) * 4;
+        self.timer += ticks;
+        while self.timer >= period {
+            self.timer -= period;
+            self.duty_step = (self.duty_step + 1) % 8;
+        }
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Clocked at 128 Hz by the frame sequencer.
+    pub fn clock_sweep(&mut self) {
+        let sweep = match self.sweep {
+            Some(ref mut sweep) => sweep,
+            None => return,
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let new_frequency = sweep.next_frequency();
+        if new_frequency > 0x07FF {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow_frequency = new_frequency;
+            self.frequency = new_frequency;
+            // the new frequency is immediately checked again for overflow
+            if sweep.next_frequency() > 0x07FF {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// The current digital output (0-15).
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_step] * self.envelope.volume()
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.raw);
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.timer);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.raw = reader.read_u8()?;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u8()?;
+        self.shadow_frequency = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        if let Some(ref sweep) = self.sweep {
+            sweep.save_state(writer);
+        }
+        writer.write_u8(self.duty);
+        writer.write_usize(self.duty_step);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u16(self.frequency);
+        writer.write_u64(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        if let Some(ref mut sweep) = self.sweep {
+            sweep.load_state(reader)?;
+        }
+        self.duty = reader.read_u8()? & 0x03;
+        self.duty_step = reader.read_usize(8)?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SquareChannel;
+
+    #[test]
+    fn test_square_trigger_needs_dac() {
+        let mut channel = SquareChannel::new(false);
+        channel.write_register(4, 0x80);
+        assert!(!channel.enabled());
+        channel.write_register(2, 0xF0);
+        channel.write_register(4, 0x80);
+        assert!(channel.enabled());
+        // turning the DAC off disables the channel
+        channel.write_register(2, 0x00);
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn test_square_duty_waveform() {
+        let mut channel = SquareChannel::new(false);
+        channel.write_register(1, 0x80); // 50%
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0xFF);
+        channel.write_register(4, 0x87); // frequency 0x7FF: period of 4 clocks
+        let mut waveform = vec![];
+        for _ in 0..8 {
+            waveform.push(channel.output());
+            channel.step(4);
+        }
+        assert_eq!(waveform, vec![15, 0, 0, 0, 0, 15, 15, 15]);
+    }
+
+    #[test]
+    fn test_square_sweep_overflow_on_trigger() {
+        let mut channel = SquareChannel::new(true);
+        channel.write_register(0, 0x11); // period 1, increase, shift 1
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0x00);
+        channel.write_register(4, 0x86); // frequency 0x600 + 0x300 > 0x7FF
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn test_square_sweep_updates_frequency() {
+        let mut channel = SquareChannel::new(true);
+        channel.write_register(0, 0x12); // period 1, increase, shift 2
+        channel.write_register(2, 0xF0);
+        channel.write_register(3, 0x00);
+        channel.write_register(4, 0x84); // frequency 0x400
+        assert!(channel.enabled());
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x500);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x640);
+        channel.clock_sweep(); // 0x640 + 0x190 = 0x7D0, next one overflows
+        assert!(!channel.enabled());
+    }
+
+    #[test]
+    fn test_square_no_sweep_register_on_channel_2() {
+        let mut channel = SquareChannel::new(false);
+        channel.write_register(0, 0x77);
+        assert_eq!(channel.read_register(0), 0xFF);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::cpu::CycleType;
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::length::LengthCounter;
+
+/// The size of the wave pattern RAM (0xFF30...0xFF3F), holding 32 4-bit samples.
+pub const WAVE_RAM_SIZE: usize = 0x10;
+
+/// Wave channel (channel 3), playing back the 4-bit samples stored in the
+/// wave pattern RAM.
+///
+/// The NR30 to NR34 registers are mapped as following:
+///
+/// - NR30 : bit 7 DAC power
+/// - NR31 : length data (write-only)
+/// - NR32 : bits 6-5 output level (0 = mute, 1 = 100%, 2 = 50%, 3 = 25%)
+/// - NR33 : frequency lower 8 bits (write-only)
+/// - NR34 : bit 7 trigger, bit 6 length enable, bits 2-0 frequency higher 3 bits
+pub struct WaveChannel {
+    /// Is the channel currently playing ?
+    enabled: bool,
+    /// Bit 7 of NR30.
+    dac_enabled: bool,
+    length: LengthCounter,
+    /// Bits 6-5 of NR32.
+    output_level: u8,
+    /// The 11-bit frequency value.
+    frequency: u16,
+    /// Clock cycles spent since the last sample step.
+    timer: CycleType,
+    /// The current sample index in the wave RAM (0-31).
+    position: usize,
+    /// The wave pattern RAM.
+    ram: [u8; WAVE_RAM_SIZE],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            output_level: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            ram: [0x00; WAVE_RAM_SIZE],
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    /// Read the NR3N register where N is the given index (0-4).
+    /// Write-only bits are handled by the caller.
+    pub fn read_register(&self, index: u16) -> u8 {
+        match index {
+            0 => (self.dac_enabled as u8) << 7,
+            1 | 3 => 0x00,
+            2 => self.output_level << 5,
+            4 => (self.length.enabled() as u8) << 6,
+            _ => unreachable!("apu::WaveChannel.read_register({}) overflow", index),
+        }
+    }
+
+    /// Write to the NR3N register where N is the given index (0-4).
+    pub fn write_register(&mut self, index: u16, value: u8) {
+        match index {
+            0 => {
+                self.dac_enabled = value & 0x80 == 0x80;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.output_level = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.set_enabled(value & 0x40 == 0x40);
+                if value & 0x80 == 0x80 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = 0;
+                    self.position = 0;
+                }
+            }
+            _ => unreachable!("apu::WaveChannel.write_register({}) overflow", index),
+        }
+    }
+
+    pub fn read_ram(&self, index: usize) -> u8 {
+        self.ram[index]
+    }
+    pub fn write_ram(&mut self, index: usize, value: u8) {
+        self.ram[index] = value;
+    }
+
+    /// Advance the channel's frequency timer by the given amount of clock cycles.
+    pub fn step(&mut self, ticks: CycleType) {
+        let period = (0x0800 - self.frequency as CycleType) * 2;
+        self.timer += ticks;
+        while self.timer >= period {
+            self.timer -= period;
+            self.position = (self.position + 1) % (WAVE_RAM_SIZE * 2);
+        }
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer.
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// The current digital output (0-15).
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.output_level == 0 {
+            return 0;
+        }
+        let byte = self.ram[self.position / 2];
+        // the upper nibble is played first
+        let sample = if self.position.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        sample >> (self.output_level - 1)
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.write_u8(self.output_level);
+        writer.write_u16(self.frequency);
+        writer.write_u64(self.timer);
+        writer.write_usize(self.position);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.output_level = reader.read_u8()? & 0x03;
+        self.frequency = reader.read_u16()? & 0x07FF;
+        self.timer = reader.read_u64()?;
+        self.position = reader.read_usize(WAVE_RAM_SIZE * 2)?;
+        reader.read_bytes(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::WaveChannel;
+
+    #[test]
+    fn test_wave_playback_and_output_level() {
+        let mut channel = WaveChannel::new();
+        channel.write_ram(0, 0xF8);
+        channel.write_ram(1, 0x42);
+        channel.write_register(0, 0x80);
+        channel.write_register(2, 0x20); // 100%
+        channel.write_register(3, 0xFF);
+        channel.write_register(4, 0x87); // frequency 0x7FF: period of 2 clocks
+        let mut samples = vec![];
+        for _ in 0..4 {
+            samples.push(channel.output());
+            channel.step(2);
+        }
+        assert_eq!(samples, vec![0x0F, 0x08, 0x04, 0x02]);
+
+        channel.write_register(2, 0x40); // 50%
+        channel.write_register(4, 0x87);
+        assert_eq!(channel.output(), 0x07);
+        channel.write_register(2, 0x60); // 25%
+        assert_eq!(channel.output(), 0x03);
+        channel.write_register(2, 0x00); // mute
+        assert_eq!(channel.output(), 0x00);
+    }
+
+    #[test]
+    fn test_wave_dac_off_disables_channel() {
+        let mut channel = WaveChannel::new();
+        channel.write_register(4, 0x80);
+        assert!(!channel.enabled());
+        channel.write_register(0, 0x80);
+        channel.write_register(4, 0x80);
+        assert!(channel.enabled());
+        channel.write_register(0, 0x00);
+        assert!(!channel.enabled());
+    }
+}
@@ -3,6 +3,7 @@ pub mod sdl2;
 use std::sync::mpsc::{Receiver, Sender};
 
 use super::config::EmulatorAppConfig;
+use super::console::ConsoleCommand;
 use super::emulator::EmulationMessage;
 use rustboylib::joypad::JoypadKey;
 
@@ -16,6 +17,14 @@ pub enum BackendMessage {
     KeyUp(JoypadKey),
     /// When the emulation is paused, perform a single step.
     Step,
+    /// Save the state of the emulation in the given numbered slot.
+    SaveState(u8),
+    /// Restore the state of the emulation from the given numbered slot.
+    LoadState(u8),
+    /// Start (if true) or stop rewinding the emulation.
+    Rewind(bool),
+    /// Execute the given debugger console command.
+    Debug(ConsoleCommand),
     /// Reset the emulation.
     Reset,
     /// Signal to gracefully shutdown the virtual machine. The backend
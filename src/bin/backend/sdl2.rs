@@ -1,5 +1,6 @@
+use self::sdl2::audio::{AudioQueue, AudioSpecDesired};
 use self::sdl2::event::Event;
-use self::sdl2::keyboard::Keycode;
+use self::sdl2::keyboard::{Keycode, Mod};
 use self::sdl2::pixels::{Color, PixelFormatEnum};
 use self::sdl2::rect::Rect;
 use self::sdl2::render::{Texture, TextureCreator, WindowCanvas};
@@ -16,6 +17,30 @@ use crate::emulator::EmulationMessage;
 use crate::input::get_key_bindings;
 use rustboylib::gpu::{RGB, SCREEN_H, SCREEN_W};
 
+/// The maximum amount of audio queued in the SDL2 audio device, in seconds.
+/// Past this limit, new samples are dropped to avoid an ever-increasing latency.
+const MAX_QUEUED_AUDIO_SECONDS: u32 = 1;
+
+/// The function keys associated to the save state slots 1 to 9 : pressing one
+/// loads the slot, and pressing it with Shift saves to it.
+const SAVE_STATE_KEYS: [Keycode; 9] = [
+    Keycode::F1,
+    Keycode::F2,
+    Keycode::F3,
+    Keycode::F4,
+    Keycode::F5,
+    Keycode::F6,
+    Keycode::F7,
+    Keycode::F8,
+    Keycode::F9,
+];
+
+/// The key to hold to rewind the emulation.
+const REWIND_KEY: Keycode = Keycode::Backspace;
+
+/// The key running a single instruction while the emulation is paused.
+const STEP_KEY: Keycode = Keycode::F10;
+
 /// The SDL 2 backend, using rust-sdl2.
 pub struct BackendSDL2;
 
@@ -88,7 +113,7 @@ impl EmulatorBackend for BackendSDL2 {
         let ttf_context = sdl2::ttf::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
         let window = match video_subsystem
-            .window(config.get_title(), w, h)
+            .window(&config.get_title(), w, h)
             .position_centered()
             .opengl()
             .build()
@@ -105,6 +130,33 @@ impl EmulatorBackend for BackendSDL2 {
         canvas.clear();
         let mut events = sdl_context.event_pump().unwrap();
 
+        // Audio output
+        let audio_sample_rate = config.get_audio_sample_rate();
+        let audio_queue: Option<AudioQueue<f32>> = if config.get_audio_enabled() {
+            let desired_spec = AudioSpecDesired {
+                freq: Some(audio_sample_rate as i32),
+                channels: Some(2),
+                samples: None,
+            };
+            match sdl_context
+                .audio()
+                .and_then(|audio| audio.open_queue(None, &desired_spec))
+            {
+                Ok(queue) => {
+                    queue.resume();
+                    Some(queue)
+                }
+                Err(why) => {
+                    warn!("SDL2 backend failed to open the audio device : {}", why);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // 2 channels of 4 bytes per sample
+        let max_queued_audio_bytes = audio_sample_rate * 2 * 4 * MAX_QUEUED_AUDIO_SECONDS;
+
         let font = ttf_context
             .load_font(Path::new("assets/OpenSans-Regular.ttf"), 48)
             .expect("could not load 'assets/OpenSans-Regular.ttf'");
@@ -137,6 +189,7 @@ impl EmulatorBackend for BackendSDL2 {
                     }
                     Event::KeyDown {
                         keycode: Some(keycode),
+                        keymod,
                         ..
                     } => {
                         if last_key.is_some() && keycode == last_key.unwrap() {
@@ -151,7 +204,30 @@ impl EmulatorBackend for BackendSDL2 {
                             // toggle pause
                             Keycode::Return => {
                                 paused = !paused;
-                                tx.send(UpdateRunStatus(paused)).unwrap();
+                                tx.send(UpdateRunStatus(!paused)).unwrap();
+                            }
+                            // save states, unless the key is bound to the joypad
+                            _ if SAVE_STATE_KEYS.contains(&keycode)
+                                && !key_binds.contains_key(&keycode) =>
+                            {
+                                let slot = SAVE_STATE_KEYS
+                                    .iter()
+                                    .position(|&key| key == keycode)
+                                    .unwrap() as u8
+                                    + 1;
+                                if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                                    tx.send(SaveState(slot)).unwrap();
+                                } else {
+                                    tx.send(LoadState(slot)).unwrap();
+                                }
+                            }
+                            // step, unless the key is bound to the joypad
+                            STEP_KEY if !key_binds.contains_key(&keycode) => {
+                                tx.send(Step).unwrap();
+                            }
+                            // rewind, unless the key is bound to the joypad
+                            REWIND_KEY if !key_binds.contains_key(&keycode) => {
+                                tx.send(Rewind(true)).unwrap();
                             }
                             _ => {
                                 if !paused {
@@ -163,6 +239,15 @@ impl EmulatorBackend for BackendSDL2 {
                         }
                         last_key = Some(keycode);
                     }
+                    Event::KeyUp {
+                        keycode: Some(REWIND_KEY),
+                        ..
+                    } if !key_binds.contains_key(&REWIND_KEY) => {
+                        tx.send(Rewind(false)).unwrap();
+                        if last_key == Some(REWIND_KEY) {
+                            last_key = None;
+                        }
+                    }
                     Event::KeyUp {
                         keycode: Some(keycode),
                         ..
@@ -193,6 +278,15 @@ impl EmulatorBackend for BackendSDL2 {
                             .copy(&texture, None, Some(Rect::new(0, 0, w, h)))
                             .expect("BackendSDL2: canvas texture copy error");
                     }
+                    UpdateAudio(samples) => {
+                        if let Some(ref queue) = audio_queue
+                            && queue.size() < max_queued_audio_bytes
+                            && let Err(why) = queue.queue_audio(&samples)
+                        {
+                            warn!("SDL2 backend audio error : {}", why);
+                        }
+                    }
+                    RunStatus(running) => paused = !running,
                     Finished => break 'ui,
                 },
                 _ => {}
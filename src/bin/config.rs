@@ -1,5 +1,4 @@
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::path::Path;
 use std::{cmp, path::PathBuf};
 
@@ -8,10 +7,23 @@ use toml;
 use super::backend::EmulatorBackend;
 use super::emulator::EmulatorApplication;
 use super::input::KeyboardBinding;
+use rustboylib::Error;
+use rustboylib::apu::DEFAULT_SAMPLE_RATE;
 use rustboylib::gpu::{SCREEN_H, SCREEN_W};
+use rustboylib::mbc::HeaderStrictness;
+use rustboylib::model::HardwareModel;
+use rustboylib::trace::{TraceFormat, TraceTrigger};
 
 // Default display scale, i.e. the actual size (in pixels) of each individual GameBoy pixel.
 const DEFAULT_SCALE: u16 = 2;
+// Default number of snapshots kept for rewinding.
+const DEFAULT_REWIND_LENGTH: usize = 300;
+// Default number of frames between two rewind snapshots.
+const DEFAULT_REWIND_INTERVAL: u32 = 4;
+// Default maximum memory used by the rewind snapshots, in MiB.
+const DEFAULT_REWIND_MAX_MEMORY: usize = 64;
+// Default CPU trace log file.
+const DEFAULT_TRACE_PATH: &str = "trace_cpu.log";
 
 // Macros to avoid boilerplate functions code.
 macro_rules! config_set_param {
@@ -35,8 +47,9 @@ macro_rules! config_get_param {
 /// application.
 #[derive(Clone, Debug)]
 pub struct EmulatorAppConfig {
-    /// The title of the emulator window.
-    window_title: &'static str,
+    /// The title of the emulator window, followed by the game title once
+    /// the cartridge is loaded.
+    window_title: String,
     /// The desired width for the emulator display window.
     ///
     /// This is just a hint, the application may resize to reach a proper
@@ -54,6 +67,38 @@ pub struct EmulatorAppConfig {
     keyboard_binding: KeyboardBinding,
     /// Display the FPS count.
     display_fps: bool,
+    /// Play the emulated sound. True by default.
+    audio_enabled: bool,
+    /// The audio output sample rate, in Hz.
+    audio_sample_rate: u32,
+    /// The hardware model to emulate. If None, it is selected from the
+    /// cartridge header.
+    hardware_model: Option<HardwareModel>,
+    /// Should a cartridge with an invalid header be refused, instead of
+    /// just logging a warning ?
+    header_strictness: HeaderStrictness,
+    /// The number of snapshots kept for rewinding the emulation (0 to
+    /// disable it).
+    rewind_length: usize,
+    /// The number of frames between two rewind snapshots.
+    rewind_interval: u32,
+    /// The maximum memory used by the rewind snapshots, in bytes : the
+    /// oldest ones are dropped beyond it.
+    rewind_max_memory: usize,
+    /// If true, the emulation starts paused with a debugger prompt on the
+    /// console.
+    debugger: bool,
+    /// If set, the local TCP port on which a GDB remote protocol client can
+    /// attach to the emulation.
+    gdb_port: Option<u16>,
+    /// If set, the file to which the CPU trace log is written.
+    trace_path: Option<PathBuf>,
+    /// The layout of the CPU trace log lines.
+    trace_format: TraceFormat,
+    /// If set, the CPU trace log only starts when this trigger is met.
+    trace_start: Option<TraceTrigger>,
+    /// If set, the CPU trace log stops when this trigger is met.
+    trace_stop: Option<TraceTrigger>,
 }
 
 impl EmulatorAppConfig {
@@ -73,34 +118,45 @@ impl EmulatorAppConfig {
     /// Create and return a new 'EmulatorAppConfig' with the default values set.
     pub fn new() -> EmulatorAppConfig {
         EmulatorAppConfig {
-            window_title: "RustBoyColor",
+            window_title: "RustBoyColor".into(),
             window_width: SCREEN_W as u16 * DEFAULT_SCALE,
             window_height: SCREEN_H as u16 * DEFAULT_SCALE,
             window_force_aspect: true,
             keyboard_binding: KeyboardBinding::QWERTY,
             display_fps: false,
+            audio_enabled: true,
+            audio_sample_rate: DEFAULT_SAMPLE_RATE,
+            hardware_model: None,
+            header_strictness: HeaderStrictness::Warn,
+            rewind_length: DEFAULT_REWIND_LENGTH,
+            rewind_interval: DEFAULT_REWIND_INTERVAL,
+            rewind_max_memory: DEFAULT_REWIND_MAX_MEMORY << 20,
+            debugger: false,
+            gdb_port: None,
+            trace_path: None,
+            trace_format: TraceFormat::Disassembly,
+            trace_start: None,
+            trace_stop: None,
         }
     }
 
     /// Create and return a new 'EmulatorAppConfig' with the default values set,
     /// and with all valid properties from the given configuration file set.
-    pub fn from_file(filepath: &PathBuf) -> Result<EmulatorAppConfig, String> {
+    pub fn from_file(filepath: &PathBuf) -> rustboylib::Result<EmulatorAppConfig> {
         let mut config = EmulatorAppConfig::new();
 
         let file_path = Path::new(filepath);
-        let mut file_content = String::new();
-        File::open(file_path)
-            .and_then(|mut f| f.read_to_string(&mut file_content))
-            .map_err(|_| format!("could not load the config file : {}", file_path.display()))?;
+        let file_content =
+            fs::read_to_string(file_path).map_err(|error| Error::io(file_path, error))?;
 
         let table = match file_content.parse::<toml::Table>() {
             Ok(table) => table,
             Err(err) => {
-                return Err(format!(
+                return Err(Error::InvalidConfig(format!(
                     "parsing error in config file \"{}\" : {}",
                     file_path.display(),
                     err
-                ));
+                )));
             }
         };
 
@@ -108,6 +164,24 @@ impl EmulatorAppConfig {
             "reading configuration from file \"{}\"...",
             file_path.display()
         );
+        if let Some(value) = table.get("emulation") {
+            let emulation = value
+                .as_table()
+                .expect("config file error : no emulation section");
+            match lookup_string_value("model", emulation) {
+                Ok(model) if model == "auto" => config.hardware_model = None,
+                Ok(model) => match model.parse::<HardwareModel>() {
+                    Ok(model) => config.hardware_model = Some(model),
+                    Err(error) => warn!("{}", error),
+                },
+                Err(error) => warn!("{}", error),
+            }
+            match lookup_bool_value("strict_header", emulation) {
+                Ok(true) => config.header_strictness = HeaderStrictness::Error,
+                Ok(false) => config.header_strictness = HeaderStrictness::Warn,
+                Err(error) => warn!("{}", error),
+            }
+        }
         if let Some(value) = table.get("display") {
             let display = value
                 .as_table()
@@ -143,6 +217,100 @@ impl EmulatorAppConfig {
         } else {
             warn!("no display section in config file");
         }
+        if let Some(value) = table.get("audio") {
+            let audio = value
+                .as_table()
+                .expect("config file error : no audio section");
+            match lookup_bool_value("enabled", audio) {
+                Ok(enabled) => config.audio_enabled = enabled,
+                Err(error) => warn!("{}", error),
+            }
+            match lookup_int_value("sample_rate", audio) {
+                Ok(sample_rate) => {
+                    if (8_000..=192_000).contains(&sample_rate) {
+                        config.audio_sample_rate = sample_rate as u32;
+                    } else {
+                        warn!("invalid audio sample rate");
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+        }
+        if let Some(value) = table.get("rewind") {
+            let rewind = value
+                .as_table()
+                .expect("config file error : no rewind section");
+            match lookup_int_value("length", rewind) {
+                Ok(length) => {
+                    if length >= 0 {
+                        config.rewind_length = length as usize;
+                    } else {
+                        warn!("invalid rewind length");
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+            match lookup_int_value("interval", rewind) {
+                Ok(interval) => {
+                    if (1..=u32::MAX as i64).contains(&interval) {
+                        config.rewind_interval = interval as u32;
+                    } else {
+                        warn!("invalid rewind interval");
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+            match lookup_int_value("max_memory", rewind) {
+                Ok(max_memory) => {
+                    if max_memory > 0 {
+                        config.rewind_max_memory = (max_memory as usize).saturating_mul(1 << 20);
+                    } else {
+                        warn!("invalid rewind maximum memory");
+                    }
+                }
+                Err(error) => warn!("{}", error),
+            }
+        }
+
+        if let Some(value) = table.get("trace") {
+            let trace = value
+                .as_table()
+                .expect("config file error : no trace section");
+            let path = match lookup_string_value("path", trace) {
+                Ok(path) => PathBuf::from(path),
+                Err(error) => {
+                    warn!("{}", error);
+                    PathBuf::from(DEFAULT_TRACE_PATH)
+                }
+            };
+            match lookup_bool_value("enabled", trace) {
+                Ok(enabled) => config.trace_path = enabled.then_some(path),
+                Err(error) => warn!("{}", error),
+            }
+            match lookup_string_value("format", trace) {
+                Ok(format) => match TraceFormat::from_name(&format) {
+                    Some(format) => config.trace_format = format,
+                    None => warn!("invalid trace format \"{}\"", format),
+                },
+                Err(error) => warn!("{}", error),
+            }
+            // the triggers are optional
+            for (key, trigger) in [
+                ("start", &mut config.trace_start),
+                ("stop", &mut config.trace_stop),
+            ] {
+                if !trace.contains_key(key) {
+                    continue;
+                }
+                match lookup_string_value(key, trace) {
+                    Ok(text) => match TraceTrigger::parse(&text) {
+                        Some(value) => *trigger = Some(value),
+                        None => warn!("invalid trace {} trigger \"{}\"", key, text),
+                    },
+                    Err(error) => warn!("{}", error),
+                }
+            }
+        }
 
         info!("configuration reading done.");
 
@@ -155,8 +323,8 @@ impl EmulatorAppConfig {
         EmulatorApplication::new(self, backend)
     }
 
-    config_set_param!(title, window_title, &'static str);
-    config_get_param!(get_title, window_title, &'static str);
+    config_set_param!(title, window_title, String);
+    config_get_param!(get_title, window_title, String);
 
     config_set_param!(width, window_width, u16);
     config_set_param!(height, window_height, u16);
@@ -167,6 +335,45 @@ impl EmulatorAppConfig {
 
     config_set_param!(display_fps, display_fps, bool);
     config_get_param!(get_display_fps, display_fps, bool);
+
+    config_set_param!(audio_enabled, audio_enabled, bool);
+    config_get_param!(get_audio_enabled, audio_enabled, bool);
+
+    config_set_param!(audio_sample_rate, audio_sample_rate, u32);
+    config_get_param!(get_audio_sample_rate, audio_sample_rate, u32);
+
+    config_set_param!(hardware_model, hardware_model, Option<HardwareModel>);
+    config_get_param!(get_hardware_model, hardware_model, Option<HardwareModel>);
+
+    config_set_param!(header_strictness, header_strictness, HeaderStrictness);
+    config_get_param!(get_header_strictness, header_strictness, HeaderStrictness);
+
+    config_set_param!(rewind_length, rewind_length, usize);
+    config_get_param!(get_rewind_length, rewind_length, usize);
+
+    config_set_param!(rewind_interval, rewind_interval, u32);
+    config_get_param!(get_rewind_interval, rewind_interval, u32);
+
+    config_set_param!(rewind_max_memory, rewind_max_memory, usize);
+    config_get_param!(get_rewind_max_memory, rewind_max_memory, usize);
+
+    config_set_param!(debugger, debugger, bool);
+    config_get_param!(get_debugger, debugger, bool);
+
+    config_set_param!(gdb_port, gdb_port, Option<u16>);
+    config_get_param!(get_gdb_port, gdb_port, Option<u16>);
+
+    config_set_param!(trace_path, trace_path, Option<PathBuf>);
+    config_get_param!(get_trace_path, trace_path, Option<PathBuf>);
+
+    config_set_param!(trace_format, trace_format, TraceFormat);
+    config_get_param!(get_trace_format, trace_format, TraceFormat);
+
+    config_set_param!(trace_start, trace_start, Option<TraceTrigger>);
+    config_get_param!(get_trace_start, trace_start, Option<TraceTrigger>);
+
+    config_set_param!(trace_stop, trace_stop, Option<TraceTrigger>);
+    config_get_param!(get_trace_stop, trace_stop, Option<TraceTrigger>);
 }
 
 fn lookup_bool_value(key: &'static str, table: &toml::value::Table) -> Result<bool, String> {
@@ -189,6 +396,24 @@ fn lookup_bool_value(key: &'static str, table: &toml::value::Table) -> Result<bo
     }
 }
 
+fn lookup_string_value(key: &'static str, table: &toml::value::Table) -> Result<String, String> {
+    if let Some(value) = table.get(key) {
+        match *value {
+            toml::Value::String(ref string) => Ok(string.clone()),
+            _ => Err(format!(
+                "config::lookup_string_value : key '{}' does not correspond to \
+                 a string",
+                key
+            )),
+        }
+    } else {
+        Err(format!(
+            "config::lookup_string_value : key '{}' was not found in the given table",
+            key
+        ))
+    }
+}
+
 fn lookup_int_value(key: &'static str, table: &toml::value::Table) -> Result<i64, String> {
     if let Some(value) = table.get(key) {
         match *value {
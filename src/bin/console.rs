@@ -0,0 +1,457 @@
+//! The debugger console : a prompt on the standard input, read in its own
+//! thread, whose commands are executed by the emulation thread.
+
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use rustboylib::cpu::Cpu;
+use rustboylib::debugger::{
+    Break, Breakpoint, Comparison, Condition, Debugger, Register, Watchpoint,
+};
+use rustboylib::disasm::disassemble;
+use rustboylib::memory::Memory;
+use rustboylib::mmu::{MMU, MemoryManagementUnit};
+
+use crate::backend::BackendMessage;
+
+const HELP: &str = "\
+commands (addresses and values in hexadecimal) :
+  b, break [BANK:]ADDR [if REG OP VALUE]   add a breakpoint, e.g. 'b 03:4000 if A == 12'
+  w, watch ADDR[-END] [r|w|rw]             add a watchpoint on writes (by default) and/or reads
+  d, delete ID                             delete a breakpoint or watchpoint
+  l, list                                  list the breakpoints and watchpoints
+  c, continue                              resume the emulation
+  p, pause                                 pause the emulation
+  s, step                                  run a single instruction
+  n, next                                  run a single instruction, stepping over the calls
+  f, finish                                run until the current subroutine returns
+  frame [N|+N]                             run until the frame N (the next one by default)
+  r, regs                                  show the CPU registers
+  x ADDR [LENGTH]                          show LENGTH (16 by default, decimal) bytes of memory
+  dis [ADDR] [COUNT]                       disassemble COUNT (10 by default, decimal) instructions at
+                                           ADDR (PC by default)
+  trace [on|off]                           switch the CPU trace log on or off (toggle by default)
+  h, help                                  show this help
+an empty line repeats the last command.";
+
+/// A command of the debugger console.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConsoleCommand {
+    Break(Breakpoint),
+    Watch(Watchpoint),
+    Delete(usize),
+    List,
+    Continue,
+    Pause,
+    Step,
+    Next,
+    Finish,
+    /// Run until the given frame, counted from the current one if relative.
+    Frame {
+        frame: u64,
+        relative: bool,
+    },
+    Registers,
+    Memory {
+        address: u16,
+        length: usize,
+    },
+    /// Disassemble the given number of instructions, at PC if no address
+    /// is given.
+    Disassemble {
+        address: Option<u16>,
+        count: usize,
+    },
+    /// Switch the CPU trace log on or off, or toggle it.
+    Trace(Option<bool>),
+    Help,
+}
+
+/// Parse an hexadecimal number, optionally prefixed by "0x" or "$".
+fn parse_hex(word: &str) -> Result<u16, String> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix('$'))
+        .unwrap_or(word);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid hexadecimal value '{}'", word))
+}
+
+fn parse_decimal<T: std::str::FromStr>(word: &str) -> Result<T, String> {
+    word.parse()
+        .map_err(|_| format!("invalid decimal value '{}'", word))
+}
+
+/// Parse a register condition such as "A == 12" or "hl>=C000".
+fn parse_condition(text: &str) -> Result<Condition, String> {
+    let (index, symbol) = ["==", "!=", "<=", ">=", "<", ">"]
+        .iter()
+        .find_map(|symbol| text.find(symbol).map(|index| (index, *symbol)))
+        .ok_or_else(|| format!("invalid condition '{}'", text))?;
+    let name = text[..index].trim();
+    Ok(Condition {
+        register: Register::from_name(name)
+            .ok_or_else(|| format!("unknown register '{}'", name))?,
+        comparison: Comparison::from_symbol(symbol).unwrap(),
+        value: parse_hex(text[index + symbol.len()..].trim())?,
+    })
+}
+
+/// Parse a line of the console. Return None for an empty line.
+pub fn parse_command(line: &str) -> Result<Option<ConsoleCommand>, String> {
+    use self::ConsoleCommand::*;
+
+    let mut words = line.split_whitespace();
+    let name = match words.next() {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let arguments: Vec<&str> = words.collect();
+    let argument = |index: usize| {
+        arguments
+            .get(index)
+            .copied()
+            .ok_or_else(|| format!("missing argument for '{}' (see 'help')", name))
+    };
+
+    let command = match name {
+        "b" | "break" => {
+            let location = argument(0)?;
+            let mut breakpoint = match location.split_once(':') {
+                Some((bank, address)) => Breakpoint {
+                    bank: Some(parse_hex(bank)? as usize),
+                    ..Breakpoint::new(parse_hex(address)?)
+                },
+                None => Breakpoint::new(parse_hex(location)?),
+            };
+            match arguments.get(1) {
+                Some(&"if") => {
+                    breakpoint.condition = Some(parse_condition(&arguments[2..].join(" "))?)
+                }
+                Some(word) => return Err(format!("unexpected '{}'", word)),
+                None => {}
+            }
+            Break(breakpoint)
+        }
+        "w" | "watch" => {
+            let range = argument(0)?;
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse_hex(start)?, parse_hex(end)?),
+                None => (parse_hex(range)?, parse_hex(range)?),
+            };
+            if end < start {
+                return Err(format!("invalid address range '{}'", range));
+            }
+            let (read, write) = match arguments.get(1) {
+                None | Some(&"w") => (false, true),
+                Some(&"r") => (true, false),
+                Some(&"rw") => (true, true),
+                Some(word) => return Err(format!("invalid access type '{}'", word)),
+            };
+            Watch(Watchpoint {
+                range: start..=end,
+                read,
+                write,
+            })
+        }
+        "d" | "delete" => Delete(parse_decimal(argument(0)?)?),
+        "l" | "list" => List,
+        "c" | "continue" => Continue,
+        "p" | "pause" => Pause,
+        "s" | "step" => Step,
+        "n" | "next" => Next,
+        "f" | "finish" => Finish,
+        "frame" => match arguments.first() {
+            Some(word) => match word.strip_prefix('+') {
+                Some(count) => Frame {
+                    frame: parse_decimal(count)?,
+                    relative: true,
+                },
+                None => Frame {
+                    frame: parse_decimal(word)?,
+                    relative: false,
+                },
+            },
+            None => Frame {
+                frame: 1,
+                relative: true,
+            },
+        },
+        "r" | "regs" => Registers,
+        "x" => Memory {
+            address: parse_hex(argument(0)?)?,
+            length: match arguments.get(1) {
+                Some(word) => parse_decimal(word)?,
+                None => 16,
+            },
+        },
+        "dis" => Disassemble {
+            address: arguments.first().map(|word| parse_hex(word)).transpose()?,
+            count: match arguments.get(1) {
+                Some(word) => parse_decimal(word)?,
+                None => 10,
+            },
+        },
+        "trace" => Trace(match arguments.first() {
+            Some(&"on") => Some(true),
+            Some(&"off") => Some(false),
+            Some(word) => return Err(format!("unexpected '{}'", word)),
+            None => None,
+        }),
+        "h" | "help" => Help,
+        _ => return Err(format!("unknown command '{}' (see 'help')", name)),
+    };
+    Ok(Some(command))
+}
+
+/// Start the console thread, sending the commands to the emulation thread
+/// until the standard input is closed.
+pub fn spawn_console(tx: Sender<BackendMessage>) {
+    let console = thread::Builder::new()
+        .name("rustboycolor_console".into())
+        .spawn(move || {
+            println!("debugger console : type 'help' for the list of commands");
+            let stdin = io::stdin();
+            let mut last_command = None;
+            loop {
+                print!("> ");
+                io::stdout().flush().unwrap_or(());
+                let mut line = String::new();
+                match stdin.lock().read_line(&mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                let command = match parse_command(&line) {
+                    Ok(Some(command)) => command,
+                    Ok(None) => match last_command.clone() {
+                        Some(command) => command,
+                        None => continue,
+                    },
+                    Err(why) => {
+                        println!("{}", why);
+                        continue;
+                    }
+                };
+                last_command = Some(command.clone());
+                if command == ConsoleCommand::Help {
+                    println!("{}", HELP);
+                } else if tx.send(BackendMessage::Debug(command)).is_err() {
+                    break;
+                }
+            }
+        });
+    if let Err(why) = console {
+        error!("cannot spawn the debugger console thread : {}", why);
+    }
+}
+
+/// Execute the given console command in the emulation thread, updating the
+/// run status of the emulation.
+pub fn execute_command(
+    command: ConsoleCommand,
+    debugger: &mut Debugger,
+    cpu: &mut Cpu<MMU>,
+    running: &mut bool,
+) {
+    use self::ConsoleCommand::*;
+
+    match command {
+        Break(breakpoint) => {
+            let description = breakpoint.to_string();
+            let id = debugger.add_breakpoint(breakpoint);
+            println!("breakpoint #{} at {}", id, description);
+        }
+        Watch(watchpoint) => {
+            let description = watchpoint.to_string();
+            let id = debugger.add_watchpoint(watchpoint);
+            println!("watchpoint #{} on {}", id, description);
+        }
+        Delete(id) => {
+            if debugger.remove(id) {
+                println!("deleted #{}", id);
+            } else {
+                println!("no breakpoint or watchpoint #{}", id);
+            }
+        }
+        List => {
+            for (id, breakpoint) in debugger.breakpoints() {
+                println!("#{} : breakpoint at {}", id, breakpoint);
+            }
+            for (id, watchpoint) in debugger.watchpoints() {
+                println!("#{} : watchpoint on {}", id, watchpoint);
+            }
+        }
+        Continue => {
+            debugger.resume();
+            *running = true;
+        }
+        Pause => {
+            *running = false;
+            print_state(cpu);
+        }
+        Step => {
+            debugger.step_into();
+            *running = true;
+        }
+        Next => {
+            debugger.step_over();
+            *running = true;
+        }
+        Finish => {
+            debugger.step_out(cpu);
+            *running = true;
+        }
+        Frame { frame, relative } => {
+            let frame = if relative {
+                cpu.mem.frame_count() + frame
+            } else {
+                frame
+            };
+            debugger.run_to_frame(frame);
+            *running = true;
+        }
+        Registers => print_state(cpu),
+        Memory { address, length } => {
+            for line_start in (0..length).step_by(16) {
+                let line_address = address.wrapping_add(line_start as u16);
+                let bytes: Vec<String> = (0..(length - line_start).min(16))
+                    .map(|offset| {
+                        let byte = cpu.mem.read_byte(line_address.wrapping_add(offset as u16));
+                        format!("{:0>2X}", byte)
+                    })
+                    .collect();
+                println!("{:0>4X}: {}", line_address, bytes.join(" "));
+            }
+        }
+        Disassemble { address, count } => {
+            let address = address.unwrap_or(cpu.regs.pc);
+            print_instructions(cpu, address, count);
+        }
+        Trace(enabled) => match cpu.tracer_mut() {
+            Some(tracer) => {
+                let enabled = enabled.unwrap_or(!tracer.is_enabled());
+                tracer.set_enabled(enabled);
+                println!("CPU trace log {}", if enabled { "on" } else { "off" });
+            }
+            None => println!("no CPU trace log set up (see the '--trace' option)"),
+        },
+        Help => {}
+    }
+}
+
+/// Report that the debugger suspended the execution.
+pub fn report_break(reason: &Break, cpu: &mut Cpu<MMU>) {
+    println!("break : {}", reason);
+    print_state(cpu);
+}
+
+/// Print the CPU registers and the bytes of the next instruction.
+fn print_state(cpu: &mut Cpu<MMU>) {
+    let regs = &cpu.regs;
+    let flags: String = "ZNHC"
+        .chars()
+        .enumerate()
+        .map(|(i, flag)| if regs.f & (0x80 >> i) != 0 { flag } else { '-' })
+        .collect();
+    println!(
+        "AF={:0>4X} BC={:0>4X} DE={:0>4X} HL={:0>4X} SP={:0>4X} PC={:0>4X} [{}] IME={} frame={}",
+        regs.af(),
+        regs.bc(),
+        regs.de(),
+        regs.hl(),
+        regs.sp,
+        regs.pc,
+        flags,
+        cpu.ime as u8,
+        cpu.mem.frame_count()
+    );
+    let pc = cpu.regs.pc;
+    print_instructions(cpu, pc, 1);
+}
+
+/// Print the disassembly of the given number of instructions.
+fn print_instructions(cpu: &mut Cpu<MMU>, address: u16, count: usize) {
+    let mut address = address;
+    for _ in 0..count {
+        let instruction = disassemble(&mut cpu.mem, address);
+        if address < 0x8000 {
+            print!("{:0>2X}:", cpu.mem.rom_bank(address));
+        }
+        println!("{}", instruction);
+        address = address.wrapping_add(instruction.length());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustboylib::debugger::{Breakpoint, Comparison, Condition, Register, Watchpoint};
+
+    use super::{ConsoleCommand, parse_command};
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("  \n"), Ok(None));
+        assert_eq!(
+            parse_command("b 150"),
+            Ok(Some(ConsoleCommand::Break(Breakpoint::new(0x0150))))
+        );
+        assert_eq!(
+            parse_command("break 0x03:$4000 if hl>=C000"),
+            Ok(Some(ConsoleCommand::Break(Breakpoint {
+                address: 0x4000,
+                bank: Some(3),
+                condition: Some(Condition {
+                    register: Register::HL,
+                    comparison: Comparison::GreaterOrEqual,
+                    value: 0xC000,
+                }),
+            })))
+        );
+        assert_eq!(
+            parse_command("watch C000-C0FF rw"),
+            Ok(Some(ConsoleCommand::Watch(Watchpoint {
+                range: 0xC000..=0xC0FF,
+                read: true,
+                write: true,
+            })))
+        );
+        assert_eq!(
+            parse_command("frame +10"),
+            Ok(Some(ConsoleCommand::Frame {
+                frame: 10,
+                relative: true,
+            }))
+        );
+        assert_eq!(
+            parse_command("x ff40 4"),
+            Ok(Some(ConsoleCommand::Memory {
+                address: 0xFF40,
+                length: 4,
+            }))
+        );
+        assert_eq!(
+            parse_command("dis"),
+            Ok(Some(ConsoleCommand::Disassemble {
+                address: None,
+                count: 10,
+            }))
+        );
+        assert_eq!(
+            parse_command("trace off"),
+            Ok(Some(ConsoleCommand::Trace(Some(false))))
+        );
+        assert_eq!(parse_command("n"), Ok(Some(ConsoleCommand::Next)));
+    }
+
+    #[test]
+    fn test_parse_command_invalid() {
+        assert!(parse_command("jump 150").is_err());
+        assert!(parse_command("break").is_err());
+        assert!(parse_command("break 150 when A == 1").is_err());
+        assert!(parse_command("break 150 if Q == 1").is_err());
+        assert!(parse_command("watch C0FF-C000").is_err());
+        assert!(parse_command("watch C000 x").is_err());
+        assert!(parse_command("delete one").is_err());
+        assert!(parse_command("trace maybe").is_err());
+    }
+}
@@ -1,23 +1,68 @@
-use std::path::Path;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Receiver, Sender, channel};
 use std::thread;
+use std::time::Duration;
 
 use crate::backend::{BackendMessage, EmulatorBackend};
 use crate::config::EmulatorAppConfig;
+use crate::console;
 use rustboylib::cpu::{CPU_CLOCK_SPEED, CycleType};
+use rustboylib::debugger::Debugger;
+use rustboylib::gdb::GdbStub;
 use rustboylib::gpu::RGB;
+use rustboylib::model::HardwareModel;
+use rustboylib::rewind::RewindBuffer;
+use rustboylib::trace::{TraceFormat, TraceTrigger, Tracer};
 use rustboylib::{cpu, mbc, mmu};
 
+/// The number of frames (about a second) after which the modified
+/// battery-buffered cartridge RAM is flushed to the save file.
+const SAVE_FLUSH_INTERVAL_FRAMES: u32 = 60;
+
+/// The interval at which the UI signals and the GDB client are polled while
+/// the emulation is paused.
+const PAUSED_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 /// Message emitted by the emulation loop to the UI backend.
 pub enum EmulationMessage {
     /// Update the display.
     UpdateDisplay(Vec<RGB>),
+    /// Queue audio samples, interleaved as left/right pairs.
+    UpdateAudio(Vec<f32>),
+    /// Notify the emulation state (running if true, paused if false), which
+    /// also changes on the debugger breaks and commands.
+    RunStatus(bool),
     /// Signal that the emulation is finished, emitted either after a
     /// 'BackendMessage::Quit' signal was received or when the virtual machine
     /// finished the execution of its cartridge.
     Finished,
 }
 
+/// The settings of the emulation loop.
+struct EmulationSettings {
+    audio_enabled: bool,
+    /// The save file of the battery-buffered cartridge RAM, if any.
+    save_path: Option<PathBuf>,
+    /// The cartridge file, next to which the save states are written.
+    rom_path: PathBuf,
+    /// The number of snapshots kept for rewinding.
+    rewind_length: usize,
+    /// The number of frames between two rewind snapshots.
+    rewind_interval: u32,
+    /// The maximum memory used by the rewind snapshots, in bytes.
+    rewind_max_memory: usize,
+    /// Start paused, waiting for the debugger console commands.
+    debugger: bool,
+    /// The local TCP port of the GDB stub, if any.
+    gdb_port: Option<u16>,
+    /// The CPU trace log file, if any.
+    trace_path: Option<PathBuf>,
+    trace_format: TraceFormat,
+    trace_start: Option<TraceTrigger>,
+    trace_stop: Option<TraceTrigger>,
+}
+
 /// The backend-agnostic RustBoyColor emulator application.
 ///
 /// Communication between the virtual machine's emulation loop and the
@@ -48,22 +93,58 @@ impl EmulatorApplication {
         let (tx_ui, rx_vm) = channel::<BackendMessage>();
 
         // VM loop, in a secondary thread
-        let mbc: Box<dyn mbc::MBC + Send> = match mbc::load_cartridge(&rom_path) {
-            Ok(mbc) => mbc,
+        let strictness = self.config.get_header_strictness();
+        let (info, mut mbc) = match mbc::load_cartridge(&rom_path, strictness) {
+            Ok(cartridge) => cartridge,
             Err(why) => {
                 error!("cannot load the cartridge : {}", why);
                 return false;
             }
         };
+        let save_path = if mbc.has_battery() {
+            let save_path = rom_path.with_extension("sav");
+            load_save_data(&mut *mbc, &save_path);
+            Some(save_path)
+        } else {
+            None
+        };
+        mbc.set_rumble_callback(Box::new(|rumble| {
+            debug!("rumble motor {}", if rumble { "on" } else { "off" })
+        }));
+        let model = self
+            .config
+            .get_hardware_model()
+            .unwrap_or_else(|| HardwareModel::from_cgb_flag(info.cgb_flag));
+        info!(
+            "emulating \"{}\" on the {} hardware model",
+            info.title, model
+        );
+        let settings = EmulationSettings {
+            audio_enabled: self.config.get_audio_enabled(),
+            save_path,
+            rom_path: rom_path.to_path_buf(),
+            rewind_length: self.config.get_rewind_length(),
+            rewind_interval: self.config.get_rewind_interval(),
+            rewind_max_memory: self.config.get_rewind_max_memory(),
+            debugger: self.config.get_debugger(),
+            gdb_port: self.config.get_gdb_port(),
+            trace_path: self.config.get_trace_path(),
+            trace_format: self.config.get_trace_format(),
+            trace_start: self.config.get_trace_start(),
+            trace_stop: self.config.get_trace_stop(),
+        };
+        let audio_sample_rate = self.config.get_audio_sample_rate();
         match thread::Builder::new()
             .name("rustboylib_vm".into())
             .spawn(move || {
-                let mmu = mmu::MMU::new(mbc, false, skip_bios, None);
+                let mut mmu = mmu::MMU::new(mbc, model, skip_bios, None);
+                mmu.set_audio_sample_rate(audio_sample_rate);
                 let mut cpu = cpu::Cpu::<mmu::MMU>::new(mmu);
                 if skip_bios {
-                    cpu.post_bios();
+                    cpu.post_bios(model);
                 }
-                emulation_loop(&mut cpu, tx_vm, rx_vm);
+                cpu.set_tracer(create_tracer(&settings));
+                emulation_loop(&mut cpu, settings, tx_vm, rx_vm);
             }) {
             Err(why) => {
                 error!("cannot spawn the VM thread: {}", why);
@@ -72,16 +153,115 @@ impl EmulatorApplication {
             _ => {}
         };
 
+        if self.config.get_debugger() {
+            console::spawn_console(tx_ui.clone());
+        }
+
         // UI loop, in the emulator's thread (should be the main thread)
-        self.backend.run(self.config.clone(), tx_ui, rx_ui);
+        let config = self.config.clone();
+        let title = format!("{} - {}", config.get_title(), info.title);
+        self.backend.run(config.title(title), tx_ui, rx_ui);
 
         true
     }
 }
 
+/// Load the battery-buffered cartridge RAM from the given save file, if it
+/// exists.
+fn load_save_data(mbc: &mut dyn mbc::MBC, save_path: &Path) {
+    if !save_path.exists() {
+        return;
+    }
+    match fs::read(save_path) {
+        Ok(data) => match mbc.import_save_data(&data) {
+            Ok(()) => info!("loaded the save file \"{}\"", save_path.display()),
+            Err(why) => warn!(
+                "ignoring the save file \"{}\" : {}",
+                save_path.display(),
+                why
+            ),
+        },
+        Err(why) => warn!(
+            "cannot read the save file \"{}\" : {}",
+            save_path.display(),
+            why
+        ),
+    }
+}
+
+/// Write the battery-buffered cartridge RAM, if any, to the given save file.
+fn flush_save_data(mmu: &mut mmu::MMU, save_path: &Option<PathBuf>) {
+    if let (Some(save_path), Some(data)) = (save_path, mmu.save_data()) {
+        match fs::write(save_path, data) {
+            Ok(()) => debug!("flushed the save file \"{}\"", save_path.display()),
+            Err(why) => error!(
+                "cannot write the save file \"{}\" : {}",
+                save_path.display(),
+                why
+            ),
+        }
+    }
+}
+
+/// The save state file of the given slot, next to the cartridge file.
+fn save_state_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+/// Save the state of the machine in the given slot.
+fn save_state(cpu: &cpu::Cpu<mmu::MMU>, rom_path: &Path, slot: u8) {
+    let state_path = save_state_path(rom_path, slot);
+    match fs::write(&state_path, cpu.save_state()) {
+        Ok(()) => info!("saved state {} to \"{}\"", slot, state_path.display()),
+        Err(why) => error!(
+            "cannot write the save state \"{}\" : {}",
+            state_path.display(),
+            why
+        ),
+    }
+}
+
+/// Restore the state of the machine from the given slot, if it exists.
+fn load_state(cpu: &mut cpu::Cpu<mmu::MMU>, rom_path: &Path, slot: u8) {
+    let state_path = save_state_path(rom_path, slot);
+    let result = fs::read(&state_path)
+        .map_err(|why| rustboylib::Error::io(&state_path, why))
+        .and_then(|data| cpu.load_state(&data));
+    match result {
+        Ok(()) => info!("loaded state {} from \"{}\"", slot, state_path.display()),
+        Err(why) => warn!("cannot load the save state {} : {}", slot, why),
+    }
+}
+
+/// Create the CPU trace logger, if enabled.
+fn create_tracer(settings: &EmulationSettings) -> Option<Tracer> {
+    let path = settings.trace_path.as_ref()?;
+    match Tracer::create(settings.trace_format, path) {
+        Ok(mut tracer) => {
+            info!(
+                "writing the CPU trace log to \"{}\" ({} format)",
+                path.display(),
+                settings.trace_format
+            );
+            if let Some(trigger) = settings.trace_start {
+                tracer = tracer.start_at(trigger);
+            }
+            if let Some(trigger) = settings.trace_stop {
+                tracer = tracer.stop_at(trigger);
+            }
+            Some(tracer)
+        }
+        Err(why) => {
+            error!("cannot set up the CPU trace log : {}", why);
+            None
+        }
+    }
+}
+
 /// Emulation loop leveraging the rustboylib crate to emulate a Game Boy (Color).
 fn emulation_loop(
     cpu: &mut cpu::Cpu<mmu::MMU>,
+    settings: EmulationSettings,
     tx: Sender<EmulationMessage>,
     rx: Receiver<BackendMessage>,
 ) {
@@ -90,10 +270,35 @@ fn emulation_loop(
 
     info!("starting the emulation thread.");
 
-    let mut running = true;
+    let mut running = !settings.debugger && settings.gdb_port.is_none();
+    let mut debugger = Debugger::new();
+    let mut gdb_stub =
+        settings
+            .gdb_port
+            .and_then(|port| match GdbStub::bind(("127.0.0.1", port)) {
+                Ok(gdb_stub) => {
+                    info!("waiting for a GDB client on port {}", port);
+                    Some(gdb_stub)
+                }
+                Err(why) => {
+                    error!("cannot listen for a GDB client on port {} : {}", port, why);
+                    running = !settings.debugger;
+                    None
+                }
+            });
     // target CPU clock cycles per second
     let frame_ticks = (CPU_CLOCK_SPEED / 1000 * 16) as CycleType;
     let mut ticks: CycleType = 0;
+    // frames emulated since the save file was last flushed
+    let mut frames_since_flush: u32 = 0;
+    // snapshots of the previous frames, restored backwards while rewinding
+    let mut rewind_buffer =
+        RewindBuffer::new(settings.rewind_length).with_max_memory(settings.rewind_max_memory);
+    let mut rewinding = false;
+    // frames emulated since the last rewind snapshot
+    let mut frames_since_snapshot: u32 = 0;
+    // the emulation state last notified to the backend
+    let mut reported_running = None;
 
     'vm: loop {
         // Signals from the UI
@@ -102,10 +307,25 @@ fn emulation_loop(
                 UpdateRunStatus(run) => running = run,
                 KeyDown(key) => cpu.mem.key_down(&key),
                 KeyUp(key) => cpu.mem.key_up(&key),
-                Step => {}
+                Step => {
+                    if !running {
+                        debugger.step_into();
+                        running = true;
+                    }
+                }
+                SaveState(slot) => save_state(cpu, &settings.rom_path, slot),
+                LoadState(slot) => load_state(cpu, &settings.rom_path, slot),
+                Rewind(rewind) => {
+                    rewinding = rewind;
+                    frames_since_snapshot = 0;
+                }
+                Debug(command) => {
+                    console::execute_command(command, &mut debugger, cpu, &mut running)
+                }
                 Reset => {}
                 Quit => {
                     running = false;
+                    flush_save_data(&mut cpu.mem, &settings.save_path);
                     info!("terminating the emulation thread...");
                     tx.send(Finished).unwrap();
                     break 'vm;
@@ -114,17 +334,79 @@ fn emulation_loop(
             _ => {}
         }
 
+        if let Some(ref mut gdb_stub) = gdb_stub {
+            gdb_stub.poll(&mut debugger, cpu, &mut running);
+        }
+
+        if reported_running != Some(running) {
+            reported_running = Some(running);
+            tx.send(RunStatus(running)).unwrap();
+        }
+
         if !running {
+            thread::sleep(PAUSED_POLL_INTERVAL);
+            continue;
+        }
+
+        if rewinding {
+            // step back to the previous snapshot, if any
+            if let Some(state) = rewind_buffer.pop() {
+                match cpu.load_state(&state) {
+                    Ok(()) => {
+                        if let Some(frame_buffer) = cpu.mem.frame_buffer() {
+                            tx.send(UpdateDisplay(frame_buffer)).unwrap();
+                        }
+                        // discard the samples of the frames replaced
+                        cpu.mem.audio_samples();
+                    }
+                    Err(why) => error!("cannot rewind : {}", why),
+                }
+            }
+            // show each restored frame for the duration of one emulated frame
+            thread::sleep(Duration::from_millis(16));
             continue;
         }
 
         while ticks < frame_ticks {
-            ticks += cpu.step();
+            if !debugger.is_active() {
+                ticks += cpu.step();
+                continue;
+            }
+            let (cycles, reason) = debugger.step(cpu);
+            ticks += cycles;
+            if let Some(reason) = reason {
+                console::report_break(&reason, cpu);
+                running = false;
+                break;
+            }
+        }
+        if ticks < frame_ticks {
+            // suspended by the debugger in the middle of the frame
+            if let Some(frame_buffer) = cpu.mem.frame_buffer() {
+                tx.send(UpdateDisplay(frame_buffer)).unwrap();
+            }
+            continue;
         }
         ticks -= frame_ticks;
         if let Some(frame_buffer) = cpu.mem.frame_buffer() {
             tx.send(UpdateDisplay(frame_buffer)).unwrap();
         }
+        let audio_samples = cpu.mem.audio_samples();
+        if settings.audio_enabled && !audio_samples.is_empty() {
+            tx.send(UpdateAudio(audio_samples)).unwrap();
+        }
+        frames_since_flush += 1;
+        if frames_since_flush >= SAVE_FLUSH_INTERVAL_FRAMES {
+            frames_since_flush = 0;
+            if cpu.mem.save_data_modified() {
+                flush_save_data(&mut cpu.mem, &settings.save_path);
+            }
+        }
+        frames_since_snapshot += 1;
+        if frames_since_snapshot >= settings.rewind_interval {
+            frames_since_snapshot = 0;
+            rewind_buffer.push(cpu.save_state());
+        }
 
         // thread::sleep(Duration::from_millis(1));
     }
@@ -1,15 +1,23 @@
 mod backend;
 mod config;
+mod console;
 mod emulator;
 mod input;
 mod logger;
+mod trace_diff;
 
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::process;
 
 #[macro_use]
 extern crate log;
 
-use clap::{Arg, Parser, ValueEnum};
+use clap::{Arg, Parser, Subcommand, ValueEnum};
+
+use rustboylib::disasm::RomBank;
+use rustboylib::model::HardwareModel;
+use rustboylib::trace::{TraceFormat, TraceTrigger};
 
 use crate::backend::sdl2;
 use crate::input::KeyboardBinding;
@@ -20,11 +28,100 @@ enum KeyboardMode {
     Qwerty,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum ModelArg {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    Agb,
+}
+
+impl ModelArg {
+    fn hardware_model(self) -> HardwareModel {
+        match self {
+            ModelArg::Dmg0 => HardwareModel::DMG0,
+            ModelArg::Dmg => HardwareModel::DMG,
+            ModelArg::Mgb => HardwareModel::MGB,
+            ModelArg::Sgb => HardwareModel::SGB,
+            ModelArg::Sgb2 => HardwareModel::SGB2,
+            ModelArg::Cgb => HardwareModel::CGB,
+            ModelArg::Agb => HardwareModel::AGB,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum TraceFormatArg {
+    Disassembly,
+    Doctor,
+}
+
+impl TraceFormatArg {
+    fn trace_format(self) -> TraceFormat {
+        match self {
+            TraceFormatArg::Disassembly => TraceFormat::Disassembly,
+            TraceFormatArg::Doctor => TraceFormat::Doctor,
+        }
+    }
+}
+
+fn parse_trace_trigger(text: &str) -> Result<TraceTrigger, String> {
+    TraceTrigger::parse(text).ok_or_else(|| "expected \"pc:ADDRESS\" or \"frame:N\"".into())
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Runs a ROM headless against a reference CPU trace log, and shows the
+    /// instructions around the first mismatching line.
+    TraceDiff {
+        #[clap(help = "The ROM file to run.")]
+        rom_file: PathBuf,
+
+        #[clap(help = "The reference CPU trace log.")]
+        reference: PathBuf,
+
+        #[clap(
+            short,
+            long,
+            value_enum,
+            value_name = "format",
+            default_value = "doctor",
+            help = "Sets the layout of the reference trace log lines. With 'doctor', LY (0xFF44) always reads as 0x90 as in the Gameboy Doctor logs."
+        )]
+        format: TraceFormatArg,
+
+        #[clap(
+            short,
+            long,
+            value_enum,
+            value_name = "model",
+            help = "Sets the hardware model to emulate. Selected from the cartridge header by default."
+        )]
+        model: Option<ModelArg>,
+
+        #[clap(
+            short = 'n',
+            long,
+            value_name = "N",
+            default_value_t = 10,
+            help = "Sets the number of instructions shown before and after the mismatch."
+        )]
+        context: usize,
+    },
+}
+
 #[derive(Debug, Parser)]
 #[clap(author, version = "alpha", about = "Game Boy (Color) emulator", long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    #[clap(help = "The ROM file to play.")]
-    rom_file: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    #[clap(required = true, help = "The ROM file to play.")]
+    rom_file: Option<PathBuf>,
 
     #[clap(
         short,
@@ -42,6 +139,67 @@ struct Args {
         help = "Sets the keyboard configuration to use. QWERTY by default. A custom binding can be defined in the configuration file."
     )]
     keyboard_mode: Option<KeyboardMode>,
+
+    #[clap(
+        short,
+        long,
+        value_enum,
+        value_name = "model",
+        help = "Sets the hardware model to emulate, overriding the configuration file. Selected from the cartridge header by default."
+    )]
+    model: Option<ModelArg>,
+
+    #[clap(
+        short,
+        long,
+        help = "Starts the emulation paused, with a debugger prompt on the console."
+    )]
+    debugger: bool,
+
+    #[clap(
+        long,
+        value_name = "PORT",
+        help = "Starts the emulation paused, waiting for a GDB remote protocol client on the given local TCP port."
+    )]
+    gdb: Option<u16>,
+
+    #[clap(
+        long,
+        value_name = "TRACE_FILE",
+        help = "Writes the CPU trace log to the given file, overriding the configuration file."
+    )]
+    trace: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_enum,
+        value_name = "format",
+        help = "Sets the layout of the CPU trace log lines, 'doctor' being the Gameboy Doctor one."
+    )]
+    trace_format: Option<TraceFormatArg>,
+
+    #[clap(
+        long,
+        value_name = "TRIGGER",
+        value_parser = parse_trace_trigger,
+        help = "Starts the CPU trace log when the trigger (\"pc:ADDRESS\" or \"frame:N\") is met."
+    )]
+    trace_start: Option<TraceTrigger>,
+
+    #[clap(
+        long,
+        value_name = "TRIGGER",
+        value_parser = parse_trace_trigger,
+        help = "Stops the CPU trace log when the trigger (\"pc:ADDRESS\" or \"frame:N\") is met."
+    )]
+    trace_stop: Option<TraceTrigger>,
+
+    #[clap(
+        long,
+        value_name = "BANK",
+        help = "Prints the disassembly of the given ROM bank instead of playing the ROM."
+    )]
+    disassemble: Option<usize>,
 }
 
 fn app_options_from_args(args: &Args) -> config::EmulatorAppConfig {
@@ -65,7 +223,53 @@ fn app_options_from_args(args: &Args) -> config::EmulatorAppConfig {
             config::EmulatorAppConfig::new()
         }
     };
-    config.keyboard_binding(keyboard_binding)
+    let config = match args.model {
+        Some(model) => config.hardware_model(Some(model.hardware_model())),
+        None => config,
+    };
+    let config = match args.trace {
+        Some(ref path) => config.trace_path(Some(path.clone())),
+        None => config,
+    };
+    let config = match args.trace_format {
+        Some(format) => config.trace_format(format.trace_format()),
+        None => config,
+    };
+    let config = match args.trace_start {
+        Some(trigger) => config.trace_start(Some(trigger)),
+        None => config,
+    };
+    let config = match args.trace_stop {
+        Some(trigger) => config.trace_stop(Some(trigger)),
+        None => config,
+    };
+    config
+        .keyboard_binding(keyboard_binding)
+        .debugger(args.debugger)
+        .gdb_port(args.gdb)
+}
+
+/// Print the disassembly of the given bank of the ROM file.
+fn disassemble(rom_path: &Path, bank: usize) {
+    let rom = match fs::read(rom_path) {
+        Ok(rom) => rom,
+        Err(why) => {
+            error!(
+                "cannot read the ROM file \"{}\" : {}",
+                rom_path.display(),
+                why
+            );
+            return;
+        }
+    };
+    match RomBank::new(&rom, bank) {
+        Some(mut rom_bank) => {
+            for instruction in rom_bank.disassemble() {
+                println!("{:0>2X}:{}", bank, instruction);
+            }
+        }
+        None => error!("the ROM has no bank {:0>2X}", bank),
+    }
 }
 
 fn main() {
@@ -76,11 +280,32 @@ fn main() {
 
     // CLI options
     let args: Args = Args::parse();
-    let rom = args.rom_file.clone();
+    if let Some(Command::TraceDiff {
+        ref rom_file,
+        ref reference,
+        format,
+        model,
+        context,
+    }) = args.command
+    {
+        let matching = trace_diff::run(
+            rom_file,
+            reference,
+            format.trace_format(),
+            model.map(ModelArg::hardware_model),
+            context,
+        );
+        process::exit(if matching { 0 } else { 1 });
+    }
+    let rom = args.rom_file.clone().expect("no ROM file");
+    if let Some(bank) = args.disassemble {
+        disassemble(&rom, bank);
+        return;
+    }
 
     // Application launch
     app_options_from_args(&args)
-        .title("RustBoyColor - SDL2")
+        .title("RustBoyColor - SDL2".into())
         .create_with_backend(Box::new(sdl2::BackendSDL2))
         .run(Path::new(&rom), true);
 }
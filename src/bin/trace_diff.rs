@@ -0,0 +1,106 @@
+//! The 'trace-diff' command : runs a cartridge headless alongside a reference
+//! CPU trace log, and shows where the emulation first diverges from it.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use rustboylib::cpu::Cpu;
+use rustboylib::mbc::{self, HeaderStrictness};
+use rustboylib::mmu::MMU;
+use rustboylib::model::HardwareModel;
+use rustboylib::trace::{TraceFormat, TraceLine, diff_trace};
+
+/// Run the given cartridge, from the state left by the boot ROM, against the
+/// given reference trace log. Return true if no mismatch was found.
+///
+/// With the 'Doctor' format, LY reads as 0x90 as when the reference logs were
+/// recorded.
+pub fn run(
+    rom_path: &Path,
+    reference_path: &Path,
+    format: TraceFormat,
+    model: Option<HardwareModel>,
+    context: usize,
+) -> bool {
+    let (info, mbc) = match mbc::load_cartridge(rom_path, HeaderStrictness::Warn) {
+        Ok(cartridge) => cartridge,
+        Err(why) => {
+            error!("cannot load the cartridge : {}", why);
+            return false;
+        }
+    };
+    let reference = match File::open(reference_path) {
+        Ok(file) => BufReader::new(file),
+        Err(why) => {
+            error!(
+                "cannot open the reference trace \"{}\" : {}",
+                reference_path.display(),
+                why
+            );
+            return false;
+        }
+    };
+    let model = model.unwrap_or_else(|| HardwareModel::from_cgb_flag(info.cgb_flag));
+    let mut cpu = Cpu::new(MMU::new(mbc, model, true, None));
+    cpu.post_bios(model);
+    if format == TraceFormat::Doctor {
+        cpu.mem.stub_ly(true);
+    }
+
+    let mismatch = match diff_trace(&mut cpu, format, reference, context) {
+        Ok(Some(mismatch)) => mismatch,
+        Ok(None) => {
+            println!(
+                "the emulation matches the reference trace \"{}\"",
+                reference_path.display()
+            );
+            return true;
+        }
+        Err(why) => {
+            error!(
+                "cannot read the reference trace \"{}\" : {}",
+                reference_path.display(),
+                why
+            );
+            return false;
+        }
+    };
+
+    println!(
+        "mismatch at line {} of \"{}\" : {}",
+        mismatch.number,
+        reference_path.display(),
+        mismatch.mismatching_fields().join(", ")
+    );
+    println!("  expected : {}", mismatch.expected);
+    match mismatch.actual {
+        Some(ref line) => println!("  actual   : {}", line.text),
+        None => println!("  actual   : none, the CPU stopped executing instructions"),
+    }
+    println!();
+    for line in mismatch.before.iter() {
+        print_line(' ', line);
+    }
+    if let Some(ref line) = mismatch.actual {
+        print_line('>', line);
+    }
+    for line in mismatch.after.iter() {
+        print_line(' ', line);
+    }
+    false
+}
+
+/// Print the given traced line, along with the disassembly of its
+/// instruction.
+fn print_line(marker: char, line: &TraceLine) {
+    let instruction = match (line.bank, line.instruction.as_ref()) {
+        (Some(bank), Some(instruction)) => format!("{:0>2X}:{}", bank, instruction),
+        (None, Some(instruction)) => format!("   {}", instruction),
+        (_, None) => String::new(),
+    };
+    println!(
+        "{} {:>10}  {:<36} {}",
+        marker, line.number, instruction, line.text
+    );
+}
@@ -6,15 +6,16 @@ mod registers;
 #[cfg(test)]
 mod test;
 
-#[cfg(feature = "tracing")]
-use std::fs::File;
-#[cfg(feature = "tracing")]
-use std::io::Write;
-
+use crate::error::Result;
 use crate::irq::Interrupt;
+use crate::mbc::CartridgeHeader;
 use crate::memory::Memory;
 use crate::mmu::MemoryManagementUnit;
-use registers::{C_FLAG, H_FLAG, N_FLAG, Registers, Z_FLAG};
+use crate::model::HardwareModel;
+use crate::state::{SaveState, StateReader, StateWriter};
+use crate::trace::Tracer;
+pub use registers::Registers;
+use registers::{C_FLAG, H_FLAG, N_FLAG, Z_FLAG};
 
 /// The CPU clock speed for the Game Boy (Classic), in Hz.
 pub const CPU_CLOCK_SPEED: u32 = 4_194_304;
@@ -44,9 +45,8 @@ pub struct Cpu<M> {
     /// The dispatching array used for decoding the CB-prefixed additional
     /// instructions.
     cb_dispatch_array: [CpuInstruction<M>; 256],
-    /// Output file for the CPU tracing.
-    #[cfg(feature = "tracing")]
-    trace_file: File,
+    /// The CPU trace logger, if tracing is set up.
+    tracer: Option<Tracer>,
 }
 
 impl<M> Cpu<M>
@@ -65,40 +65,45 @@ where
             opcode: 0x0,
             dispatch_array: dispatch_array(),
             cb_dispatch_array: cb_dispatch_array(),
-            #[cfg(feature = "tracing")]
-            trace_file: File::create("trace_cpu.log").expect("CPU tracing file creation error"),
+            tracer: None,
         }
     }
 
-    /// Simulate the effects of the power-up sequence.
-    /// Source: http://gbdev.gg8.se/wiki/articles/Power_Up_Sequence
-    /// TODO: CGB mode
-    pub fn post_bios(&mut self) {
-        self.regs.set_af(0x01B0);
-        self.regs.set_bc(0x0013);
-        self.regs.set_de(0x00D8);
-        self.regs.set_hl(0x014D);
+    /// Simulate the effects of the power-up sequence on the given hardware
+    /// model.
+    /// Source: https://gbdev.io/pandocs/Power_Up_Sequence.html
+    pub fn post_bios(&mut self, model: HardwareModel) {
+        use crate::model::HardwareModel::*;
+
+        let cgb_flag = self
+            .mem
+            .read_byte(CartridgeHeader::CGB_Flag.address() as u16);
+        let cgb_mode = model.cgb_mode(cgb_flag);
+        // DMG and MGB : the H and C flags are set if the header checksum is not 0
+        let header_checksum = self
+            .mem
+            .read_byte(CartridgeHeader::HeaderChecksum.address() as u16);
+        let f = if header_checksum == 0x00 { 0x80 } else { 0xB0 };
+        // the CGB models in DMG compatibility mode actually leave some values
+        // depending on the cartridge title, the most common ones are used here
+        let (af, bc, de, hl) = match model {
+            DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            DMG => (0x0100 | f, 0x0013, 0x00D8, 0x014D),
+            MGB => (0xFF00 | f, 0x0013, 0x00D8, 0x014D),
+            SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            CGB if cgb_mode => (0x1180, 0x0000, 0xFF56, 0x000D),
+            CGB => (0x1180, 0x0000, 0x0008, 0x007C),
+            AGB if cgb_mode => (0x1100, 0x0100, 0xFF56, 0x000D),
+            AGB => (0x1100, 0x0100, 0x0008, 0x007C),
+        };
+        self.regs.set_af(af);
+        self.regs.set_bc(bc);
+        self.regs.set_de(de);
+        self.regs.set_hl(hl);
         self.regs.pc = 0x100;
         self.regs.sp = 0xFFFE;
-        self.mem.write_byte(0xFF10, 0x80);
-        self.mem.write_byte(0xFF11, 0xBF);
-        self.mem.write_byte(0xFF12, 0xF3);
-        self.mem.write_byte(0xFF14, 0xBF);
-        self.mem.write_byte(0xFF16, 0x3F);
-        self.mem.write_byte(0xFF19, 0xBF);
-        self.mem.write_byte(0xFF1A, 0x7F);
-        self.mem.write_byte(0xFF1B, 0xFF);
-        self.mem.write_byte(0xFF1C, 0x9F);
-        self.mem.write_byte(0xFF1E, 0xBF);
-        self.mem.write_byte(0xFF20, 0xFF);
-        self.mem.write_byte(0xFF23, 0xBF);
-        self.mem.write_byte(0xFF24, 0x77);
-        self.mem.write_byte(0xFF25, 0xF3);
-        self.mem.write_byte(0xFF26, 0xF1);
-        self.mem.write_byte(0xFF40, 0x91);
-        self.mem.write_byte(0xFF47, 0xFC);
-        self.mem.write_byte(0xFF48, 0xFF);
-        self.mem.write_byte(0xFF49, 0xFF);
+        self.mem.post_bios(model);
     }
 
     /// Get an immutable reference to the Registers state.
@@ -106,6 +111,15 @@ where
         &self.regs
     }
 
+    /// Set up (or remove) the CPU trace logger.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
     /// Fetch the next byte in memory.
     fn fetch_byte(&mut self) -> u8 {
         let b = self.mem.read_byte(self.regs.pc);
@@ -119,8 +133,8 @@ where
         w
     }
 
-    /// Advance the machine simulation and return the number of CPU clock cycles
-    /// spent.
+    /// Advance the machine simulation and return the number of clock cycles
+    /// spent, at the normal CPU speed (even in CGB double speed mode).
     pub fn step(&mut self) -> CycleType {
         let clock_cycles = self.cpu_step() * 4;
         self.mem.step(clock_cycles)
@@ -141,25 +155,13 @@ where
             n => return n,
         }
 
-        self.opcode = self.fetch_byte();
-        #[cfg(feature = "tracing")]
-        {
-            write!(
-                &mut self.trace_file,
-                "OP={:0>2X} PC={:0>4X} AF={:0>4X} BC={:0>4X} DE={:0>4X} HL={:0>4X} SP={:0>4X}",
-                self.opcode,
-                self.regs.pc,
-                self.regs.af(),
-                self.regs.bc(),
-                self.regs.de(),
-                self.regs.hl(),
-                self.regs.sp,
-            );
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.trace_instruction(&self.regs, &mut self.mem);
         }
+        self.opcode = self.fetch_byte();
         let step_cycles = self.dispatch_array[self.opcode as usize](self);
-        #[cfg(feature = "tracing")]
-        {
-            writeln!(&mut self.trace_file, " CY={}", step_cycles);
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.trace_cycles(step_cycles);
         }
         self.cycles += step_cycles;
         step_cycles
@@ -175,9 +177,8 @@ where
         if interrupts == 0x00 {
             return 0;
         }
-        #[cfg(feature = "tracing")]
-        {
-            writeln!(&mut self.trace_file, "INT {:0>2X}", interrupts);
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.trace_interrupt(interrupts);
         }
 
         // check the interrupts by order of priority
@@ -364,6 +365,47 @@ where
     }
 }
 
+impl<M> Cpu<M>
+where
+    M: Memory + MemoryManagementUnit + SaveState,
+{
+    /// Snapshot the whole machine into a save state (see the 'state' module).
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.write_u64(self.cycles);
+        writer.write_bool(self.halted);
+        self.regs.save_state(&mut writer);
+        writer.write_bool(self.ime);
+        writer.write_u8(self.if_reg_before_halt);
+        writer.write_u8(self.opcode);
+        self.mem.save_state(&mut writer);
+        writer.into_data()
+    }
+
+    /// Restore the whole machine from a save state created by 'save_state'.
+    ///
+    /// If the save state is invalid, the machine is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let backup = self.save_state();
+        self.restore_state(data).inspect_err(|_| {
+            self.restore_state(&backup)
+                .expect("CPU : cannot restore the state before the failed load")
+        })
+    }
+
+    fn restore_state(&mut self, data: &[u8]) -> Result<()> {
+        let mut reader = StateReader::new(data)?;
+        self.cycles = reader.read_u64()?;
+        self.halted = reader.read_bool()?;
+        self.regs.load_state(&mut reader)?;
+        self.ime = reader.read_bool()?;
+        self.if_reg_before_halt = reader.read_u8()?;
+        self.opcode = reader.read_u8()?;
+        self.mem.load_state(&mut reader)?;
+        reader.finish()
+    }
+}
+
 /// The type of the methods used to execute a CPU instruction.
 /// The return value is the number of machine cycles spent.
 type CpuInstruction<M> = fn(&mut Cpu<M>) -> CycleType;
@@ -74,7 +74,9 @@ where
         1
     }
 
+    // STOP : also performs the CGB speed switch if prepared through KEY1
     pub fn STOP(&mut self) -> CycleType {
+        self.mem.switch_speed();
         1
     }
 
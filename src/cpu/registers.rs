@@ -1,5 +1,8 @@
 use std::fmt;
 
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
 /// Zero flag : set if the last operation evaluates to zero, otherwise
 /// is cleared.
 pub const Z_FLAG: u8 = 0b_1000_0000;
@@ -95,6 +98,27 @@ impl Registers {
     }
 }
 
+impl SaveState for Registers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.af());
+        writer.write_u16(self.bc());
+        writer.write_u16(self.de());
+        writer.write_u16(self.hl());
+        writer.write_u16(self.pc);
+        writer.write_u16(self.sp);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.set_af(reader.read_u16()?);
+        self.set_bc(reader.read_u16()?);
+        self.set_de(reader.read_u16()?);
+        self.set_hl(reader.read_u16()?);
+        self.pc = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        Ok(())
+    }
+}
+
 impl fmt::Debug for Registers {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -6,6 +6,7 @@ mod load; // load/store/move instructions
 use super::{Cpu, CycleType};
 use crate::memory::Memory;
 use crate::mmu::MemoryManagementUnit;
+use crate::model::HardwareModel;
 
 const OPCODE_END: u8 = 0xD3;
 const OPCODES_LIMIT: u32 = 100;
@@ -82,4 +83,12 @@ impl MemoryManagementUnit for TestMemory {
         0
     }
     fn set_interrupt_flag(&mut self, _: u8) {}
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+    fn post_bios(&mut self, _: HardwareModel) {}
+    fn frame_count(&self) -> u64 {
+        0
+    }
+    fn pause_memory_accesses(&mut self, _: bool) {}
 }
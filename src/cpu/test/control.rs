@@ -262,3 +262,33 @@ test_RST! {
     test_RST_30H: (0xF7, 0x0030),
     test_RST_38H: (0xFF, 0x0038),
 }
+
+// post_bios : CPU registers as left by the boot ROM of each model
+#[test]
+fn test_post_bios_registers() {
+    use crate::model::HardwareModel::{self, *};
+
+    let post_bios = |model: HardwareModel, cgb_flag: u8, header_checksum: u8| {
+        let mut machine = super::TestMachine::with_instructions(&[]);
+        machine.cpu.mem.write_byte(0x0143, cgb_flag);
+        machine.cpu.mem.write_byte(0x014D, header_checksum);
+        machine.cpu.post_bios(model);
+        let regs = machine.cpu.registers();
+        (regs.af(), regs.bc(), regs.de(), regs.hl())
+    };
+    assert_eq!(
+        post_bios(DMG0, 0x00, 0x00),
+        (0x0100, 0xFF13, 0x00C1, 0x8403)
+    );
+    assert_eq!(post_bios(DMG, 0x00, 0x4E), (0x01B0, 0x0013, 0x00D8, 0x014D));
+    assert_eq!(post_bios(DMG, 0x00, 0x00), (0x0180, 0x0013, 0x00D8, 0x014D));
+    assert_eq!(post_bios(MGB, 0x00, 0x4E).0, 0xFFB0);
+    assert_eq!(post_bios(SGB, 0x00, 0x4E), (0x0100, 0x0014, 0x0000, 0xC060));
+    assert_eq!(post_bios(SGB2, 0x00, 0x4E).0, 0xFF00);
+    assert_eq!(post_bios(CGB, 0x80, 0x4E), (0x1180, 0x0000, 0xFF56, 0x000D));
+    assert_eq!(post_bios(CGB, 0x00, 0x4E), (0x1180, 0x0000, 0x0008, 0x007C));
+    assert_eq!(post_bios(AGB, 0xC0, 0x4E), (0x1100, 0x0100, 0xFF56, 0x000D));
+    let machine = super::TestMachine::with_instructions(&[]).init_cpu(|cpu| cpu.post_bios(DMG));
+    assert_eq!(machine.cpu.regs.pc, 0x0100);
+    assert_eq!(machine.cpu.regs.sp, 0xFFFE);
+}
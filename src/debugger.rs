@@ -0,0 +1,658 @@
+//! Debugger : breakpoints, watchpoints and stepping on top of the CPU.
+//!
+//! While it has something to do, the debugger drives the emulation one
+//! instruction at a time through 'Debugger::step', and reports when the
+//! execution should be suspended.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::{Cpu, CycleType, Registers};
+use crate::disasm::{Flow, disassemble};
+use crate::memory::{Memory, MemoryAccess};
+use crate::mmu::{MMU, MemoryManagementUnit};
+
+/// A CPU register (or register pair) tested by a breakpoint condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+}
+
+impl Register {
+    /// Get the register from its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<Register> {
+        use self::Register::*;
+        Some(match name.to_ascii_uppercase().as_str() {
+            "A" => A,
+            "F" => F,
+            "B" => B,
+            "C" => C,
+            "D" => D,
+            "E" => E,
+            "H" => H,
+            "L" => L,
+            "AF" => AF,
+            "BC" => BC,
+            "DE" => DE,
+            "HL" => HL,
+            "SP" => SP,
+            "PC" => PC,
+            _ => return None,
+        })
+    }
+
+    pub fn value(self, regs: &Registers) -> u16 {
+        use self::Register::*;
+        match self {
+            A => regs.a as u16,
+            F => regs.f as u16,
+            B => regs.b as u16,
+            C => regs.c as u16,
+            D => regs.d as u16,
+            E => regs.e as u16,
+            H => regs.h as u16,
+            L => regs.l as u16,
+            AF => regs.af(),
+            BC => regs.bc(),
+            DE => regs.de(),
+            HL => regs.hl(),
+            SP => regs.sp,
+            PC => regs.pc,
+        }
+    }
+
+    /// Set the register to the given value, truncated to 8 bits for a single
+    /// register. The lower 4 bits of F always stay cleared.
+    pub fn set_value(self, regs: &mut Registers, value: u16) {
+        use self::Register::*;
+        match self {
+            A => regs.a = value as u8,
+            F => regs.f = (value & 0x00F0) as u8,
+            B => regs.b = value as u8,
+            C => regs.c = value as u8,
+            D => regs.d = value as u8,
+            E => regs.e = value as u8,
+            H => regs.h = value as u8,
+            L => regs.l = value as u8,
+            AF => regs.set_af(value),
+            BC => regs.set_bc(value),
+            DE => regs.set_de(value),
+            HL => regs.set_hl(value),
+            SP => regs.sp = value,
+            PC => regs.pc = value,
+        }
+    }
+
+    /// Is the register 16 bits wide ?
+    pub fn is_pair(self) -> bool {
+        use self::Register::*;
+        matches!(self, AF | BC | DE | HL | SP | PC)
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The comparison operator of a breakpoint condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// Get the comparison from its symbol ("==", "!=", "<", "<=", ">", ">=").
+    pub fn from_symbol(symbol: &str) -> Option<Comparison> {
+        use self::Comparison::*;
+        Some(match symbol {
+            "==" => Equal,
+            "!=" => NotEqual,
+            "<" => Less,
+            "<=" => LessOrEqual,
+            ">" => Greater,
+            ">=" => GreaterOrEqual,
+            _ => return None,
+        })
+    }
+
+    pub fn symbol(self) -> &'static str {
+        use self::Comparison::*;
+        match self {
+            Equal => "==",
+            NotEqual => "!=",
+            Less => "<",
+            LessOrEqual => "<=",
+            Greater => ">",
+            GreaterOrEqual => ">=",
+        }
+    }
+
+    fn compare(self, a: u16, b: u16) -> bool {
+        use self::Comparison::*;
+        match self {
+            Equal => a == b,
+            NotEqual => a != b,
+            Less => a < b,
+            LessOrEqual => a <= b,
+            Greater => a > b,
+            GreaterOrEqual => a >= b,
+        }
+    }
+}
+
+/// A condition on the value of a register, e.g. "A == 0x12".
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    pub fn is_met(&self, regs: &Registers) -> bool {
+        self.comparison
+            .compare(self.register.value(regs), self.value)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} ", self.register, self.comparison.symbol())?;
+        if self.register.is_pair() {
+            write!(f, "0x{:0>4X}", self.value)
+        } else {
+            write!(f, "0x{:0>2X}", self.value)
+        }
+    }
+}
+
+/// Suspends the execution when the CPU is about to execute the instruction
+/// at the given address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// If set and the address is in the cartridge ROM (0x0000-0x7FFF), the
+    /// ROM bank which must be mapped there.
+    pub bank: Option<usize>,
+    /// If set, the condition which must be met.
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint {
+            address,
+            bank: None,
+            condition: None,
+        }
+    }
+
+    fn is_hit(&self, cpu: &Cpu<MMU>) -> bool {
+        let pc = cpu.regs.pc;
+        pc == self.address
+            && self
+                .bank
+                .is_none_or(|bank| pc >= 0x8000 || cpu.mem.rom_bank(pc) == bank)
+            && self
+                .condition
+                .is_none_or(|condition| condition.is_met(&cpu.regs))
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(bank) = self.bank {
+            write!(f, "{:0>2X}:", bank)?;
+        }
+        write!(f, "{:0>4X}", self.address)?;
+        if let Some(ref condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        Ok(())
+    }
+}
+
+/// Suspends the execution when the CPU reads and/or writes in the given
+/// address range.
+///
+/// NB : the instructions fetched by the CPU count as reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn is_hit(&self, access: &MemoryAccess) -> bool {
+        let watched = match *access {
+            MemoryAccess::Read(_) => self.read,
+            MemoryAccess::Write(..) => self.write,
+        };
+        watched && self.range.contains(&access.address())
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = match (self.read, self.write) {
+            (true, true) => "read/write",
+            (true, false) => "read",
+            (false, _) => "write",
+        };
+        write!(f, "{} {:0>4X}", access, self.range.start())?;
+        if self.range.end() != self.range.start() {
+            write!(f, "-{:0>4X}", self.range.end())?;
+        }
+        Ok(())
+    }
+}
+
+/// The reason why the debugger suspended the execution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Break {
+    /// The breakpoint of the given ID was hit.
+    Breakpoint(usize),
+    /// The watchpoint of the given ID was hit by the given access.
+    Watchpoint(usize, MemoryAccess),
+    /// The requested step (into, over or out) is done.
+    Step,
+    /// The requested frame was reached.
+    Frame(u64),
+}
+
+impl fmt::Display for Break {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Break::Breakpoint(id) => write!(f, "breakpoint #{}", id),
+            Break::Watchpoint(id, MemoryAccess::Read(address)) => {
+                write!(f, "watchpoint #{} : read at {:0>4X}", id, address)
+            }
+            Break::Watchpoint(id, MemoryAccess::Write(address, value)) => write!(
+                f,
+                "watchpoint #{} : write of {:0>2X} at {:0>4X}",
+                id, value, address
+            ),
+            Break::Step => write!(f, "step"),
+            Break::Frame(frame) => write!(f, "frame {}", frame),
+        }
+    }
+}
+
+/// What the debugger is running until.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunMode {
+    /// Until a breakpoint or watchpoint is hit.
+    Continue,
+    /// Until the next instruction is executed.
+    StepInto,
+    /// Until the next instruction is executed, and the subroutine it called
+    /// (if any) returned.
+    StepOver,
+    /// Until the CPU gets back to the given address and stack pointer.
+    Return { address: u16, sp: u16 },
+    /// Until the current subroutine (with the given stack pointer) returns.
+    StepOut { sp: u16 },
+    /// Until the given frame is reached.
+    Frame(u64),
+}
+
+/// The debugger, holding the breakpoints and watchpoints.
+///
+/// Breakpoints and watchpoints share the same numbering.
+pub struct Debugger {
+    breakpoints: BTreeMap<usize, Breakpoint>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    /// The ID of the next breakpoint or watchpoint.
+    next_id: usize,
+    mode: RunMode,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Debugger::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            next_id: 1,
+            mode: RunMode::Continue,
+        }
+    }
+
+    /// Add the given breakpoint and return its ID.
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) -> usize {
+        self.next_id += 1;
+        self.breakpoints.insert(self.next_id - 1, breakpoint);
+        self.next_id - 1
+    }
+
+    /// Add the given watchpoint and return its ID.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.watchpoints.insert(self.next_id - 1, watchpoint);
+        self.next_id - 1
+    }
+
+    /// Remove the breakpoint or watchpoint of the given ID. Return false if
+    /// there is none.
+    pub fn remove(&mut self, id: usize) -> bool {
+        self.breakpoints.remove(&id).is_some() || self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (usize, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .map(|(&id, breakpoint)| (id, breakpoint))
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.watchpoints
+            .iter()
+            .map(|(&id, watchpoint)| (id, watchpoint))
+    }
+
+    /// Must the emulation go through 'step' ? If false, the CPU can be
+    /// stepped directly.
+    pub fn is_active(&self) -> bool {
+        self.mode != RunMode::Continue
+            || !self.breakpoints.is_empty()
+            || !self.watchpoints.is_empty()
+    }
+
+    /// Run until a breakpoint or watchpoint is hit.
+    pub fn resume(&mut self) {
+        self.mode = RunMode::Continue;
+    }
+
+    /// Run a single instruction, entering the subroutines.
+    pub fn step_into(&mut self) {
+        self.mode = RunMode::StepInto;
+    }
+
+    /// Run a single instruction, running the subroutine it calls (or the
+    /// interrupt handler it is interrupted by) until it returns.
+    pub fn step_over(&mut self) {
+        self.mode = RunMode::StepOver;
+    }
+
+    /// Run until the current subroutine returns.
+    pub fn step_out(&mut self, cpu: &Cpu<MMU>) {
+        self.mode = RunMode::StepOut { sp: cpu.regs.sp };
+    }
+
+    /// Run until the given frame (as counted by
+    /// 'MemoryManagementUnit::frame_count') is reached.
+    pub fn run_to_frame(&mut self, frame: u64) {
+        self.mode = RunMode::Frame(frame);
+    }
+
+    /// Advance the machine simulation by a single instruction (see
+    /// 'Cpu::step') and return the number of clock cycles spent, along with
+    /// the reason to suspend the execution, if any.
+    pub fn step(&mut self, cpu: &mut Cpu<MMU>) -> (CycleType, Option<Break>) {
+        let (pc, sp) = (cpu.regs.pc, cpu.regs.sp);
+        let instruction = match self.mode {
+            RunMode::StepOver | RunMode::StepOut { .. } => Some(disassemble(&mut cpu.mem, pc)),
+            _ => None,
+        };
+        let flow = instruction.as_ref().map(|instruction| instruction.flow);
+        cpu.mem.log_memory_accesses(!self.watchpoints.is_empty());
+        cpu.mem.take_memory_accesses();
+
+        let cycles = cpu.step();
+
+        let watch_break = cpu.mem.take_memory_accesses().iter().find_map(|access| {
+            self.watchpoints
+                .iter()
+                .find(|(_, watchpoint)| watchpoint.is_hit(access))
+                .map(|(&id, _)| Break::Watchpoint(id, *access))
+        });
+        // a halted CPU does not execute the instruction at PC
+        let breakpoint_break = if cpu.halted {
+            None
+        } else {
+            self.breakpoints
+                .iter()
+                .find(|(_, breakpoint)| breakpoint.is_hit(cpu))
+                .map(|(&id, _)| Break::Breakpoint(id))
+        };
+        let mode_break = match self.mode {
+            RunMode::Continue => None,
+            RunMode::StepInto => Some(Break::Step),
+            RunMode::StepOver => {
+                // a subroutine was called, or an interrupt handler entered
+                let return_address = cpu.mem.read_word(cpu.regs.sp);
+                let called = cpu.regs.sp == sp.wrapping_sub(2)
+                    && (return_address == pc
+                        || instruction.is_some_and(|instruction| {
+                            instruction.flow == Flow::Call
+                                && return_address == pc.wrapping_add(instruction.length())
+                        }));
+                if called {
+                    self.mode = RunMode::Return {
+                        address: return_address,
+                        sp,
+                    };
+                    None
+                } else {
+                    Some(Break::Step)
+                }
+            }
+            RunMode::Return { address, sp } => {
+                (cpu.regs.pc == address && cpu.regs.sp == sp).then_some(Break::Step)
+            }
+            RunMode::StepOut { sp: frame_sp } => {
+                (flow == Some(Flow::Return) && cpu.regs.sp > frame_sp).then_some(Break::Step)
+            }
+            RunMode::Frame(frame) => {
+                (cpu.mem.frame_count() >= frame).then(|| Break::Frame(cpu.mem.frame_count()))
+            }
+        };
+
+        let reason = watch_break.or(breakpoint_break).or(mode_break);
+        if reason.is_some() {
+            self.mode = RunMode::Continue;
+        }
+        (cycles, reason)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use crate::cpu::Cpu;
+    use crate::mbc::{HeaderStrictness, load_cartridge_from_bytes};
+    use crate::memory::MemoryAccess;
+    use crate::mmu::{MMU, MemoryManagementUnit};
+    use crate::model::HardwareModel;
+    use crate::trace::{TraceFormat, Tracer};
+
+    use super::{Break, Breakpoint, Comparison, Condition, Debugger, Register, Watchpoint};
+
+    /// 0100: NOP
+    /// 0101: CALL 0110
+    /// 0104: LD HL,C000
+    /// 0107: LD (HL),A
+    /// 0108: INC A
+    /// 0109: JR 0104
+    /// 0110: LD A,05
+    /// 0112: RET
+    const PROGRAM: [(u16, &[u8]); 2] = [
+        (
+            0x0100,
+            &[
+                0x00, 0xCD, 0x10, 0x01, 0x21, 0x00, 0xC0, 0x77, 0x3C, 0x18, 0xF9,
+            ],
+        ),
+        (0x0110, &[0x3E, 0x05, 0xC9]),
+    ];
+
+    fn make_cpu() -> Cpu<MMU> {
+        let mut rom = vec![0x00; 0x8000];
+        for (address, code) in PROGRAM.iter() {
+            let address = *address as usize;
+            rom[address..address + code.len()].copy_from_slice(code);
+        }
+        let (_, mbc) = load_cartridge_from_bytes(rom, HeaderStrictness::Warn).unwrap();
+        let mut cpu = Cpu::new(MMU::new(mbc, HardwareModel::DMG, true, None));
+        cpu.post_bios(HardwareModel::DMG);
+        cpu
+    }
+
+    /// Step until the debugger breaks.
+    fn run(debugger: &mut Debugger, cpu: &mut Cpu<MMU>) -> Break {
+        for _ in 0..1_000_000 {
+            if let (_, Some(reason)) = debugger.step(cpu) {
+                return reason;
+            }
+        }
+        panic!("the debugger did not break");
+    }
+
+    #[test]
+    fn test_debugger_stepping() {
+        let mut cpu = make_cpu();
+        let mut debugger = Debugger::new();
+        assert!(!debugger.is_active());
+
+        debugger.step_into();
+        assert!(debugger.is_active());
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Step);
+        assert_eq!(cpu.regs.pc, 0x0101);
+        assert!(!debugger.is_active());
+
+        debugger.step_over();
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Step);
+        assert_eq!((cpu.regs.pc, cpu.regs.a), (0x0104, 0x05));
+        debugger.step_over();
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Step);
+        assert_eq!(cpu.regs.pc, 0x0107);
+
+        let mut cpu = make_cpu();
+        debugger.step_into();
+        run(&mut debugger, &mut cpu);
+        debugger.step_into();
+        run(&mut debugger, &mut cpu);
+        assert_eq!(cpu.regs.pc, 0x0110);
+        debugger.step_out(&cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Step);
+        assert_eq!((cpu.regs.pc, cpu.regs.sp), (0x0104, 0xFFFE));
+    }
+
+    #[test]
+    fn test_debugger_breakpoints() {
+        let mut cpu = make_cpu();
+        let mut debugger = Debugger::new();
+        let id = debugger.add_breakpoint(Breakpoint {
+            address: 0x0107,
+            bank: None,
+            condition: Some(Condition {
+                register: Register::A,
+                comparison: Comparison::GreaterOrEqual,
+                value: 0x07,
+            }),
+        });
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Breakpoint(id));
+        assert_eq!((cpu.regs.pc, cpu.regs.a), (0x0107, 0x07));
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Breakpoint(id));
+        assert_eq!(cpu.regs.a, 0x08);
+
+        // the ROM bank 1 is not mapped at 0x0104
+        assert!(debugger.remove(id));
+        assert!(!debugger.remove(id));
+        debugger.add_breakpoint(Breakpoint {
+            bank: Some(1),
+            ..Breakpoint::new(0x0104)
+        });
+        let id = debugger.add_breakpoint(Breakpoint {
+            bank: Some(0),
+            ..Breakpoint::new(0x0104)
+        });
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Breakpoint(id));
+        assert_eq!(
+            debugger.breakpoints().next().unwrap().1.to_string(),
+            "01:0104"
+        );
+    }
+
+    #[test]
+    fn test_debugger_watchpoints() {
+        let mut cpu = make_cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(Watchpoint {
+            range: 0xC000..=0xC0FF,
+            read: true,
+            write: false,
+        });
+        let id = debugger.add_watchpoint(Watchpoint {
+            range: 0xBFFF..=0xC000,
+            read: false,
+            write: true,
+        });
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            Break::Watchpoint(id, MemoryAccess::Write(0xC000, 0x05))
+        );
+        assert_eq!(cpu.regs.pc, 0x0108);
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            Break::Watchpoint(id, MemoryAccess::Write(0xC000, 0x06))
+        );
+    }
+
+    #[test]
+    fn test_debugger_watchpoints_with_tracer() {
+        let mut cpu = make_cpu();
+        cpu.set_tracer(Some(Tracer::new(TraceFormat::Doctor, Box::new(io::sink()))));
+        let mut debugger = Debugger::new();
+        // read by the tracer along with the CALL at 0101, but only fetched
+        // by the CPU after the return
+        let id = debugger.add_watchpoint(Watchpoint {
+            range: 0x0104..=0x0104,
+            read: true,
+            write: false,
+        });
+        assert_eq!(
+            run(&mut debugger, &mut cpu),
+            Break::Watchpoint(id, MemoryAccess::Read(0x0104))
+        );
+        assert_eq!(cpu.regs.pc, 0x0107);
+    }
+
+    #[test]
+    fn test_debugger_run_to_frame() {
+        let mut cpu = make_cpu();
+        let mut debugger = Debugger::new();
+        let frame = cpu.mem.frame_count() + 2;
+        debugger.run_to_frame(frame);
+        assert_eq!(run(&mut debugger, &mut cpu), Break::Frame(frame));
+        assert_eq!(cpu.mem.frame_count(), frame);
+    }
+}
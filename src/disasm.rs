@@ -0,0 +1,511 @@
+//! Disassembler for the Sharp LR35902 instruction set.
+//!
+//! The instructions are decoded from the bit fields of their opcode, as
+//! described in :
+//! https://gbdev.io/pandocs/CPU_Instruction_Set.html
+//! https://gb-archive.github.io/salvage/decoding_gbz80_opcodes/Decoding%20Gamboy%20Z80%20Opcodes.html
+
+use std::fmt;
+
+use crate::cpu::CycleType;
+use crate::memory::Memory;
+
+/// The size of a cartridge ROM bank.
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+/// The 8-bit operands, indexed by the 3-bit register fields of the opcodes.
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+/// The 16-bit operands of the arithmetic and load instructions.
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+/// The 16-bit operands of the stack instructions.
+const R16_STACK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+/// The indirect operands of the 'LD (r16),A' and 'LD A,(r16)' instructions.
+const R16_MEMORY: [&str; 4] = ["(BC)", "(DE)", "(HL+)", "(HL-)"];
+/// The branch conditions.
+const CONDITIONS: [&str; 4] = ["NZ", "Z", "NC", "C"];
+/// The 8-bit arithmetic and logical operations on register A.
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB ", "SBC A,", "AND ", "XOR ", "OR ", "CP ",
+];
+/// The rotate and shift operations of the CB-prefixed instructions.
+const ROTATIONS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+/// The quick operations on register A.
+const ACCUMULATOR_OPERATIONS: [&str; 8] =
+    ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+/// How an instruction affects the control flow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// The execution continues with the next instruction.
+    Next,
+    /// Jump (JP, JR), possibly conditional.
+    Jump,
+    /// Subroutine call (CALL, RST), possibly conditional.
+    Call,
+    /// Return from a subroutine (RET, RETI), possibly conditional.
+    Return,
+}
+
+/// A decoded instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Instruction {
+    /// The address of the instruction.
+    pub address: u16,
+    /// The opcode (and prefixed opcode, or operands) of the instruction.
+    bytes: [u8; 3],
+    /// The length of the instruction, in bytes (1 to 3).
+    length: usize,
+    /// The instruction in assembly language, e.g. "LD A,(HL+)".
+    pub text: String,
+    /// The number of machine cycles spent, if the branch (if any) is not
+    /// taken.
+    pub cycles: CycleType,
+    /// The number of machine cycles spent if the conditional branch is taken.
+    pub branch_cycles: Option<CycleType>,
+    pub flow: Flow,
+    /// The destination of the jump or call, if not computed at run time
+    /// (JP HL and the returns).
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    /// The bytes of the instruction.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+
+    pub fn length(&self) -> u16 {
+        self.length as u16
+    }
+
+    /// Is the instruction a conditional branch ?
+    pub fn is_conditional(&self) -> bool {
+        self.branch_cycles.is_some()
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Format the instruction as a listing line, e.g. "0150: C3 13 02  JP $0213".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self
+            .bytes()
+            .iter()
+            .map(|byte| format!("{:0>2X}", byte))
+            .collect();
+        write!(
+            f,
+            "{:0>4X}: {:<8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Decode the instruction at the given address.
+///
+/// NB : STOP is decoded as a single byte instruction, like the CPU emulation
+/// executes it.
+pub fn disassemble<M: Memory>(mem: &mut M, address: u16) -> Instruction {
+    let opcode = mem.read_byte(address);
+    let mut instruction = Instruction {
+        address,
+        bytes: [opcode, 0x00, 0x00],
+        length: 1,
+        text: String::new(),
+        cycles: 1,
+        branch_cycles: None,
+        flow: Flow::Next,
+        target: None,
+    };
+    let (x, y, z) = (opcode >> 6, ((opcode >> 3) & 0x07) as usize, opcode & 0x07);
+    let (p, q) = (y >> 1, y & 0x01);
+    // (HL) operands take 1 (or 2 if read then written) more machine cycles
+    let hl_cycles = |register: usize, cycles: CycleType| if register == 6 { cycles } else { 0 };
+
+    let (text, cycles) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP".into(), 1),
+            1 => {
+                let nn = n16(mem, &mut instruction);
+                (format!("LD (${:0>4X}),SP", nn), 5)
+            }
+            2 => ("STOP".into(), 1),
+            _ => {
+                let offset = n8(mem, &mut instruction) as i8;
+                let target = address.wrapping_add(2).wrapping_add(offset as i16 as u16);
+                instruction.flow = Flow::Jump;
+                instruction.target = Some(target);
+                if y == 3 {
+                    (format!("JR ${:0>4X}", target), 3)
+                } else {
+                    instruction.branch_cycles = Some(3);
+                    (format!("JR {},${:0>4X}", CONDITIONS[y - 4], target), 2)
+                }
+            }
+        },
+        (0, 1) if q == 0 => {
+            let nn = n16(mem, &mut instruction);
+            (format!("LD {},${:0>4X}", R16[p], nn), 3)
+        }
+        (0, 1) => (format!("ADD HL,{}", R16[p]), 2),
+        (0, 2) if q == 0 => (format!("LD {},A", R16_MEMORY[p]), 2),
+        (0, 2) => (format!("LD A,{}", R16_MEMORY[p]), 2),
+        (0, 3) if q == 0 => (format!("INC {}", R16[p]), 2),
+        (0, 3) => (format!("DEC {}", R16[p]), 2),
+        (0, 4) => (format!("INC {}", R8[y]), 1 + hl_cycles(y, 2)),
+        (0, 5) => (format!("DEC {}", R8[y]), 1 + hl_cycles(y, 2)),
+        (0, 6) => {
+            let n = n8(mem, &mut instruction);
+            (format!("LD {},${:0>2X}", R8[y], n), 2 + hl_cycles(y, 1))
+        }
+        (0, _) => (ACCUMULATOR_OPERATIONS[y].into(), 1),
+        (1, 6) if y == 6 => ("HALT".into(), 1),
+        (1, _) => (
+            format!("LD {},{}", R8[y], R8[z as usize]),
+            1 + hl_cycles(y, 1) + hl_cycles(z as usize, 1),
+        ),
+        (2, _) => (
+            format!("{}{}", ALU[y], R8[z as usize]),
+            1 + hl_cycles(z as usize, 1),
+        ),
+        (3, 0) => match y {
+            0..=3 => {
+                instruction.flow = Flow::Return;
+                instruction.branch_cycles = Some(5);
+                (format!("RET {}", CONDITIONS[y]), 2)
+            }
+            4 => {
+                let n = n8(mem, &mut instruction);
+                (format!("LDH (${:0>4X}),A", 0xFF00 | n as u16), 3)
+            }
+            5 => {
+                let n = n8(mem, &mut instruction) as i8;
+                (format!("ADD SP,{}", signed(n)), 4)
+            }
+            6 => {
+                let n = n8(mem, &mut instruction);
+                (format!("LDH A,(${:0>4X})", 0xFF00 | n as u16), 3)
+            }
+            _ => {
+                let n = n8(mem, &mut instruction) as i8;
+                (format!("LD HL,SP{}", signed(n)), 3)
+            }
+        },
+        (3, 1) if q == 0 => (format!("POP {}", R16_STACK[p]), 3),
+        (3, 1) => match p {
+            0 | 1 => {
+                instruction.flow = Flow::Return;
+                (if p == 0 { "RET" } else { "RETI" }.into(), 4)
+            }
+            2 => {
+                instruction.flow = Flow::Jump;
+                ("JP HL".into(), 1)
+            }
+            _ => ("LD SP,HL".into(), 2),
+        },
+        (3, 2) => match y {
+            0..=3 => {
+                let nn = n16(mem, &mut instruction);
+                instruction.flow = Flow::Jump;
+                instruction.target = Some(nn);
+                instruction.branch_cycles = Some(4);
+                (format!("JP {},${:0>4X}", CONDITIONS[y], nn), 3)
+            }
+            4 => ("LD ($FF00+C),A".into(), 2),
+            5 => {
+                let nn = n16(mem, &mut instruction);
+                (format!("LD (${:0>4X}),A", nn), 4)
+            }
+            6 => ("LD A,($FF00+C)".into(), 2),
+            _ => {
+                let nn = n16(mem, &mut instruction);
+                (format!("LD A,(${:0>4X})", nn), 4)
+            }
+        },
+        (3, 3) => match y {
+            0 => {
+                let nn = n16(mem, &mut instruction);
+                instruction.flow = Flow::Jump;
+                instruction.target = Some(nn);
+                (format!("JP ${:0>4X}", nn), 4)
+            }
+            1 => {
+                let cb_opcode = n8(mem, &mut instruction);
+                decode_cb(cb_opcode)
+            }
+            6 => ("DI".into(), 1),
+            7 => ("EI".into(), 1),
+            _ => (unknown(opcode), 1),
+        },
+        (3, 4) if y < 4 => {
+            let nn = n16(mem, &mut instruction);
+            instruction.flow = Flow::Call;
+            instruction.target = Some(nn);
+            instruction.branch_cycles = Some(6);
+            (format!("CALL {},${:0>4X}", CONDITIONS[y], nn), 3)
+        }
+        (3, 5) if q == 0 => (format!("PUSH {}", R16_STACK[p]), 4),
+        (3, 5) if p == 0 => {
+            let nn = n16(mem, &mut instruction);
+            instruction.flow = Flow::Call;
+            instruction.target = Some(nn);
+            (format!("CALL ${:0>4X}", nn), 6)
+        }
+        (3, 6) => {
+            let n = n8(mem, &mut instruction);
+            (format!("{}${:0>2X}", ALU[y], n), 2)
+        }
+        (3, 7) => {
+            instruction.flow = Flow::Call;
+            instruction.target = Some((y as u16) * 8);
+            (format!("RST ${:0>2X}", y * 8), 4)
+        }
+        _ => (unknown(opcode), 1),
+    };
+    instruction.text = text;
+    instruction.cycles = cycles;
+    instruction
+}
+
+/// Read the next byte of an instruction, i.e. an 8-bit immediate operand or
+/// the opcode of a CB-prefixed instruction.
+fn n8<M: Memory>(mem: &mut M, instruction: &mut Instruction) -> u8 {
+    let byte = mem.read_byte(instruction.address.wrapping_add(instruction.length as u16));
+    instruction.bytes[instruction.length] = byte;
+    instruction.length += 1;
+    byte
+}
+
+/// Read the 16-bit immediate operand of an instruction.
+fn n16<M: Memory>(mem: &mut M, instruction: &mut Instruction) -> u16 {
+    let low = n8(mem, instruction) as u16;
+    low | ((n8(mem, instruction) as u16) << 8)
+}
+
+/// Format a signed 8-bit operand, e.g. "+$05" or "-$10".
+fn signed(n: i8) -> String {
+    if n < 0 {
+        format!("-${:0>2X}", n.unsigned_abs())
+    } else {
+        format!("+${:0>2X}", n)
+    }
+}
+
+/// The text of the opcodes which do not correspond to any instruction.
+fn unknown(opcode: u8) -> String {
+    format!("DB ${:0>2X}", opcode)
+}
+
+/// Decode a CB-prefixed instruction, returning its text and machine cycles.
+fn decode_cb(opcode: u8) -> (String, CycleType) {
+    let (x, y, z) = (opcode >> 6, (opcode >> 3) & 0x07, (opcode & 0x07) as usize);
+    let (text, hl_cycles) = match x {
+        0 => (format!("{} {}", ROTATIONS[y as usize], R8[z]), 2),
+        1 => (format!("BIT {},{}", y, R8[z]), 1),
+        2 => (format!("RES {},{}", y, R8[z]), 2),
+        _ => (format!("SET {},{}", y, R8[z]), 2),
+    };
+    (text, if z == 6 { 2 + hl_cycles } else { 2 })
+}
+
+/// A read-only view of a cartridge ROM, with the given bank mapped at
+/// 0x4000-0x7FFF. The other addresses read as 0xFF.
+pub struct RomBank<'a> {
+    rom: &'a [u8],
+    bank: usize,
+}
+
+impl<'a> RomBank<'a> {
+    /// Return None if the ROM has no such bank.
+    pub fn new(rom: &'a [u8], bank: usize) -> Option<RomBank<'a>> {
+        if (bank + 1) * ROM_BANK_SIZE > rom.len().max(ROM_BANK_SIZE * 2) {
+            return None;
+        }
+        Some(RomBank { rom, bank })
+    }
+
+    /// The address range at which the bank is mapped.
+    pub fn addresses(&self) -> std::ops::Range<u16> {
+        if self.bank == 0 {
+            0x0000..0x4000
+        } else {
+            0x4000..0x8000
+        }
+    }
+
+    /// Decode the whole bank, instruction after instruction.
+    pub fn disassemble(&mut self) -> Vec<Instruction> {
+        let mut instructions = Vec::new();
+        let end = self.addresses().end as usize;
+        let mut address = self.addresses().start as usize;
+        while address < end {
+            let instruction = disassemble(self, address as u16);
+            address += instruction.length;
+            instructions.push(instruction);
+        }
+        instructions
+    }
+}
+
+impl Memory for RomBank<'_> {
+    fn read_byte(&mut self, address: u16) -> u8 {
+        let index = match address {
+            0x0000..=0x3FFF => address as usize,
+            0x4000..=0x7FFF => self.bank.max(1) * ROM_BANK_SIZE + (address as usize - 0x4000),
+            _ => return 0xFF,
+        };
+        self.rom.get(index).copied().unwrap_or(0xFF)
+    }
+    fn write_byte(&mut self, _: u16, _: u8) {}
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::{Cpu, CycleType};
+    use crate::memory::Memory;
+    use crate::mmu::MemoryManagementUnit;
+    use crate::model::HardwareModel;
+
+    use super::{Flow, RomBank, disassemble};
+
+    struct TestMemory {
+        memory: Vec<u8>,
+    }
+
+    impl Memory for TestMemory {
+        fn read_byte(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+        fn write_byte(&mut self, address: u16, byte: u8) {
+            self.memory[address as usize] = byte;
+        }
+    }
+
+    impl MemoryManagementUnit for TestMemory {
+        fn step(&mut self, ticks: CycleType) -> CycleType {
+            ticks
+        }
+        fn interrupt_enable(&self) -> u8 {
+            0
+        }
+        fn interrupt_flag(&self) -> u8 {
+            0
+        }
+        fn set_interrupt_flag(&mut self, _: u8) {}
+        fn switch_speed(&mut self) -> bool {
+            false
+        }
+        fn post_bios(&mut self, _: HardwareModel) {}
+        fn frame_count(&self) -> u64 {
+            0
+        }
+        fn pause_memory_accesses(&mut self, _: bool) {}
+    }
+
+    fn disassemble_bytes(bytes: &[u8]) -> super::Instruction {
+        let mut memory = TestMemory {
+            memory: vec![0x00; 0x10000],
+        };
+        memory.memory[0x0150..0x0150 + bytes.len()].copy_from_slice(bytes);
+        disassemble(&mut memory, 0x0150)
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cases: [(&[u8], &str); 16] = [
+            (&[0x00], "NOP"),
+            (&[0x01, 0x34, 0x12], "LD BC,$1234"),
+            (&[0x08, 0x00, 0xC0], "LD ($C000),SP"),
+            (&[0x18, 0xFE], "JR $0150"),
+            (&[0x20, 0x05], "JR NZ,$0157"),
+            (&[0x2A], "LD A,(HL+)"),
+            (&[0x36, 0x42], "LD (HL),$42"),
+            (&[0x76], "HALT"),
+            (&[0x9E], "SBC A,(HL)"),
+            (&[0xC3, 0x13, 0x02], "JP $0213"),
+            (&[0xE0, 0x44], "LDH ($FF44),A"),
+            (&[0xE8, 0xFD], "ADD SP,-$03"),
+            (&[0xF8, 0x05], "LD HL,SP+$05"),
+            (&[0xFE, 0x90], "CP $90"),
+            (&[0xCB, 0x7E], "BIT 7,(HL)"),
+            (&[0xD3], "DB $D3"),
+        ];
+        for (bytes, text) in cases.iter() {
+            let instruction = disassemble_bytes(bytes);
+            assert_eq!(instruction.text, *text);
+            assert_eq!(instruction.bytes(), *bytes);
+        }
+
+        let call = disassemble_bytes(&[0xDC, 0x00, 0x40]);
+        assert_eq!(call.to_string(), "0150: DC 00 40  CALL C,$4000");
+        assert_eq!((call.flow, call.target), (Flow::Call, Some(0x4000)));
+        assert_eq!((call.cycles, call.branch_cycles), (3, Some(6)));
+        let rst = disassemble_bytes(&[0xEF]);
+        assert_eq!((rst.flow, rst.target), (Flow::Call, Some(0x0028)));
+        let ret = disassemble_bytes(&[0xD9]);
+        assert_eq!((ret.flow, ret.target, ret.cycles), (Flow::Return, None, 4));
+        let swap = disassemble_bytes(&[0xCB, 0x36]);
+        assert_eq!((swap.text.as_str(), swap.cycles), ("SWAP (HL)", 4));
+    }
+
+    /// The cycles of every instruction must match the CPU emulation.
+    #[test]
+    fn test_disassemble_cycles() {
+        // the operands make the branches go elsewhere than the next instruction
+        let opcodes = (0x00..=0xFF).map(|opcode| [opcode, 0x10, 0x10]);
+        let cb_opcodes = (0x00..=0xFF).map(|opcode| [0xCB, opcode, 0x00]);
+        for bytes in opcodes.chain(cb_opcodes) {
+            let instruction = disassemble_bytes(&bytes);
+            if instruction.text.starts_with("DB") || instruction.text == "HALT" {
+                continue;
+            }
+            // the conditional branches are taken if the flags are set, for
+            // the Z and C conditions, and not taken otherwise
+            for flags in [0x00, 0xF0] {
+                let mut memory = TestMemory {
+                    memory: vec![0x00; 0x10000],
+                };
+                memory.memory[0x0150..0x0153].copy_from_slice(&bytes);
+                let mut cpu = Cpu::new(memory);
+                cpu.regs.pc = 0x0150;
+                cpu.regs.sp = 0xD000;
+                cpu.regs.set_hl(0xC000);
+                cpu.regs.f = flags;
+                let clock_cycles = cpu.step();
+                let taken =
+                    cpu.regs.pc != 0x0150 + instruction.length() && instruction.is_conditional();
+                let expected = match instruction.branch_cycles {
+                    Some(branch_cycles) if taken => branch_cycles,
+                    _ => instruction.cycles,
+                };
+                assert_eq!(
+                    clock_cycles,
+                    expected * 4,
+                    "{} (flags {:0>2X})",
+                    instruction.text,
+                    flags
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_disassemble_rom_bank() {
+        let mut rom = vec![0x00; 0x10000];
+        rom[0x0000] = 0xC3;
+        rom[0x8000..0x8003].copy_from_slice(&[0xCD, 0x00, 0x40]);
+        rom[0xBFFF] = 0x01;
+        let instructions = RomBank::new(&rom, 2).unwrap().disassemble();
+        assert_eq!(instructions[0].address, 0x4000);
+        assert_eq!(instructions[0].text, "CALL $4000");
+        assert_eq!(instructions[1].address, 0x4003);
+        // the operands past the bank are not part of it
+        assert_eq!(instructions.last().unwrap().text, "LD BC,$FFFF");
+        assert_eq!(
+            RomBank::new(&rom, 0).unwrap().disassemble()[0].text,
+            "JP $0000"
+        );
+        assert!(RomBank::new(&rom, 4).is_none());
+    }
+}
@@ -0,0 +1,89 @@
+//! The error type of the library.
+
+use std::path::PathBuf;
+use std::{error, fmt, io};
+
+/// A Result with the library 'Error' as an error type.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An error raised by the library.
+#[derive(Debug)]
+pub enum Error {
+    /// The given file could not be read or written.
+    Io { path: PathBuf, source: io::Error },
+    /// The cartridge hardware, given by the header type byte (0x0147), is not
+    /// supported.
+    UnsupportedMapper(u8),
+    /// The ROM is truncated, or its header is invalid or does not match its
+    /// content.
+    InvalidHeader(String),
+    /// The battery-buffered RAM save data does not match the cartridge.
+    BadSaveData(String),
+    /// The save state cannot be restored.
+    BadSaveState(String),
+    /// The configuration is invalid.
+    InvalidConfig(String),
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Error {
+        Error::Io {
+            path: path.into(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io {
+                ref path,
+                ref source,
+            } => write!(f, "I/O error on \"{}\" : {}", path.display(), source),
+            Error::UnsupportedMapper(mbc_type) => write!(
+                f,
+                "unsupported cartridge type {} ({:0>2X})",
+                crate::mbc::CartridgeType::from_byte(mbc_type),
+                mbc_type
+            ),
+            Error::InvalidHeader(ref why) => write!(f, "invalid cartridge : {}", why),
+            Error::BadSaveData(ref why) => write!(f, "invalid save data : {}", why),
+            Error::BadSaveState(ref why) => write!(f, "invalid save state : {}", why),
+            Error::InvalidConfig(ref why) => write!(f, "invalid configuration : {}", why),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::error::Error as _;
+    use std::io;
+
+    use super::Error;
+
+    #[test]
+    fn test_error_display_and_source() {
+        let error = Error::io(
+            "rom.gb",
+            io::Error::new(io::ErrorKind::NotFound, "not found"),
+        );
+        assert_eq!(error.to_string(), "I/O error on \"rom.gb\" : not found");
+        assert!(error.source().is_some());
+        let error = Error::UnsupportedMapper(0xFC);
+        assert_eq!(
+            error.to_string(),
+            "unsupported cartridge type POCKET CAMERA (FC)"
+        );
+        assert!(error.source().is_none());
+    }
+}
@@ -0,0 +1,474 @@
+//! GDB remote serial protocol stub : lets an external debugger control the
+//! emulation through a local TCP port.
+//!
+//! The stub handles the register ('g', 'G', 'p', 'P') and memory ('m', 'M')
+//! accesses, the software breakpoints ('Z0', 'z0'), single stepping ('s'),
+//! continuing ('c') and interrupting the execution. Other packets get the
+//! empty "unsupported" reply.
+//!
+//! Detaching ('D') resumes the execution, while killing ('k') leaves it
+//! stopped.
+//!
+//! The registers follow the layout of GDB's Z80 target : AF, BC, DE, HL, SP
+//! and PC, each one sent as a 16-bit little-endian value.
+
+use std::collections::BTreeMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use crate::cpu::Cpu;
+use crate::debugger::{Breakpoint, Debugger, Register};
+use crate::memory::Memory;
+use crate::mmu::MMU;
+
+/// The registers, in the order of their GDB numbers.
+const REGISTERS: [Register; 6] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+];
+
+/// The signal reported when the client interrupted the execution.
+const SIGINT: u8 = 2;
+/// The signal reported when the execution was suspended by the debugger.
+const SIGTRAP: u8 = 5;
+
+/// The byte sent by the client to interrupt the execution.
+const INTERRUPT: u8 = 0x03;
+
+/// The maximum size of a memory read or write, in bytes.
+const MAX_MEMORY_ACCESS: usize = 0x1000;
+
+/// The delay before writing again to a client not reading fast enough.
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The connection with a client.
+struct Connection {
+    stream: TcpStream,
+    /// The received bytes not handled yet.
+    input: Vec<u8>,
+    /// The last packet sent, sent again if the client did not get it right.
+    last_packet: Vec<u8>,
+    /// Are the acknowledgments disabled (see 'QStartNoAckMode') ?
+    no_ack: bool,
+    /// Is the client waiting for the execution to be suspended ?
+    waiting_stop: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> io::Result<Connection> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Connection {
+            stream,
+            input: Vec::new(),
+            last_packet: Vec::new(),
+            no_ack: false,
+            waiting_stop: false,
+        })
+    }
+
+    /// Read the bytes available without blocking. Fail if the client
+    /// disconnected.
+    fn receive(&mut self) -> io::Result<()> {
+        let mut buffer = [0x00; 1024];
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(size) => self.input.extend_from_slice(&buffer[..size]),
+                Err(ref why) if why.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(ref why) if why.kind() == ErrorKind::Interrupted => {}
+                Err(why) => return Err(why),
+            }
+        }
+    }
+
+    /// Write all the given bytes, waiting for the client if needed.
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut data = data;
+        while !data.is_empty() {
+            match self.stream.write(data) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(size) => data = &data[size..],
+                // the client is not reading fast enough
+                Err(ref why) if why.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(WRITE_RETRY_INTERVAL)
+                }
+                Err(ref why) if why.kind() == ErrorKind::Interrupted => {}
+                Err(why) => return Err(why),
+            }
+        }
+        Ok(())
+    }
+
+    /// Send the given packet data, framed as "$data#checksum".
+    fn send_packet(&mut self, data: &str) -> io::Result<()> {
+        self.last_packet = format!("${}#{:0>2x}", data, checksum(data.as_bytes())).into_bytes();
+        let packet = self.last_packet.clone();
+        self.write(&packet)
+    }
+
+    /// Remove the next complete packet from the input, and return its data
+    /// (or None if its checksum is wrong) along with the interruption
+    /// requests received before it. Return None if there is no complete
+    /// packet yet.
+    fn next_packet(&mut self) -> io::Result<Option<(Option<Vec<u8>>, bool)>> {
+        let mut interrupted = false;
+        let mut start = 0;
+        while start < self.input.len() && self.input[start] != b'$' {
+            match self.input[start] {
+                INTERRUPT => interrupted = true,
+                b'-' => {
+                    let packet = self.last_packet.clone();
+                    self.write(&packet)?;
+                }
+                _ => {}
+            }
+            start += 1;
+        }
+        let end = self.input[start..]
+            .iter()
+            .position(|&byte| byte == b'#')
+            .map(|index| start + index);
+        let result = match end {
+            Some(end) if end + 2 < self.input.len() => {
+                let data = self.input[start + 1..end].to_vec();
+                let expected = std::str::from_utf8(&self.input[end + 1..end + 3])
+                    .ok()
+                    .and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+                let valid = expected == Some(checksum(&data));
+                if !self.no_ack {
+                    self.write(if valid { b"+" } else { b"-" })?;
+                }
+                self.input.drain(..end + 3);
+                Some((valid.then_some(data), interrupted))
+            }
+            _ => {
+                // keep the incomplete packet for later
+                self.input.drain(..start);
+                interrupted.then_some((None, true))
+            }
+        };
+        Ok(result)
+    }
+}
+
+/// The GDB remote serial protocol server, handling one client at a time.
+pub struct GdbStub {
+    listener: TcpListener,
+    connection: Option<Connection>,
+    /// The IDs of the debugger breakpoints set by the client, by address.
+    breakpoints: BTreeMap<u16, usize>,
+}
+
+impl GdbStub {
+    /// Listen for a client on the given address.
+    pub fn bind<A: ToSocketAddrs>(address: A) -> io::Result<GdbStub> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbStub {
+            listener,
+            connection: None,
+            breakpoints: BTreeMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// Handle, without blocking, the client connection and the packets
+    /// received since the last call.
+    ///
+    /// Like the debugger console, the client controls the emulation through
+    /// the given debugger and 'running' flag : the caller must run the
+    /// machine while 'running' is true, through 'Debugger::step' when the
+    /// debugger is active, and clear it when the debugger suspends the
+    /// execution.
+    pub fn poll(&mut self, debugger: &mut Debugger, cpu: &mut Cpu<MMU>, running: &mut bool) {
+        if self.connection.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) => match Connection::new(stream) {
+                    Ok(connection) => {
+                        info!("GDB client connected from {}", address);
+                        self.connection = Some(connection);
+                        // the client expects the execution to be suspended
+                        *running = false;
+                    }
+                    Err(why) => error!("cannot set up the GDB connection : {}", why),
+                },
+                Err(ref why) if why.kind() == ErrorKind::WouldBlock => {}
+                Err(why) => error!("cannot accept a GDB client : {}", why),
+            }
+        }
+        if self.connection.is_none() {
+            return;
+        }
+        if let Err(why) = self.serve(debugger, cpu, running) {
+            info!("GDB client disconnected : {}", why);
+            self.detach(debugger, running);
+        }
+    }
+
+    fn serve(
+        &mut self,
+        debugger: &mut Debugger,
+        cpu: &mut Cpu<MMU>,
+        running: &mut bool,
+    ) -> io::Result<()> {
+        self.connection_mut().receive()?;
+        while let Some((data, interrupted)) = self.connection_mut().next_packet()? {
+            if interrupted && self.connection_mut().waiting_stop {
+                *running = false;
+                self.connection_mut().waiting_stop = false;
+                self.connection_mut()
+                    .send_packet(&format!("S{:0>2x}", SIGINT))?;
+            }
+            let Some(data) = data else {
+                continue;
+            };
+            let packet = String::from_utf8_lossy(&data);
+            match self.handle_packet(&packet, debugger, cpu, running) {
+                Some(reply) => self.connection_mut().send_packet(&reply)?,
+                None => {
+                    if self.connection.is_none() {
+                        return Ok(());
+                    }
+                }
+            }
+            if packet == "QStartNoAckMode" {
+                self.connection_mut().no_ack = true;
+            }
+            if packet == "D" {
+                self.detach(debugger, running);
+                return Ok(());
+            }
+        }
+        let connection = self.connection_mut();
+        if connection.waiting_stop && !*running {
+            connection.waiting_stop = false;
+            connection.send_packet(&format!("S{:0>2x}", SIGTRAP))?;
+        }
+        Ok(())
+    }
+
+    fn connection_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut().expect("no GDB client")
+    }
+
+    /// Close the connection, remove the breakpoints of the client and resume
+    /// the execution.
+    fn detach(&mut self, debugger: &mut Debugger, running: &mut bool) {
+        self.close(debugger);
+        *running = true;
+    }
+
+    /// Close the connection and remove the breakpoints of the client, leaving
+    /// the execution stopped.
+    fn kill(&mut self, debugger: &mut Debugger, running: &mut bool) {
+        self.close(debugger);
+        *running = false;
+    }
+
+    fn close(&mut self, debugger: &mut Debugger) {
+        self.connection = None;
+        for (_, id) in std::mem::take(&mut self.breakpoints) {
+            debugger.remove(id);
+        }
+        debugger.resume();
+    }
+
+    /// Handle the given packet and return the reply to send, if any.
+    fn handle_packet(
+        &mut self,
+        packet: &str,
+        debugger: &mut Debugger,
+        cpu: &mut Cpu<MMU>,
+        running: &mut bool,
+    ) -> Option<String> {
+        let Some(command) = packet.chars().next() else {
+            return Some(String::new());
+        };
+        let arguments = &packet[1..];
+        let reply = match command {
+            '?' => format!("S{:0>2x}", SIGTRAP),
+            'g' => REGISTERS
+                .iter()
+                .map(|register| encode_word(register.value(&cpu.regs)))
+                .collect(),
+            'G' => match decode_hex(arguments) {
+                Some(ref data) if data.len() == 2 * REGISTERS.len() => {
+                    for (register, value) in REGISTERS.iter().zip(data.chunks(2)) {
+                        register.set_value(&mut cpu.regs, u16::from_le_bytes([value[0], value[1]]));
+                    }
+                    "OK".into()
+                }
+                _ => error_reply(),
+            },
+            'p' => match parse_hex(arguments).and_then(|number| REGISTERS.get(number)) {
+                Some(register) => encode_word(register.value(&cpu.regs)),
+                None => error_reply(),
+            },
+            'P' => {
+                let register = arguments.split_once('=').and_then(|(number, value)| {
+                    let register = REGISTERS.get(parse_hex(number)?)?;
+                    Some((register, decode_hex(value)?))
+                });
+                match register {
+                    Some((register, value)) if value.len() == 2 => {
+                        register.set_value(&mut cpu.regs, u16::from_le_bytes([value[0], value[1]]));
+                        "OK".into()
+                    }
+                    _ => error_reply(),
+                }
+            }
+            'm' => match parse_memory_range(arguments) {
+                Some((address, length)) => (0..length)
+                    .map(|offset| {
+                        let byte = cpu.mem.read_byte(address.wrapping_add(offset as u16));
+                        format!("{:0>2x}", byte)
+                    })
+                    .collect(),
+                None => error_reply(),
+            },
+            'M' => {
+                let write = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = parse_memory_range(range)?;
+                    let data = decode_hex(data)?;
+                    (data.len() == length).then_some((address, data))
+                });
+                match write {
+                    Some((address, data)) => {
+                        for (offset, &byte) in data.iter().enumerate() {
+                            cpu.mem
+                                .write_byte(address.wrapping_add(offset as u16), byte);
+                        }
+                        "OK".into()
+                    }
+                    None => error_reply(),
+                }
+            }
+            'Z' | 'z' => match parse_breakpoint(arguments) {
+                Some(Some(address)) => {
+                    if command == 'Z' {
+                        self.breakpoints
+                            .entry(address)
+                            .or_insert_with(|| debugger.add_breakpoint(Breakpoint::new(address)));
+                    } else if let Some(id) = self.breakpoints.remove(&address) {
+                        debugger.remove(id);
+                    }
+                    "OK".into()
+                }
+                // only the software breakpoints are supported
+                Some(None) => String::new(),
+                None => error_reply(),
+            },
+            'c' | 's' => {
+                if !arguments.is_empty() {
+                    match parse_hex(arguments) {
+                        Some(address) => cpu.regs.pc = address as u16,
+                        None => return Some(error_reply()),
+                    }
+                }
+                if command == 'c' {
+                    debugger.resume();
+                } else {
+                    debugger.step_into();
+                }
+                *running = true;
+                self.connection_mut().waiting_stop = true;
+                return None;
+            }
+            'D' => "OK".into(),
+            'k' => {
+                self.kill(debugger, running);
+                return None;
+            }
+            'H' => "OK".into(),
+            _ if packet.starts_with("qSupported") => {
+                format!(
+                    "PacketSize={:x};QStartNoAckMode+",
+                    2 * MAX_MEMORY_ACCESS + 32
+                )
+            }
+            _ if packet == "QStartNoAckMode" => "OK".into(),
+            _ if packet == "qAttached" => "1".into(),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+}
+
+/// The checksum of a packet : the sum of its data bytes, modulo 256.
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn error_reply() -> String {
+    "E01".into()
+}
+
+fn encode_word(value: u16) -> String {
+    format!("{:0>2x}{:0>2x}", value & 0xFF, value >> 8)
+}
+
+fn decode_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) || !data.is_ascii() {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&data[index..index + 2], 16).ok())
+        .collect()
+}
+
+fn parse_hex(data: &str) -> Option<usize> {
+    usize::from_str_radix(data, 16).ok()
+}
+
+/// Parse an "address,length" memory range.
+fn parse_memory_range(data: &str) -> Option<(u16, usize)> {
+    let (address, length) = data.split_once(',')?;
+    let address = u16::try_from(parse_hex(address)?).ok()?;
+    let length = parse_hex(length)?;
+    (length <= MAX_MEMORY_ACCESS).then_some((address, length))
+}
+
+/// Parse a "type,address,kind" breakpoint, returning None as the address if
+/// it is not a software breakpoint.
+fn parse_breakpoint(data: &str) -> Option<Option<u16>> {
+    let mut fields = data.split(',');
+    let kind = fields.next()?;
+    let address = u16::try_from(parse_hex(fields.next()?)?).ok()?;
+    Some((kind == "0").then_some(address))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{checksum, decode_hex, encode_word, parse_breakpoint, parse_memory_range};
+
+    #[test]
+    fn test_gdb_packet_encoding() {
+        assert_eq!(checksum(b"OK"), 0x9A);
+        assert_eq!(checksum(b""), 0x00);
+        assert_eq!(encode_word(0x0150), "5001");
+        assert_eq!(decode_hex("00c3FF"), Some(vec![0x00, 0xC3, 0xFF]));
+        assert_eq!(decode_hex("0c3"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(parse_memory_range("ff40,2"), Some((0xFF40, 2)));
+        assert_eq!(parse_memory_range("10000,2"), None);
+        assert_eq!(parse_memory_range("0,ffff"), None);
+        assert_eq!(parse_breakpoint("0,213,1"), Some(Some(0x0213)));
+        assert_eq!(parse_breakpoint("2,c000,1"), Some(None));
+        assert_eq!(parse_breakpoint("0"), None);
+    }
+}
@@ -1,17 +1,21 @@
 mod cgb;
 mod palette;
 mod registers;
+mod sprite;
 mod tile;
 
 use std::cmp;
 
 use crate::cpu::CycleType;
+use crate::error::{Error, Result};
 use crate::irq::{Interrupt, IrqHandler};
 use crate::memory::Memory;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 use self::GpuMode::*;
 use self::palette::PaletteClassic;
 use self::registers::{LcdControl, LcdControllerInterruptStatus};
+use self::sprite::OAM_SIZE;
 use self::tile::Tile;
 
 /// The width of the Game Boy's screen, in pixels.
@@ -51,6 +55,19 @@ pub enum GpuMode {
     VRAM_Read = 3,
 }
 
+impl GpuMode {
+    /// Build a 'GpuMode' from its value in the STAT register.
+    fn from_u8(value: u8) -> Option<GpuMode> {
+        match value {
+            0 => Some(H_Blank),
+            1 => Some(V_Blank),
+            2 => Some(OAM_Read),
+            3 => Some(VRAM_Read),
+            _ => None,
+        }
+    }
+}
+
 pub const H_BLANK_CYCLES: CycleType = 204;
 pub const V_BLANK_CYCLES: CycleType = 456;
 pub const OAM_READ_CYCLES: CycleType = 80;
@@ -95,7 +112,7 @@ pub struct Gpu {
     // Vertical position of the top-left of the Window area.
     window_y: u8,
     /// The frame buffer containing the display's pixels.
-    frame_buffer: ScreenData,
+    frame_buffer: Box<ScreenData>,
     /// The background palette, assigning gray shades to the color numbers
     /// of the background and window tiles.
     ///
@@ -107,12 +124,22 @@ pub struct Gpu {
     /// Not used in CGB mode.
     ob_palettes: [PaletteClassic; 2],
     /// The tileset in VRAM.
-    tileset: [Tile; 384],
+    tileset: Box<[Tile; 384]>,
     /// The two tilemaps in VRAM.
     tilemaps: [[u8; TILEMAP_SIZE]; 2],
+    /// The Object Attribute Memory, defining the sprites.
+    oam: [u8; OAM_SIZE],
+    /// The background/window color numbers (0-3) of the current scanline,
+    /// needed to resolve the sprites priority.
+    line_color_indices: [u8; SCREEN_W],
+    /// The BG-to-OAM priority attributes of the current scanline (CGB mode only).
+    line_bg_priorities: [bool; SCREEN_W],
     /// Should the screen be redrawn by the frontend ?
     /// Must be externally set to false after that.
     pub dirty: bool,
+    /// The number of frames completed since the GPU was created. Not part of
+    /// the save states.
+    frame_count: u64,
 }
 
 impl Gpu {
@@ -135,27 +162,54 @@ impl Gpu {
             scroll_y: 0,
             window_x: 0,
             window_y: 0,
-            frame_buffer: [RGB::new(255, 255, 255); SCREEN_W * SCREEN_H],
+            frame_buffer: Box::new([RGB::new(255, 255, 255); SCREEN_W * SCREEN_H]),
             bg_palette: PaletteClassic::new(),
             ob_palettes: [PaletteClassic::new(); 2],
-            tileset: [Tile::new([0x00; 16]); 384],
+            tileset: Box::new([Tile::new([0x00; 16]); 384]),
             tilemaps: [[0x00; TILEMAP_SIZE]; 2],
+            oam: [0x00; OAM_SIZE],
+            line_color_indices: [0x00; SCREEN_W],
+            line_bg_priorities: [false; SCREEN_W],
             dirty: true,
+            frame_count: 0,
+        }
+    }
+
+    /// The number of frames completed (i.e. V-Blank periods entered) since
+    /// the GPU was created.
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Put the GPU in the state left by the boot ROM : the LCD is enabled
+    /// and the given line is being drawn since the given amount of clock
+    /// cycles.
+    pub fn post_bios(&mut self, ly: usize, line_clock: CycleType) {
+        self.ly = ly;
+        if ly < SCREEN_H {
+            self.switch_mode(OAM_Read);
+        } else {
+            self.switch_mode(V_Blank);
         }
+        self.mode_clock = line_clock;
     }
 
     /// Advance the GPU simulation forward by the given amount of clock ticks.
     ///
     /// Loops through OAM_Read, VRAM_Read and H_Blank modes to draw the 144 lines,
     /// then switches to V_Blank mode for 10 lines before starting over.
-    pub fn step(&mut self, ticks: CycleType, irq_handler: &mut dyn IrqHandler) {
+    ///
+    /// Return true if the H_Blank mode was entered, which drives the CGB
+    /// H-Blank DMA transfers.
+    pub fn step(&mut self, ticks: CycleType, irq_handler: &mut dyn IrqHandler) -> bool {
         use self::LcdControllerInterruptStatus::*;
 
         if !LcdControl::LcdDisplayEnable.is_set(self.lcd_control) {
-            return;
+            return false;
         }
 
         self.mode_clock += ticks;
+        let mut hblank_entered = false;
 
         match self.mode {
             // scanline, accessing OAM
@@ -169,6 +223,7 @@ impl Gpu {
                 // end of scanline
                 self.render_scanline();
                 self.switch_mode(H_Blank);
+                hblank_entered = true;
                 if HBlank.is_set(self.lcdc_status) {
                     irq_handler.request_interrupt(Interrupt::LCD_Stat);
                 }
@@ -181,6 +236,7 @@ impl Gpu {
                     // last H_BLANK: render framebuffer
                     self.switch_mode(V_Blank);
                     self.dirty = true;
+                    self.frame_count += 1;
                     irq_handler.request_interrupt(Interrupt::V_Blank);
                     if VBlank.is_set(self.lcdc_status) {
                         irq_handler.request_interrupt(Interrupt::LCD_Stat);
@@ -220,6 +276,8 @@ impl Gpu {
             self.lcdc_status =
                 LcdControllerInterruptStatus::with_coincidence_flag(self.lcdc_status, false);
         }
+
+        hblank_entered
     }
 
     /// Switch the current GPU mode.
@@ -237,72 +295,164 @@ impl Gpu {
     }
 
     fn render_line_tiles(&mut self, y: usize) {
-        let palette_data = self.bg_palette.data();
+        self.line_color_indices = [0x00; SCREEN_W];
+        self.line_bg_priorities = [false; SCREEN_W];
+        // in CGB mode, LCDC bit 0 is the master priority instead of blanking
+        // the background and window
+        let tiles_enabled = self.cgb_mode || LcdControl::BgDisplayEnable.is_set(self.lcd_control);
         // background line
-        if LcdControl::BgDisplayEnable.is_set(self.lcd_control) {
+        if !tiles_enabled {
+            let line = &mut self.frame_buffer[y * SCREEN_W..(y + 1) * SCREEN_W];
+            line.fill(palette::PaletteGrayShade::White.as_rgb());
+        } else {
+            let tilemap_2 = LcdControl::BgTileMapDisplaySelect.is_set(self.lcd_control);
+            let background_y = (self.scroll_y as usize + y) % BACKGROUND_HEIGHT;
             for x in 0..SCREEN_W {
                 let background_x = (self.scroll_x as usize + x) % BACKGROUND_WIDTH;
-                let background_y = (self.scroll_y as usize + y) % BACKGROUND_HEIGHT;
-
-                let tile = self.get_tile(
-                    background_x,
-                    background_y,
-                    LcdControl::BgTileMapDisplaySelect.is_set(self.lcd_control),
-                );
-                let (x_offset, y_offset) = (background_x % 8, background_y % 8);
-                let color_index = tile.data()[y_offset][x_offset] as usize;
-                self.frame_buffer[y * SCREEN_W + x] = palette_data[color_index].as_rgb();
+                self.render_tile_pixel(x, y, background_x, background_y, tilemap_2);
             }
         }
         // window line
-        if LcdControl::WindowDisplayEnable.is_set(self.lcd_control) {
+        if tiles_enabled
+            && LcdControl::WindowDisplayEnable.is_set(self.lcd_control)
+            && y >= self.window_y as usize
+        {
+            let tilemap_2 = LcdControl::WindowTileMapDisplaySelect.is_set(self.lcd_control);
+            let window_y = y - self.window_y as usize;
             let x_start = cmp::max(self.window_x as i32 - 7, 0) as usize;
             for x in x_start..SCREEN_W {
-                let window_x = (self.scroll_x as usize + x) % BACKGROUND_WIDTH;
-                let window_y = (self.scroll_y as usize + y) % BACKGROUND_HEIGHT;
-
-                let tile = self.get_tile(
-                    window_x,
-                    window_y,
-                    LcdControl::WindowTileMapDisplaySelect.is_set(self.lcd_control),
-                );
-                let (x_offset, y_offset) = (window_x % 8, window_y % 8);
-                let color_index = tile.data()[y_offset][x_offset] as usize;
-                self.frame_buffer[y * SCREEN_W + x] = palette_data[color_index].as_rgb();
+                let window_x = x + 7 - self.window_x as usize;
+                self.render_tile_pixel(x, y, window_x, window_y, tilemap_2);
             }
         }
     }
 
-    fn get_tile(&self, x: usize, y: usize, tilemap_2: bool) -> Tile {
-        let index = (y / 8) * 32 + x / 8;
-        let tile_index = if tilemap_2 {
-            self.tilemaps[1][index]
-        } else {
-            self.tilemaps[0][index]
+    /// Draw the screen pixel at (x, y) from the pixel at (map_x, map_y) in
+    /// the given tilemap.
+    fn render_tile_pixel(
+        &mut self,
+        x: usize,
+        y: usize,
+        map_x: usize,
+        map_y: usize,
+        tilemap_2: bool,
+    ) {
+        let tilemap = tilemap_2 as usize;
+        let map_index = (map_y / 8) * 32 + map_x / 8;
+        let tileset_index = self.tileset_index(self.tilemaps[tilemap][map_index]);
+        let (x_offset, y_offset) = (map_x % 8, map_y % 8);
+
+        let (color_index, color) = match self.cgb_data {
+            Some(ref data) => {
+                let attributes =
+                    cgb::TileAttributes::new(data.tilemap_attributes[tilemap][map_index]);
+                let tile = if attributes.vram_bank == 1 {
+                    &data.tileset[tileset_index]
+                } else {
+                    &self.tileset[tileset_index]
+                };
+                let x_offset = if attributes.x_flip {
+                    7 - x_offset
+                } else {
+                    x_offset
+                };
+                let y_offset = if attributes.y_flip {
+                    7 - y_offset
+                } else {
+                    y_offset
+                };
+                let color_index = tile.data()[y_offset][x_offset] as usize;
+                self.line_bg_priorities[x] = attributes.priority;
+                let palette_data = data.bg_palettes[attributes.palette].data();
+                (color_index, palette_data[color_index].rgb())
+            }
+            None => {
+                let tile = &self.tileset[tileset_index];
+                let color_index = tile.data()[y_offset][x_offset] as usize;
+                (color_index, self.bg_palette.data()[color_index].as_rgb())
+            }
         };
+        self.line_color_indices[x] = color_index as u8;
+        self.frame_buffer[y * SCREEN_W + x] = color;
+    }
 
-        let tileset_index = if LcdControl::BgWindowTileDataSelect.is_set(self.lcd_control) {
+    /// Get the index in the tileset of the given background or window tile
+    /// number, according to the current tile data addressing mode.
+    fn tileset_index(&self, tile_index: u8) -> usize {
+        if LcdControl::BgWindowTileDataSelect.is_set(self.lcd_control) {
             tile_index as usize
         } else {
             (256i32 + (tile_index as i8) as i32) as usize
-        };
-        debug_assert!(
-            tileset_index < self.tileset.len(),
-            "Gpu.get_tile(x={}, y={}, tilemap_2={}): tileset_index overflow (value={}, max={})",
-            x,
-            y,
-            tilemap_2,
-            tileset_index,
-            self.tileset.len()
-        );
-        self.tileset[tileset_index]
+        }
     }
 
-    fn render_line_sprites(&mut self, _y: usize) {
+    fn render_line_sprites(&mut self, y: usize) {
         if !LcdControl::ObjDisplayEnable.is_set(self.lcd_control) {
             return;
         }
-        // TODO:
+        let height = if LcdControl::ObjSize.is_set(self.lcd_control) {
+            16
+        } else {
+            8
+        };
+        let sprites = sprite::line_sprites(&self.oam, y, height, self.cgb_mode);
+        // in CGB mode, clearing LCDC bit 0 puts the sprites above the background and window
+        let master_priority =
+            !self.cgb_mode || LcdControl::BgDisplayEnable.is_set(self.lcd_control);
+        for x in 0..SCREEN_W {
+            // the first sprite with an opaque pixel wins, even if hidden by the background
+            for sprite in &sprites {
+                let column = x + 8;
+                if column < sprite.x as usize || column >= sprite.x as usize + 8 {
+                    continue;
+                }
+                let color_index =
+                    self.sprite_color_index(sprite, column - sprite.x as usize, y, height);
+                if color_index == 0 {
+                    continue; // transparent
+                }
+                let bg_priority = sprite.behind_background() || self.line_bg_priorities[x];
+                if !(master_priority && bg_priority) || self.line_color_indices[x] == 0 {
+                    self.frame_buffer[y * SCREEN_W + x] = self.sprite_color(sprite, color_index);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Get the color number (0-3) of the given sprite at the given column
+    /// (0-7) on the given scanline.
+    fn sprite_color_index(
+        &self,
+        sprite: &sprite::Sprite,
+        column: usize,
+        y: usize,
+        height: usize,
+    ) -> usize {
+        let mut row = y + 16 - sprite.y as usize;
+        if sprite.y_flip() {
+            row = height - 1 - row;
+        }
+        let column = if sprite.x_flip() { 7 - column } else { column };
+        // in 8x16 mode, bit 0 of the tile index is ignored
+        let tile_index = if height == 16 {
+            (sprite.tile_index & 0xFE) as usize + row / 8
+        } else {
+            sprite.tile_index as usize
+        };
+        let tile = match self.cgb_data {
+            Some(ref data) if sprite.vram_bank() == 1 => &data.tileset[tile_index],
+            _ => &self.tileset[tile_index],
+        };
+        tile.data()[row % 8][column] as usize
+    }
+
+    /// Get the color of the given sprite color number, according to its palette.
+    fn sprite_color(&self, sprite: &sprite::Sprite, color_index: usize) -> RGB {
+        match self.cgb_data {
+            Some(ref data) => data.ob_palettes[sprite.cgb_palette()].data()[color_index].rgb(),
+            None => self.ob_palettes[sprite.classic_palette()].data()[color_index].as_rgb(),
+        }
     }
 
     pub fn screen_data(&self) -> Vec<RGB> {
@@ -310,6 +460,76 @@ impl Gpu {
     }
 }
 
+/// The scanline buffers are not part of the state, being filled before use
+/// at each scanline.
+impl SaveState for Gpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.mode.clone() as u8);
+        writer.write_u64(self.mode_clock);
+        writer.write_u8(self.lcd_control);
+        writer.write_u8(self.lcdc_status);
+        writer.write_usize(self.ly);
+        writer.write_usize(self.lyc);
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.window_x);
+        writer.write_u8(self.window_y);
+        for pixel in self.frame_buffer.iter() {
+            writer.write_bytes(&[pixel.r, pixel.g, pixel.b]);
+        }
+        writer.write_u8(self.bg_palette.raw());
+        writer.write_u8(self.ob_palettes[0].raw());
+        writer.write_u8(self.ob_palettes[1].raw());
+        for tile in self.tileset.iter() {
+            writer.write_bytes(tile.raw_data());
+        }
+        for tilemap in self.tilemaps.iter() {
+            writer.write_bytes(tilemap);
+        }
+        writer.write_bytes(&self.oam);
+        if let Some(ref cgb_data) = self.cgb_data {
+            cgb_data.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let mode = reader.read_u8()?;
+        self.mode = GpuMode::from_u8(mode)
+            .ok_or_else(|| Error::BadSaveState(format!("invalid GPU mode {}", mode)))?;
+        self.mode_clock = reader.read_u64()?;
+        self.lcd_control = reader.read_u8()?;
+        self.lcdc_status = reader.read_u8()?;
+        self.ly = reader.read_usize(SCREEN_H + 10)?;
+        self.lyc = reader.read_usize(0x100)?;
+        self.scroll_x = reader.read_u8()?;
+        self.scroll_y = reader.read_u8()?;
+        self.window_x = reader.read_u8()?;
+        self.window_y = reader.read_u8()?;
+        for pixel in self.frame_buffer.iter_mut() {
+            let mut rgb = [0x00; 3];
+            reader.read_bytes(&mut rgb)?;
+            *pixel = RGB::new(rgb[0], rgb[1], rgb[2]);
+        }
+        self.bg_palette.set(reader.read_u8()?);
+        self.ob_palettes[0].set(reader.read_u8()?);
+        self.ob_palettes[1].set(reader.read_u8()?);
+        for tile in self.tileset.iter_mut() {
+            let mut raw_data = [0x00; 16];
+            reader.read_bytes(&mut raw_data)?;
+            *tile = Tile::new(raw_data);
+        }
+        for tilemap in self.tilemaps.iter_mut() {
+            reader.read_bytes(tilemap)?;
+        }
+        reader.read_bytes(&mut self.oam)?;
+        if let Some(ref mut cgb_data) = self.cgb_data {
+            cgb_data.load_state(reader)?;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+}
+
 impl Memory for Gpu {
     fn read_byte(&mut self, address: u16) -> u8 {
         use self::cgb::regs as r;
@@ -319,8 +539,15 @@ impl Memory for Gpu {
 
         if self.cgb_mode {
             let data = self.cgb_data.as_ref().unwrap();
+            let bank_1 = data.vram_bank_selector & 0x01 == 0x01;
             match a {
-                r::VRAM_BANK => return data.vram_bank_selector,
+                r::VRAM_BANK => return 0xFE | data.vram_bank_selector,
+                0x8000..=0x97FF if bank_1 => {
+                    let addr = a - 0x8000;
+                    return data.tileset[addr / 16].raw_byte(addr % 16);
+                }
+                0x9800..=0x9BFF if bank_1 => return data.tilemap_attributes[0][a - 0x9800],
+                0x9C00..=0x9FFF if bank_1 => return data.tilemap_attributes[1][a - 0x9C00],
                 r::BGP_INDEX => return data.bg_palette_index.raw_value(),
                 r::BGP_DATA => return data.get_bg_color(),
                 r::OBP_INDEX => return data.ob_palette_index.raw_value(),
@@ -347,6 +574,7 @@ impl Memory for Gpu {
                 let addr = a - 0x9C00;
                 self.tilemaps[1][addr]
             }
+            0xFE00..=0xFE9F => self.oam[a - 0xFE00],
             CONTROL => self.lcd_control,
             STAT => self.lcdc_status,
             SCY => self.scroll_y,
@@ -370,9 +598,16 @@ impl Memory for Gpu {
 
         if self.cgb_mode {
             let data = self.cgb_data.as_mut().unwrap();
+            let bank_1 = data.vram_bank_selector & 0x01 == 0x01;
             let mut done = true;
             match a {
-                r::VRAM_BANK => data.vram_bank_selector = byte,
+                r::VRAM_BANK => data.vram_bank_selector = byte & 0x01,
+                0x8000..=0x97FF if bank_1 => {
+                    let addr = a - 0x8000;
+                    data.tileset[addr / 16].update_raw_byte(addr % 16, byte);
+                }
+                0x9800..=0x9BFF if bank_1 => data.tilemap_attributes[0][a - 0x9800] = byte,
+                0x9C00..=0x9FFF if bank_1 => data.tilemap_attributes[1][a - 0x9C00] = byte,
                 r::BGP_INDEX => data.bg_palette_index.update_with(byte),
                 r::BGP_DATA => data.set_bg_color(byte),
                 r::OBP_INDEX => data.ob_palette_index.update_with(byte),
@@ -401,6 +636,7 @@ impl Memory for Gpu {
                 let addr = a - 0x9C00;
                 self.tilemaps[1][addr] = byte;
             }
+            0xFE00..=0xFE9F => self.oam[a - 0xFE00] = byte,
             CONTROL => self.lcd_control = byte,
             // LCDC Status: bits 2 to 0 are read-only
             STAT => self.lcdc_status = (byte & 0xF8) | (self.lcdc_status & 0x07),
@@ -417,3 +653,265 @@ impl Memory for Gpu {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::memory::Memory;
+
+    use super::palette::PaletteGrayShade::{self, *};
+    use super::{Gpu, RGB, SCREEN_W};
+
+    /// LCD, sprites and background on, tile data at 0x8000, 8x8 sprites.
+    const LCDC: u8 = 0x93;
+
+    /// Write the given tile (in the current VRAM bank), made of the given
+    /// color numbers.
+    fn write_tile(gpu: &mut Gpu, index: u16, rows: [[u8; 8]; 8]) {
+        for (y, row) in rows.iter().enumerate() {
+            let (mut low, mut high) = (0x00, 0x00);
+            for (x, &color) in row.iter().enumerate() {
+                low |= (color & 0x01) << (7 - x);
+                high |= ((color >> 1) & 0x01) << (7 - x);
+            }
+            let address = 0x8000 + index * 16 + y as u16 * 2;
+            gpu.write_byte(address, low);
+            gpu.write_byte(address + 1, high);
+        }
+    }
+
+    /// Write the given tile filled with a single color number.
+    fn fill_tile(gpu: &mut Gpu, index: u16, color: u8) {
+        write_tile(gpu, index, [[color; 8]; 8]);
+    }
+
+    /// Write the given OAM entry, for a sprite whose top-left corner is at
+    /// the given screen position.
+    fn write_sprite(gpu: &mut Gpu, number: u16, x: u8, y: u8, tile: u8, attributes: u8) {
+        let address = 0xFE00 + number * 4;
+        for (offset, byte) in [y + 16, x + 8, tile, attributes].into_iter().enumerate() {
+            gpu.write_byte(address + offset as u16, byte);
+        }
+    }
+
+    /// Render the given scanline and return its pixels.
+    fn render_line(gpu: &mut Gpu, y: usize) -> Vec<RGB> {
+        gpu.render_line_tiles(y);
+        gpu.render_line_sprites(y);
+        gpu.frame_buffer[y * SCREEN_W..(y + 1) * SCREEN_W].to_vec()
+    }
+
+    /// A classic mode GPU with the background made of tile 0 and the usual
+    /// palettes : identity for BGP and OBP0, and OBP1 mapping the colors 2
+    /// and 3 to light and dark gray.
+    fn make_classic_gpu() -> Gpu {
+        let mut gpu = Gpu::new(false);
+        gpu.write_byte(0xFF40, LCDC);
+        gpu.write_byte(0xFF47, 0xE4);
+        gpu.write_byte(0xFF48, 0xE4);
+        gpu.write_byte(0xFF49, 0x90);
+        gpu
+    }
+
+    #[test]
+    fn test_cgb_vram_bank_switching() {
+        let mut gpu = Gpu::new(true);
+        gpu.write_byte(0x8000, 0x11);
+        gpu.write_byte(0x9800, 0x33);
+        gpu.write_byte(0xFF4F, 0x01);
+        assert_eq!(gpu.read_byte(0xFF4F), 0xFF);
+        assert_eq!(gpu.read_byte(0x8000), 0x00);
+        gpu.write_byte(0x8000, 0x22);
+        gpu.write_byte(0x9800, 0x44);
+        gpu.write_byte(0xFF4F, 0x00);
+        assert_eq!(gpu.read_byte(0xFF4F), 0xFE);
+        assert_eq!(gpu.read_byte(0x8000), 0x11);
+        assert_eq!(gpu.read_byte(0x9800), 0x33);
+        gpu.write_byte(0xFF4F, 0x01);
+        assert_eq!(gpu.read_byte(0x8000), 0x22);
+        assert_eq!(gpu.read_byte(0x9800), 0x44);
+    }
+
+    #[test]
+    fn test_classic_mode_has_no_vram_bank_1() {
+        let mut gpu = Gpu::new(false);
+        gpu.write_byte(0xFF4F, 0x01);
+        gpu.write_byte(0x8000, 0x22);
+        gpu.write_byte(0xFF4F, 0x00);
+        assert_eq!(gpu.read_byte(0x8000), 0x22);
+    }
+
+    #[test]
+    fn test_classic_mode_bg_disable_blanks_window() {
+        let mut gpu = Gpu::new(false);
+        for address in 0x8000..0x8010 {
+            gpu.write_byte(address, 0xFF);
+        }
+        gpu.write_byte(0xFF47, 0xE4);
+        gpu.write_byte(0xFF4A, 0x00);
+        gpu.write_byte(0xFF4B, 0x07);
+        // window enabled, background disabled
+        gpu.write_byte(0xFF40, 0xB0);
+        gpu.render_line_tiles(0);
+        let white = PaletteGrayShade::White.as_rgb();
+        assert!(
+            gpu.frame_buffer[..SCREEN_W]
+                .iter()
+                .all(|&pixel| pixel == white)
+        );
+        gpu.write_byte(0xFF40, 0xB1);
+        gpu.render_line_tiles(0);
+        assert!(
+            gpu.frame_buffer[..SCREEN_W]
+                .iter()
+                .all(|&pixel| pixel != white)
+        );
+    }
+
+    #[test]
+    fn test_classic_sprite_flips() {
+        let mut gpu = make_classic_gpu();
+        // a single opaque pixel in the top-left corner
+        let mut rows = [[0; 8]; 8];
+        rows[0][0] = 3;
+        write_tile(&mut gpu, 1, rows);
+        for (attributes, x, y) in [(0x00, 0, 0), (0x20, 7, 0), (0x40, 0, 7), (0x60, 7, 7)] {
+            write_sprite(&mut gpu, 0, 0, 0, 1, attributes);
+            for line in 0..8 {
+                let pixels = render_line(&mut gpu, line);
+                for (column, &pixel) in pixels[..8].iter().enumerate() {
+                    let expected = if (column, line) == (x, y) {
+                        Dark
+                    } else {
+                        White
+                    };
+                    assert_eq!(pixel, expected.as_rgb(), "attributes {:0>2X}", attributes);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_classic_sprite_palettes_and_transparency() {
+        let mut gpu = make_classic_gpu();
+        let mut rows = [[3; 8]; 8];
+        rows[0][1] = 0;
+        rows[0][2] = 2;
+        write_tile(&mut gpu, 1, rows);
+        // background color 1 behind the sprites
+        fill_tile(&mut gpu, 0, 1);
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        write_sprite(&mut gpu, 1, 8, 0, 1, 0x10);
+        let pixels = render_line(&mut gpu, 0);
+        // OBP0, then OBP1
+        let expected = [Dark, LightGray, DarkGray, Dark].map(|shade| shade.as_rgb());
+        assert_eq!(pixels[..4], expected);
+        let expected = [DarkGray, LightGray, LightGray, DarkGray].map(|shade| shade.as_rgb());
+        assert_eq!(pixels[8..12], expected);
+    }
+
+    #[test]
+    fn test_classic_sprite_behind_background() {
+        let mut gpu = make_classic_gpu();
+        // background colors 0 then 2
+        write_tile(&mut gpu, 0, [[0, 0, 0, 0, 2, 2, 2, 2]; 8]);
+        fill_tile(&mut gpu, 1, 3);
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x80);
+        let pixels = render_line(&mut gpu, 0);
+        assert_eq!(pixels[..4], [Dark.as_rgb(); 4]);
+        assert_eq!(pixels[4..8], [DarkGray.as_rgb(); 4]);
+        // above the background otherwise
+        write_sprite(&mut gpu, 0, 0, 0, 1, 0x00);
+        assert_eq!(render_line(&mut gpu, 0)[..8], [Dark.as_rgb(); 8]);
+    }
+
+    #[test]
+    fn test_classic_tall_sprites() {
+        let mut gpu = make_classic_gpu();
+        gpu.write_byte(0xFF40, LCDC | 0x04);
+        fill_tile(&mut gpu, 2, 1);
+        fill_tile(&mut gpu, 3, 2);
+        // bit 0 of the tile index is ignored
+        write_sprite(&mut gpu, 0, 0, 0, 3, 0x00);
+        assert_eq!(render_line(&mut gpu, 0)[0], LightGray.as_rgb());
+        assert_eq!(render_line(&mut gpu, 15)[0], DarkGray.as_rgb());
+        assert_eq!(render_line(&mut gpu, 16)[0], White.as_rgb());
+        // flipped over the whole height
+        write_sprite(&mut gpu, 0, 0, 0, 2, 0x40);
+        assert_eq!(render_line(&mut gpu, 0)[0], DarkGray.as_rgb());
+        assert_eq!(render_line(&mut gpu, 15)[0], LightGray.as_rgb());
+    }
+
+    #[test]
+    fn test_classic_sprites_x_priority() {
+        let mut gpu = make_classic_gpu();
+        fill_tile(&mut gpu, 1, 1);
+        fill_tile(&mut gpu, 2, 2);
+        // the sprite with the smallest X is drawn above, whatever its OAM index
+        write_sprite(&mut gpu, 0, 4, 0, 1, 0x00);
+        write_sprite(&mut gpu, 1, 2, 0, 2, 0x00);
+        let pixels = render_line(&mut gpu, 0);
+        assert_eq!(pixels[..2], [White.as_rgb(); 2]);
+        assert_eq!(pixels[2..10], [DarkGray.as_rgb(); 8]);
+        assert_eq!(pixels[10..12], [LightGray.as_rgb(); 2]);
+        assert_eq!(pixels[12], White.as_rgb());
+    }
+
+    /// A CGB mode GPU with the background made of tile 0 (color 2) using
+    /// background palette 1, whose color 2 is red, and tile 1 (color 3) for
+    /// the sprites using object palette 2, whose color 3 is green.
+    fn make_cgb_gpu() -> Gpu {
+        let mut gpu = Gpu::new(true);
+        gpu.write_byte(0xFF40, LCDC);
+        // palette 1, color 2 : 0x001F
+        gpu.write_byte(0xFF68, 0x80 | (1 << 3) | (2 << 1));
+        gpu.write_byte(0xFF69, 0x1F);
+        gpu.write_byte(0xFF69, 0x00);
+        // palette 2, color 3 : 0x03E0
+        gpu.write_byte(0xFF6A, 0x80 | (2 << 3) | (3 << 1));
+        gpu.write_byte(0xFF6B, 0xE0);
+        gpu.write_byte(0xFF6B, 0x03);
+        fill_tile(&mut gpu, 0, 2);
+        fill_tile(&mut gpu, 1, 3);
+        // background tile attributes, in VRAM bank 1
+        gpu.write_byte(0xFF4F, 0x01);
+        for address in 0x9800..0x9C00 {
+            gpu.write_byte(address, 0x01);
+        }
+        gpu.write_byte(0xFF4F, 0x00);
+        gpu
+    }
+
+    const RED: RGB = RGB { r: 248, g: 0, b: 0 };
+    const GREEN: RGB = RGB { r: 0, g: 248, b: 0 };
+
+    #[test]
+    fn test_cgb_palettes_rendering() {
+        let mut gpu = make_cgb_gpu();
+        write_sprite(&mut gpu, 0, 16, 0, 1, 0x02);
+        let pixels = render_line(&mut gpu, 0);
+        assert_eq!(pixels[..16], [RED; 16]);
+        assert_eq!(pixels[16..24], [GREEN; 8]);
+        assert_eq!(pixels[24..], [RED; SCREEN_W - 24]);
+    }
+
+    #[test]
+    fn test_cgb_background_priority() {
+        let mut gpu = make_cgb_gpu();
+        write_sprite(&mut gpu, 0, 16, 0, 1, 0x02);
+        // the third background tile of the first row is drawn above the sprites
+        gpu.write_byte(0xFF4F, 0x01);
+        gpu.write_byte(0x9802, 0x81);
+        gpu.write_byte(0xFF4F, 0x00);
+        assert_eq!(render_line(&mut gpu, 0)[16..24], [RED; 8]);
+        // as is the whole background for a sprite behind it
+        write_sprite(&mut gpu, 0, 24, 0, 1, 0x82);
+        assert_eq!(render_line(&mut gpu, 0)[24..32], [RED; 8]);
+
+        // LCDC bit 0 cleared : the sprites are drawn above the background
+        gpu.write_byte(0xFF40, LCDC & !0x01);
+        write_sprite(&mut gpu, 1, 16, 0, 1, 0x02);
+        let pixels = render_line(&mut gpu, 0);
+        assert_eq!(pixels[16..32], [GREEN; 16]);
+        assert_eq!(pixels[..16], [RED; 16]);
+    }
+}
@@ -1,4 +1,9 @@
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::TILEMAP_SIZE;
 use super::palette::PaletteColor;
+use super::tile::Tile;
 
 /// The GameBoyColor-specific GPU register addresses.
 pub mod regs {
@@ -69,14 +74,43 @@ impl PaletteIndexRegister {
 
     /// Must be called every time the associated palette data register is written
     /// to. If auto-increment is set to true (bit 7 = 1), increment the index.
+    /// Only the index (bit 0-5) wraps around, bit 7 is left untouched.
     pub fn auto_increment(&mut self) {
         if self.auto_increment {
-            let new_value = self.raw_value + 1;
+            let new_value = (self.raw_value & 0x80) | (self.raw_value.wrapping_add(1) & 0x3F);
             self.update_with(new_value);
         }
     }
 }
 
+/// The attributes of a background map tile, stored in the second VRAM bank at
+/// the same address as its tile index:
+/// bit 0-2 : background palette number (0-7)
+/// bit 3   : tile VRAM bank number
+/// bit 5   : horizontal flip
+/// bit 6   : vertical flip
+/// bit 7   : BG-to-OAM priority (if 1, the tile is drawn above the sprites)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TileAttributes {
+    pub palette: usize,
+    pub vram_bank: usize,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    pub priority: bool,
+}
+
+impl TileAttributes {
+    pub fn new(raw_value: u8) -> TileAttributes {
+        TileAttributes {
+            palette: (raw_value & 0x07) as usize,
+            vram_bank: ((raw_value >> 3) & 0x01) as usize,
+            x_flip: (raw_value & 0x20) == 0x20,
+            y_flip: (raw_value & 0x40) == 0x40,
+            priority: (raw_value & 0x80) == 0x80,
+        }
+    }
+}
+
 /// GameBoyColor-specific GPU data. This allows to eventually save on memory
 /// when in classic mode (using an 'Option' typically).
 pub struct GpuData {
@@ -91,8 +125,10 @@ pub struct GpuData {
     /// Accessed at the 'VRAM_BANK' address, when 0x01 use the second VRAM bank,
     /// otherwise use the first one (common with the Classic mode).
     pub vram_bank_selector: u8,
-    /// The secondary VRAM bank.
-    pub vram_bank: [u8; 0x2000],
+    /// The tileset in the second VRAM bank.
+    pub tileset: Box<[Tile; 384]>,
+    /// The attributes of the two tilemaps, in the second VRAM bank.
+    pub tilemap_attributes: [[u8; TILEMAP_SIZE]; 2],
 }
 
 impl GpuData {
@@ -103,7 +139,8 @@ impl GpuData {
             ob_palette_index: PaletteIndexRegister::new(0x00),
             ob_palettes: [PaletteColor::new(); 8],
             vram_bank_selector: 0x00,
-            vram_bank: [0x00; 0x2000],
+            tileset: Box::new([Tile::new([0x00; 16]); 384]),
+            tilemap_attributes: [[0x00; TILEMAP_SIZE]; 2],
         }
     }
 
@@ -132,11 +169,11 @@ impl GpuData {
     /// Get the byte value in the object palette according to the
     /// specifications of the object palette index.
     pub fn get_ob_color(&self) -> u8 {
-        let palette = self.bg_palettes[self.bg_palette_index.index()].data();
-        if self.bg_palette_index.high_byte() {
-            palette[self.bg_palette_index.color_index()].raw_high()
+        let palette = self.ob_palettes[self.ob_palette_index.index()].data();
+        if self.ob_palette_index.high_byte() {
+            palette[self.ob_palette_index.color_index()].raw_high()
         } else {
-            palette[self.bg_palette_index.color_index()].raw_low()
+            palette[self.ob_palette_index.color_index()].raw_low()
         }
     }
     /// Set the byte value in the object palette according to the
@@ -152,9 +189,52 @@ impl GpuData {
     }
 }
 
+impl SaveState for GpuData {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bg_palette_index.raw_value());
+        writer.write_u8(self.ob_palette_index.raw_value());
+        for palette in self.bg_palettes.iter().chain(self.ob_palettes.iter()) {
+            for color in palette.data() {
+                writer.write_u16(color.raw());
+            }
+        }
+        writer.write_u8(self.vram_bank_selector);
+        for tile in self.tileset.iter() {
+            writer.write_bytes(tile.raw_data());
+        }
+        for attributes in self.tilemap_attributes.iter() {
+            writer.write_bytes(attributes);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.bg_palette_index.update_with(reader.read_u8()?);
+        self.ob_palette_index.update_with(reader.read_u8()?);
+        for palette in self
+            .bg_palettes
+            .iter_mut()
+            .chain(self.ob_palettes.iter_mut())
+        {
+            for color in palette.data_mut() {
+                color.set(reader.read_u16()?);
+            }
+        }
+        self.vram_bank_selector = reader.read_u8()? & 0x01;
+        for tile in self.tileset.iter_mut() {
+            let mut raw_data = [0x00; 16];
+            reader.read_bytes(&mut raw_data)?;
+            *tile = Tile::new(raw_data);
+        }
+        for attributes in self.tilemap_attributes.iter_mut() {
+            reader.read_bytes(attributes)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::PaletteIndexRegister;
+    use super::{GpuData, PaletteIndexRegister, TileAttributes};
 
     #[test]
     fn test_palette_index_register_decoding() {
@@ -164,4 +244,51 @@ mod test {
         assert_eq!(index.color_index(), 2);
         assert_eq!(index.index(), 5);
     }
+
+    #[test]
+    fn test_palette_data_auto_increment() {
+        let mut data = GpuData::new();
+        data.ob_palette_index.update_with(0x80 | 0x0A); // palette 1, color 1
+        data.set_ob_color(0x1F);
+        data.set_ob_color(0x7C);
+        data.ob_palette_index.update_with(0x0A);
+        assert_eq!(data.get_ob_color(), 0x1F);
+        data.ob_palette_index.update_with(0x0B);
+        assert_eq!(data.get_ob_color(), 0x7C);
+        // the background palettes are left untouched
+        data.bg_palette_index.update_with(0x0A);
+        assert_eq!(data.get_bg_color(), 0x00);
+    }
+
+    #[test]
+    fn test_palette_index_auto_increment_wrapping() {
+        for start in [0xBF, 0xFF] {
+            let mut data = GpuData::new();
+            data.bg_palette_index.update_with(start);
+            data.ob_palette_index.update_with(start);
+            for i in 1..=130 {
+                data.set_bg_color(i as u8);
+                data.set_ob_color(i as u8);
+                let expected = 0x80 | ((0x3F + i) & 0x3F) as u8;
+                assert_eq!(data.bg_palette_index.raw_value(), expected);
+                assert_eq!(data.ob_palette_index.raw_value(), expected);
+            }
+            // the last byte written to each index is the one left in the palettes
+            data.bg_palette_index.update_with(0x3F);
+            assert_eq!(data.get_bg_color(), 129);
+            data.ob_palette_index.update_with(0x00);
+            assert_eq!(data.get_ob_color(), 130);
+        }
+    }
+
+    #[test]
+    fn test_tile_attributes_decoding() {
+        let attributes = TileAttributes::new(0b_1010_1101);
+        assert_eq!(attributes.palette, 5);
+        assert_eq!(attributes.vram_bank, 1);
+        assert!(attributes.x_flip);
+        assert!(!attributes.y_flip);
+        assert!(attributes.priority);
+        assert_eq!(TileAttributes::new(0x00).vram_bank, 0);
+    }
 }
@@ -130,6 +130,9 @@ impl PaletteColorValue {
         self.set(new_raw);
     }
 
+    pub fn raw(&self) -> u16 {
+        self.raw
+    }
     pub fn raw_low(&self) -> u8 {
         (self.raw & 0x00FF) as u8
     }
@@ -0,0 +1,153 @@
+/// The size of the Object Attribute Memory (0xFE00...0xFE9F), holding the
+/// attributes of 40 sprites.
+pub const OAM_SIZE: usize = 0xA0;
+
+/// The hardware can only display up to 10 sprites per scanline.
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+/// A sprite (or object) as defined by its 4 bytes in OAM:
+///
+/// byte 0 : Y position on screen + 16
+/// byte 1 : X position on screen + 8
+/// byte 2 : tile index in the 0x8000-0x8FFF tileset
+/// byte 3 : attributes flags
+///   bit 7   : background and window over sprite (colors 1-3 only)
+///   bit 6   : vertical flip
+///   bit 5   : horizontal flip
+///   bit 4   : palette number (Classic mode only) : OBP0 if 0, OBP1 if 1
+///   bit 3   : tile VRAM bank (CGB mode only)
+///   bit 2-0 : palette number (CGB mode only)
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Sprite {
+    pub y: u8,
+    pub x: u8,
+    pub tile_index: u8,
+    pub flags: u8,
+}
+
+impl Sprite {
+    /// Build a 'Sprite' from its 4 bytes in OAM.
+    pub fn from_oam(data: &[u8]) -> Sprite {
+        Sprite {
+            y: data[0],
+            x: data[1],
+            tile_index: data[2],
+            flags: data[3],
+        }
+    }
+
+    pub fn behind_background(&self) -> bool {
+        self.flags & 0x80 == 0x80
+    }
+    pub fn y_flip(&self) -> bool {
+        self.flags & 0x40 == 0x40
+    }
+    pub fn x_flip(&self) -> bool {
+        self.flags & 0x20 == 0x20
+    }
+    /// The index of the object palette to use in Classic mode (0 or 1).
+    pub fn classic_palette(&self) -> usize {
+        ((self.flags >> 4) & 0x01) as usize
+    }
+    /// The index of the object palette to use in CGB mode (0-7).
+    pub fn cgb_palette(&self) -> usize {
+        (self.flags & 0x07) as usize
+    }
+    /// The VRAM bank holding the sprite tile (CGB mode only).
+    pub fn vram_bank(&self) -> usize {
+        ((self.flags >> 3) & 0x01) as usize
+    }
+}
+
+/// Return the sprites to draw on the given scanline, by order of drawing
+/// priority.
+///
+/// The first 10 sprites in OAM order that vertically intersect the scanline
+/// are selected, regardless of their horizontal position. Among them, the
+/// sprite with the smallest X coordinate has priority ; for equal X
+/// coordinates the first one in OAM wins.
+///
+/// In CGB mode, the priority only depends on the OAM order.
+pub fn line_sprites(oam: &[u8; OAM_SIZE], ly: usize, height: usize, cgb_mode: bool) -> Vec<Sprite> {
+    let line = ly + 16;
+    let mut sprites: Vec<Sprite> = oam
+        .chunks(4)
+        .map(Sprite::from_oam)
+        .filter(|sprite| (sprite.y as usize) <= line && line < sprite.y as usize + height)
+        .take(MAX_SPRITES_PER_LINE)
+        .collect();
+    if !cgb_mode {
+        // stable sort, preserving the OAM order for equal X coordinates
+        sprites.sort_by_key(|sprite| sprite.x);
+    }
+    sprites
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OAM_SIZE, Sprite, line_sprites};
+
+    fn oam_with(sprites: &[[u8; 4]]) -> [u8; OAM_SIZE] {
+        let mut oam = [0x00; OAM_SIZE];
+        for (i, sprite) in sprites.iter().enumerate() {
+            oam[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        oam
+    }
+
+    #[test]
+    fn test_sprite_from_oam() {
+        let sprite = Sprite::from_oam(&[0x10, 0x08, 0x2A, 0b_1011_0000]);
+        assert_eq!(sprite.y, 0x10);
+        assert_eq!(sprite.x, 0x08);
+        assert_eq!(sprite.tile_index, 0x2A);
+        assert!(sprite.behind_background());
+        assert!(!sprite.y_flip());
+        assert!(sprite.x_flip());
+        assert_eq!(sprite.classic_palette(), 1);
+        let sprite = Sprite::from_oam(&[0x10, 0x08, 0x2A, 0b_0000_1101]);
+        assert_eq!(sprite.cgb_palette(), 5);
+        assert_eq!(sprite.vram_bank(), 1);
+    }
+
+    #[test]
+    fn test_line_sprites_vertical_intersection() {
+        let oam = oam_with(&[[16, 8, 0, 0], [24, 8, 1, 0], [32, 8, 2, 0]]);
+        // 8x8 sprites
+        let sprites = line_sprites(&oam, 7, 8, false);
+        assert_eq!(sprites.len(), 1);
+        assert_eq!(sprites[0].tile_index, 0);
+        let sprites = line_sprites(&oam, 8, 8, false);
+        assert_eq!(sprites.len(), 1);
+        assert_eq!(sprites[0].tile_index, 1);
+        // 8x16 sprites
+        let sprites = line_sprites(&oam, 8, 16, false);
+        assert_eq!(sprites.len(), 2);
+    }
+
+    #[test]
+    fn test_line_sprites_limit_per_line() {
+        let entries: Vec<[u8; 4]> = (0..12).map(|i| [16, 200 - i as u8, i as u8, 0]).collect();
+        let sprites = line_sprites(&oam_with(&entries), 0, 8, false);
+        assert_eq!(sprites.len(), 10);
+        // the last 2 sprites in OAM are dropped even though their X is smaller
+        assert!(sprites.iter().all(|sprite| sprite.tile_index < 10));
+    }
+
+    #[test]
+    fn test_line_sprites_priority_order() {
+        let oam = oam_with(&[
+            [16, 40, 0, 0],
+            [16, 20, 1, 0],
+            [16, 40, 2, 0],
+            [16, 30, 3, 0],
+        ]);
+        let sprites = line_sprites(&oam, 0, 8, false);
+        let order: Vec<u8> = sprites.iter().map(|sprite| sprite.tile_index).collect();
+        assert_eq!(order, vec![1, 3, 0, 2]);
+        // CGB mode: OAM order only
+        let sprites = line_sprites(&oam, 0, 8, true);
+        let order: Vec<u8> = sprites.iter().map(|sprite| sprite.tile_index).collect();
+        assert_eq!(order, vec![0, 1, 2, 3]);
+    }
+}
@@ -29,6 +29,10 @@ impl Tile {
         new_tile
     }
 
+    pub fn raw_data(&self) -> &[u8; 16] {
+        &self.raw_data
+    }
+
     pub fn raw_byte(&self, index: usize) -> u8 {
         self.raw_data[index]
     }
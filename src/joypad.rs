@@ -1,6 +1,8 @@
 use self::JoypadKey::*;
+use crate::error::Result;
 use crate::irq::{Interrupt, IrqHandler};
 use crate::memory::Memory;
+use crate::state::{SaveState, StateReader, StateWriter};
 
 pub const JOYPAD_ADDRESS: u16 = 0xFF00;
 pub const JOYPAD_KEYS: [&str; 8] = ["Up", "Down", "Left", "Right", "Select", "Start", "A", "B"];
@@ -123,6 +125,19 @@ impl Memory for Joypad {
     }
 }
 
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.rows);
+        writer.write_usize(self.selection);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes(&mut self.rows)?;
+        self.selection = reader.read_usize(3)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::JoypadKey::*;
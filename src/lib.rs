@@ -1,15 +1,23 @@
 #[macro_use]
 extern crate log;
 
+pub mod apu;
 mod bios;
 pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
 pub mod gpu;
 pub mod irq;
 pub mod joypad;
 pub mod mbc;
 pub mod memory;
 pub mod mmu;
+pub mod model;
+pub mod rewind;
 pub mod serial;
+pub mod state;
+pub mod trace;
 
-/// A Result with a string literal as an error type.
-pub type ResultStr<T> = Result<T, &'static str>;
+pub use crate::error::{Error, Result};
@@ -7,18 +7,28 @@
 //! Main reference for the implementation :
 //! http://bgb.bircd.org/pandocs.htm (Pan Docs)
 
-use std::fs::File;
-use std::io::Read;
+use std::fs;
 use std::path::Path;
 
-use super::ResultStr;
+use crate::error::{Error, Result};
+use crate::state::SaveState;
 
+pub use self::info::{CartridgeInfo, CartridgeType, HeaderStrictness};
+pub use self::mbc3::{SystemTimeSource, TimeSource};
+
+mod info;
 mod mbc0;
 mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
 
 /// Allows to access information stored in the cartridge header.
 #[allow(non_camel_case_types)]
 pub enum CartridgeHeader {
+    /// CGB flag : 0x80 if the cartridge supports the CGB functions, 0xC0 if
+    /// it only works on a CGB.
+    CGB_Flag,
     MPC_TYPE,
     ROM_Size,
     RAM_Size,
@@ -27,6 +37,8 @@ pub enum CartridgeHeader {
     /// Licensee (publisher) code. If equals to 0x33, the new format will
     /// be used instead (in range 0x144...0x0145).
     LicenseeCodeOld,
+    /// Checksum of the header bytes 0x134...0x14C.
+    HeaderChecksum,
 }
 
 impl CartridgeHeader {
@@ -34,68 +46,196 @@ impl CartridgeHeader {
     /// if the header information does not fit in a single byte and/or is
     /// unsupported.
     pub fn address(&self) -> usize {
-        match self{
+        match self {
+            CGB_Flag => 0x0143,
             MPC_TYPE => 0x0147,
             ROM_Size => 0x0148,
             RAM_Size => 0x0149,
             DestinationCode => 0x014A,
             LicenseeCodeOld => 0x014B,
+            HeaderChecksum => 0x014D,
         }
     }
 
-    /// Return the RAM size from the given cartridge data.
+    /// Return the RAM size from the given cartridge data, 0 if the RAM size
+    /// code is invalid.
     pub fn ram_size(data: &[u8]) -> usize {
-        match data[RAM_Size.address()] {
-            // No RAM
-            0x00 => 0,
-            // 2 KB
-            0x01 => 0x0800,
-            // 8 KB
-            0x02 => 0x2000,
-            // 32 KB
-            0x03 => 0x8000,
-            // Not possible (see Pandoc)
-            _ => unreachable!(),
-        }
+        info::ram_size(data[RAM_Size.address()]).unwrap_or(0)
     }
 }
 
 use self::CartridgeHeader::*;
 
+/// Callback notified of the state of the rumble motor of the cartridge
+/// (true when turned on), if any.
+pub type RumbleCallback = Box<dyn FnMut(bool) + Send>;
+
 /// Memory Bank Controller trait.
-pub trait MBC {
+///
+/// The save state of an MBC holds its registers and the content of its RAM
+/// (and RTC, if any), but not the ROM.
+pub trait MBC: SaveState {
     fn rom_read(&self, address: u16) -> u8;
+    /// The ROM bank currently mapped at the given ROM address (0x0000-0x7FFF).
+    fn rom_bank(&self, address: u16) -> usize;
     fn ram_read(&self, address: u16) -> u8;
     /// For some MBCs, trying to write at specific ROM addresses allows to
     /// write to the Control Registers.
     fn rom_control(&mut self, address: u16, value: u8);
     fn ram_write(&mut self, address: u16, value: u8);
+    /// Is the external RAM (and RTC, if any) of the cartridge kept alive by
+    /// a battery, meaning its content must be saved between sessions ?
+    fn has_battery(&self) -> bool;
+    /// Export the content of the external RAM, followed by the state of the
+    /// RTC if any.
+    fn export_save_data(&self) -> Vec<u8>;
+    /// Import save data previously exported by 'export_save_data'.
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()>;
+    /// Set the callback to notify when the cartridge turns its rumble motor
+    /// on or off. Ignored by the MBCs without rumble support.
+    fn set_rumble_callback(&mut self, _callback: RumbleCallback) {}
+}
+
+/// Copy the given save data into the external RAM of a cartridge, checking
+/// that their sizes match.
+fn import_ram(ram: &mut [u8], data: &[u8]) -> Result<()> {
+    if data.len() != ram.len() {
+        return Err(Error::BadSaveData(format!(
+            "{} bytes of save data for {} bytes of cartridge RAM",
+            data.len(),
+            ram.len()
+        )));
+    }
+    ram.copy_from_slice(data);
+    Ok(())
+}
+
+/// Try to load a cartridge from the given filepath and return its header
+/// information and the appropriate MBC with its content loaded in.
+///
+/// The header is validated with the given strictness.
+pub fn load_cartridge(
+    filepath: &Path,
+    strictness: HeaderStrictness,
+) -> Result<(CartridgeInfo, Box<dyn MBC + Send>)> {
+    let data = fs::read(filepath).map_err(|error| Error::io(filepath, error))?;
+    load_cartridge_from_bytes(data, strictness)
+}
+
+/// Try to load a cartridge from the given ROM content and return its header
+/// information and the appropriate MBC with its content loaded in.
+///
+/// The header is validated with the given strictness.
+pub fn load_cartridge_from_bytes(
+    data: Vec<u8>,
+    strictness: HeaderStrictness,
+) -> Result<(CartridgeInfo, Box<dyn MBC + Send>)> {
+    load_cartridge_from_bytes_with_time_source(data, strictness, Box::new(SystemTimeSource))
 }
 
-/// Try to load a cartridge from the given filepath and return the appropriate
-/// MBC with its content loaded in.
-/// TODO: read cartridge information
-/// TODO: cartridge header checksum validation
-/// TODO: state saving with battery-backed RAM
-/// TODO: take an u8 array instead to move file loading into the actual application
-pub fn load_cartridge(filepath: &Path) -> ResultStr<Box<dyn MBC + Send>> {
-    let mut data = Vec::<u8>::new();
-    File::open(filepath)
-        .and_then(|mut f| f.read_to_end(&mut data))
-        .map_err(|_| "could not load the file as a GameBoy (Color) ROM")?;
-
-    match data[MPC_TYPE.address()] {
-        // MBC0: no MBC
-        0x00 => {
-            info!("MBC used by the cartridge: none.");
+/// Same as 'load_cartridge_from_bytes', with the given time source counting
+/// the wall-clock time of the cartridge Real Time Clock, if any, instead of
+/// the host system clock.
+pub fn load_cartridge_from_bytes_with_time_source(
+    data: Vec<u8>,
+    strictness: HeaderStrictness,
+    time_source: Box<dyn TimeSource + Send>,
+) -> Result<(CartridgeInfo, Box<dyn MBC + Send>)> {
+    if data.is_empty() {
+        return Err(Error::InvalidHeader("the ROM is empty".into()));
+    }
+
+    let info = CartridgeInfo::from_data(&data)?;
+    info.validate(&data, strictness)?;
+    // an invalid size code is already reported by the validation
+    if let Some(rom_size) = info.rom_size
+        && rom_size != data.len()
+    {
+        return Err(Error::InvalidHeader(format!(
+            "the ROM size ({} bytes) does not match its header ({} bytes)",
+            data.len(),
+            rom_size
+        )));
+    }
+    info!("cartridge: {}", info);
+
+    let mbc = match info.cartridge_type {
+        // no MBC, ROM+RAM, ROM+RAM+BATTERY
+        CartridgeType::RomOnly { .. } => {
             mbc0::MBC0::from_data(data).map(|v| Box::new(v) as Box<dyn MBC + Send>)
         }
-        // MBC1, MBC1+RAM, MBC1+RAM+BATTERY
-        0x01..=0x03 => {
-            info!("MBC used by the cartridge: MBC1.");
+        CartridgeType::MBC1 { .. } => {
             mbc1::MBC1::from_data(data).map(|v| Box::new(v) as Box<dyn MBC + Send>)
         }
+        CartridgeType::MBC2 { .. } => {
+            mbc2::MBC2::from_data(data).map(|v| Box::new(v) as Box<dyn MBC + Send>)
+        }
+        CartridgeType::MBC3 { .. } => mbc3::MBC3::with_time_source(data, time_source)
+            .map(|v| Box::new(v) as Box<dyn MBC + Send>),
+        CartridgeType::MBC5 { .. } => {
+            mbc5::MBC5::from_data(data).map(|v| Box::new(v) as Box<dyn MBC + Send>)
+        }
         // MBCs not yet implemented
-        _ => Err("MBC not implemented yet."),
+        _ => {
+            return Err(Error::UnsupportedMapper(data[MPC_TYPE.address()]));
+        }
+    }?;
+    Ok((info, mbc))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+
+    use super::{HeaderStrictness, load_cartridge_from_bytes};
+
+    fn make_rom(size: usize, mbc_type: u8, rom_size_byte: u8) -> Vec<u8> {
+        let mut data = vec![0x00; size];
+        data[0x0147] = mbc_type;
+        data[0x0148] = rom_size_byte;
+        data
+    }
+
+    #[test]
+    fn test_load_cartridge_from_bytes() {
+        let (info, mbc) =
+            load_cartridge_from_bytes(make_rom(0x10000, 0x01, 0x01), HeaderStrictness::Warn)
+                .unwrap();
+        assert_eq!(info.rom_size, Some(0x10000));
+        assert_eq!(mbc.rom_read(0x0147), 0x01);
+    }
+
+    #[test]
+    fn test_load_cartridge_from_bytes_invalid_size() {
+        let strictness = HeaderStrictness::Warn;
+        assert!(matches!(
+            load_cartridge_from_bytes(vec![], strictness),
+            Err(Error::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            load_cartridge_from_bytes(vec![0x00; 0x14F], strictness),
+            Err(Error::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            load_cartridge_from_bytes(make_rom(0x8000, 0x01, 0x01), strictness),
+            Err(Error::InvalidHeader(why)) if why.contains("does not match")
+        ));
+        // truncated bank
+        assert!(matches!(
+            load_cartridge_from_bytes(make_rom(0xFFFF, 0x01, 0x01), strictness),
+            Err(Error::InvalidHeader(why)) if why.contains("does not match")
+        ));
+    }
+
+    #[test]
+    fn test_load_cartridge_from_bytes_invalid_header() {
+        assert!(matches!(
+            load_cartridge_from_bytes(make_rom(0x8000, 0x00, 0x00), HeaderStrictness::Error),
+            Err(Error::InvalidHeader(_))
+        ));
+        assert!(matches!(
+            load_cartridge_from_bytes(make_rom(0x8000, 0x22, 0x00), HeaderStrictness::Warn),
+            Err(Error::UnsupportedMapper(0x22))
+        ));
     }
 }
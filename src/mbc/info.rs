@@ -0,0 +1,580 @@
+//! Parsing and validation of the cartridge header (0x0100...0x014F).
+//!
+//! See: https://gbdev.io/pandocs/The_Cartridge_Header.html
+
+use std::fmt;
+
+use crate::error::{Error, Result};
+
+/// The size of the ROM area holding the cartridge header.
+pub const HEADER_END: usize = 0x0150;
+
+/// How to handle a cartridge header with invalid checksums or size codes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HeaderStrictness {
+    /// Log a warning and try to run the cartridge anyway, like the hardware
+    /// which only checks the header checksum in its boot ROM.
+    #[default]
+    Warn,
+    /// Refuse to load the cartridge.
+    Error,
+}
+
+/// The hardware of the cartridge, as declared by the header byte 0x0147.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CartridgeType {
+    /// No MBC, with an optional single RAM bank.
+    RomOnly {
+        ram: bool,
+        battery: bool,
+    },
+    MBC1 {
+        ram: bool,
+        battery: bool,
+    },
+    MBC2 {
+        battery: bool,
+    },
+    MMM01 {
+        ram: bool,
+        battery: bool,
+    },
+    MBC3 {
+        timer: bool,
+        ram: bool,
+        battery: bool,
+    },
+    MBC5 {
+        rumble: bool,
+        ram: bool,
+        battery: bool,
+    },
+    MBC6,
+    /// MBC7+SENSOR+RUMBLE+RAM+BATTERY.
+    MBC7,
+    PocketCamera,
+    BandaiTama5,
+    HuC3,
+    /// HuC1+RAM+BATTERY.
+    HuC1,
+    Unknown(u8),
+}
+
+impl CartridgeType {
+    pub fn from_byte(byte: u8) -> CartridgeType {
+        use self::CartridgeType::*;
+        match byte {
+            0x00 => RomOnly {
+                ram: false,
+                battery: false,
+            },
+            0x01 => MBC1 {
+                ram: false,
+                battery: false,
+            },
+            0x02 => MBC1 {
+                ram: true,
+                battery: false,
+            },
+            0x03 => MBC1 {
+                ram: true,
+                battery: true,
+            },
+            0x05 => MBC2 { battery: false },
+            0x06 => MBC2 { battery: true },
+            0x08 => RomOnly {
+                ram: true,
+                battery: false,
+            },
+            0x09 => RomOnly {
+                ram: true,
+                battery: true,
+            },
+            0x0B => MMM01 {
+                ram: false,
+                battery: false,
+            },
+            0x0C => MMM01 {
+                ram: true,
+                battery: false,
+            },
+            0x0D => MMM01 {
+                ram: true,
+                battery: true,
+            },
+            0x0F => MBC3 {
+                timer: true,
+                ram: false,
+                battery: true,
+            },
+            0x10 => MBC3 {
+                timer: true,
+                ram: true,
+                battery: true,
+            },
+            0x11 => MBC3 {
+                timer: false,
+                ram: false,
+                battery: false,
+            },
+            0x12 => MBC3 {
+                timer: false,
+                ram: true,
+                battery: false,
+            },
+            0x13 => MBC3 {
+                timer: false,
+                ram: true,
+                battery: true,
+            },
+            0x19 => MBC5 {
+                rumble: false,
+                ram: false,
+                battery: false,
+            },
+            0x1A => MBC5 {
+                rumble: false,
+                ram: true,
+                battery: false,
+            },
+            0x1B => MBC5 {
+                rumble: false,
+                ram: true,
+                battery: true,
+            },
+            0x1C => MBC5 {
+                rumble: true,
+                ram: false,
+                battery: false,
+            },
+            0x1D => MBC5 {
+                rumble: true,
+                ram: true,
+                battery: false,
+            },
+            0x1E => MBC5 {
+                rumble: true,
+                ram: true,
+                battery: true,
+            },
+            0x20 => MBC6,
+            0x22 => MBC7,
+            0xFC => PocketCamera,
+            0xFD => BandaiTama5,
+            0xFE => HuC3,
+            0xFF => HuC1,
+            _ => Unknown(byte),
+        }
+    }
+
+    /// Is the cartridge RAM (or RTC) kept alive by a battery ?
+    pub fn has_battery(&self) -> bool {
+        use self::CartridgeType::*;
+        match *self {
+            RomOnly { battery, .. }
+            | MBC1 { battery, .. }
+            | MBC2 { battery }
+            | MMM01 { battery, .. }
+            | MBC3 { battery, .. }
+            | MBC5 { battery, .. } => battery,
+            MBC7 | HuC1 => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CartridgeType::*;
+        let (name, timer, rumble, ram, battery) = match *self {
+            RomOnly { ram, battery } => ("ROM", false, false, ram, battery),
+            MBC1 { ram, battery } => ("MBC1", false, false, ram, battery),
+            MBC2 { battery } => ("MBC2", false, false, false, battery),
+            MMM01 { ram, battery } => ("MMM01", false, false, ram, battery),
+            MBC3 {
+                timer,
+                ram,
+                battery,
+            } => ("MBC3", timer, false, ram, battery),
+            MBC5 {
+                rumble,
+                ram,
+                battery,
+            } => ("MBC5", false, rumble, ram, battery),
+            MBC6 => ("MBC6", false, false, false, false),
+            MBC7 => ("MBC7+SENSOR", false, true, true, true),
+            PocketCamera => ("POCKET CAMERA", false, false, false, false),
+            BandaiTama5 => ("BANDAI TAMA5", false, false, false, false),
+            HuC3 => ("HuC3", false, false, false, false),
+            HuC1 => ("HuC1", false, false, true, true),
+            Unknown(byte) => return write!(f, "unknown ({:0>2X})", byte),
+        };
+        write!(f, "{}", name)?;
+        for (present, feature) in [
+            (timer, "TIMER"),
+            (rumble, "RUMBLE"),
+            (ram, "RAM"),
+            (battery, "BATTERY"),
+        ] {
+            if present {
+                write!(f, "+{}", feature)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The information stored in the cartridge header.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CartridgeInfo {
+    /// 0x0134...0x0143 : the game title, in upper case ASCII.
+    pub title: String,
+    /// 0x013F...0x0142 : the manufacturer code, only present on some of the
+    /// cartridges released after the CGB.
+    pub manufacturer_code: Option<String>,
+    /// 0x0143 : 0x80 if the cartridge supports the CGB functions, 0xC0 if it
+    /// only works on a CGB.
+    pub cgb_flag: u8,
+    /// 0x0144...0x0145 : the publisher code, used if the old licensee code is
+    /// 0x33.
+    pub new_licensee_code: String,
+    /// 0x014B : the publisher code.
+    pub old_licensee_code: u8,
+    /// 0x0146 : does the cartridge support the SGB functions ?
+    pub sgb_flag: bool,
+    /// 0x0147 : the MBC and other hardware of the cartridge.
+    pub cartridge_type: CartridgeType,
+    /// 0x0148 : the ROM size in bytes, or None if the size code is invalid.
+    pub rom_size: Option<usize>,
+    /// 0x0149 : the external RAM size in bytes, or None if the size code is
+    /// invalid.
+    pub ram_size: Option<usize>,
+    /// 0x014C : the version number of the game.
+    pub version: u8,
+    /// 0x014D : the checksum of the header bytes 0x0134...0x014C.
+    pub header_checksum: u8,
+    /// 0x014E...0x014F : the checksum of the whole ROM, except these 2 bytes.
+    pub global_checksum: u16,
+}
+
+impl CartridgeInfo {
+    /// Parse the header of the given ROM.
+    pub fn from_data(data: &[u8]) -> Result<CartridgeInfo> {
+        if data.len() < HEADER_END {
+            return Err(Error::InvalidHeader(format!(
+                "ROM too small ({} bytes) to contain a cartridge header",
+                data.len()
+            )));
+        }
+
+        let cgb_flag = data[0x0143];
+        // newer cartridges use the end of the title area for the
+        // manufacturer code and the CGB flag
+        let manufacturer_code = &data[0x013F..0x0143];
+        let has_manufacturer_code = cgb_flag & 0x80 == 0x80
+            && manufacturer_code
+                .iter()
+                .all(|&c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let title_end = if has_manufacturer_code {
+            0x013F
+        } else if cgb_flag & 0x80 == 0x80 {
+            0x0143
+        } else {
+            0x0144
+        };
+
+        Ok(CartridgeInfo {
+            title: header_string(&data[0x0134..title_end]),
+            manufacturer_code: if has_manufacturer_code {
+                Some(header_string(manufacturer_code))
+            } else {
+                None
+            },
+            cgb_flag,
+            new_licensee_code: header_string(&data[0x0144..0x0146]),
+            old_licensee_code: data[0x014B],
+            sgb_flag: data[0x0146] == 0x03,
+            cartridge_type: CartridgeType::from_byte(data[0x0147]),
+            rom_size: rom_size(data[0x0148]),
+            ram_size: ram_size(data[0x0149]),
+            version: data[0x014C],
+            header_checksum: data[0x014D],
+            global_checksum: ((data[0x014E] as u16) << 8) | data[0x014F] as u16,
+        })
+    }
+
+    /// Check the header checksums and size codes against the given ROM.
+    ///
+    /// Depending on the strictness, the problems found are either logged as
+    /// warnings or make the validation fail.
+    pub fn validate(&self, data: &[u8], strictness: HeaderStrictness) -> Result<()> {
+        let header_checksum = compute_header_checksum(data);
+        let global_checksum = compute_global_checksum(data);
+        let checks = [
+            (
+                self.header_checksum == header_checksum,
+                "invalid cartridge header checksum",
+                format!(
+                    "expected {:0>2X}, computed {:0>2X}",
+                    self.header_checksum, header_checksum
+                ),
+            ),
+            (
+                self.global_checksum == global_checksum,
+                "invalid cartridge global checksum",
+                format!(
+                    "expected {:0>4X}, computed {:0>4X}",
+                    self.global_checksum, global_checksum
+                ),
+            ),
+            (
+                self.rom_size.is_some(),
+                "invalid cartridge ROM size code",
+                format!("{:0>2X}", data[0x0148]),
+            ),
+            (
+                self.ram_size.is_some(),
+                "invalid cartridge RAM size code",
+                format!("{:0>2X}", data[0x0149]),
+            ),
+        ];
+
+        let mut result = Ok(());
+        for (valid, error, details) in checks {
+            if valid {
+                continue;
+            }
+            match strictness {
+                HeaderStrictness::Warn => warn!("{} : {}", error, details),
+                HeaderStrictness::Error => {
+                    error!("{} : {}", error, details);
+                    if result.is_ok() {
+                        result = Err(Error::InvalidHeader(format!("{} : {}", error, details)));
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Does the cartridge support the CGB functions ?
+    pub fn cgb_compatible(&self) -> bool {
+        self.cgb_flag & 0x80 == 0x80
+    }
+
+    /// Does the cartridge only work on a CGB ?
+    pub fn cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+}
+
+impl fmt::Display for CartridgeInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "\"{}\" (v{}, {}",
+            self.title, self.version, self.cartridge_type
+        )?;
+        if let Some(rom_size) = self.rom_size {
+            write!(f, ", {} KB ROM", rom_size / 1024)?;
+        }
+        if let Some(ram_size) = self.ram_size.filter(|&size| size > 0) {
+            write!(f, ", {} KB RAM", ram_size / 1024)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Return the ROM size in bytes for the given header size code.
+pub fn rom_size(code: u8) -> Option<usize> {
+    match code {
+        // 32 KB << code : 2 to 512 banks
+        0x00..=0x08 => Some(0x8000 << code),
+        // 1.1 MB, 1.2 MB and 1.5 MB (72, 80 and 96 banks)
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+        _ => None,
+    }
+}
+
+/// Return the external RAM size in bytes for the given header size code.
+pub fn ram_size(code: u8) -> Option<usize> {
+    match code {
+        // No RAM
+        0x00 => Some(0),
+        // 2 KB
+        0x01 => Some(0x0800),
+        // 8 KB
+        0x02 => Some(0x2000),
+        // 32 KB
+        0x03 => Some(0x8000),
+        // 128 KB
+        0x04 => Some(0x20000),
+        // 64 KB
+        0x05 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// Compute the checksum of the header bytes 0x0134...0x014C, as verified by
+/// the boot ROM.
+pub fn compute_header_checksum(data: &[u8]) -> u8 {
+    data[0x0134..=0x014C].iter().fold(0u8, |checksum, &byte| {
+        checksum.wrapping_sub(byte).wrapping_sub(1)
+    })
+}
+
+/// Compute the sum of all the ROM bytes, except the global checksum itself.
+pub fn compute_global_checksum(data: &[u8]) -> u16 {
+    data.iter()
+        .enumerate()
+        .filter(|&(address, _)| address != 0x014E && address != 0x014F)
+        .fold(0u16, |checksum, (_, &byte)| {
+            checksum.wrapping_add(byte as u16)
+        })
+}
+
+/// Read a NUL-padded ASCII string from the header, replacing the
+/// non-printable characters.
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&c| c != 0x00)
+        .map(|&c| {
+            if c.is_ascii_graphic() || c == b' ' {
+                c as char
+            } else {
+                '?'
+            }
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+
+    use super::{
+        CartridgeInfo, CartridgeType, HEADER_END, HeaderStrictness, compute_global_checksum,
+        compute_header_checksum,
+    };
+
+    /// Return a 32 KB ROM with the given title, CGB flag and cartridge type,
+    /// and valid checksums.
+    fn make_rom(title: &[u8], cgb_flag: u8, mbc_type: u8) -> Vec<u8> {
+        let mut data = vec![0x00; 0x8000];
+        data[0x0134..0x0134 + title.len()].copy_from_slice(title);
+        data[0x0143] = cgb_flag;
+        data[0x0147] = mbc_type;
+        fix_checksums(&mut data);
+        data
+    }
+
+    fn fix_checksums(data: &mut [u8]) {
+        data[0x014D] = compute_header_checksum(data);
+        let global_checksum = compute_global_checksum(data);
+        data[0x014E] = (global_checksum >> 8) as u8;
+        data[0x014F] = global_checksum as u8;
+    }
+
+    #[test]
+    fn test_cartridge_info_parsing() {
+        let mut data = make_rom(b"TETRIS", 0x00, 0x01);
+        data[0x0146] = 0x03;
+        data[0x0148] = 0x05;
+        data[0x0149] = 0x03;
+        data[0x014B] = 0x33;
+        data[0x0144..0x0146].copy_from_slice(b"01");
+        data[0x014C] = 0x02;
+        fix_checksums(&mut data);
+        let info = CartridgeInfo::from_data(&data).unwrap();
+        assert_eq!(info.title, "TETRIS");
+        assert_eq!(info.manufacturer_code, None);
+        assert!(!info.cgb_compatible());
+        assert!(info.sgb_flag);
+        assert_eq!(
+            info.cartridge_type,
+            CartridgeType::MBC1 {
+                ram: false,
+                battery: false
+            }
+        );
+        assert_eq!(info.rom_size, Some(0x100000));
+        assert_eq!(info.ram_size, Some(0x8000));
+        assert_eq!(info.old_licensee_code, 0x33);
+        assert_eq!(info.new_licensee_code, "01");
+        assert_eq!(info.version, 0x02);
+        assert!(info.validate(&data, HeaderStrictness::Error).is_ok());
+    }
+
+    #[test]
+    fn test_cartridge_info_cgb_title() {
+        let info = CartridgeInfo::from_data(&make_rom(b"POKEMON_SLVAAXE", 0x80, 0x10)).unwrap();
+        assert_eq!(info.title, "POKEMON_SLV");
+        assert_eq!(info.manufacturer_code, Some("AAXE".to_string()));
+        assert!(info.cgb_compatible());
+        assert!(!info.cgb_only());
+        let info = CartridgeInfo::from_data(&make_rom(b"ZELDA DX", 0xC0, 0x1B)).unwrap();
+        assert_eq!(info.title, "ZELDA DX");
+        assert_eq!(info.manufacturer_code, None);
+        assert!(info.cgb_only());
+    }
+
+    #[test]
+    fn test_cartridge_info_too_small() {
+        assert!(CartridgeInfo::from_data(&[0x00; HEADER_END - 1]).is_err());
+    }
+
+    #[test]
+    fn test_cartridge_info_ram_size_codes() {
+        for (code, size) in [(0x04, Some(0x20000)), (0x05, Some(0x10000)), (0x06, None)] {
+            let mut data = make_rom(b"TEST", 0x00, 0x1B);
+            data[0x0149] = code;
+            fix_checksums(&mut data);
+            let info = CartridgeInfo::from_data(&data).unwrap();
+            assert_eq!(info.ram_size, size);
+            assert_eq!(
+                info.validate(&data, HeaderStrictness::Error).is_ok(),
+                size.is_some()
+            );
+        }
+    }
+
+    #[test]
+    fn test_cartridge_info_checksums_strictness() {
+        let mut data = make_rom(b"TEST", 0x00, 0x00);
+        data[0x0200] = 0x42;
+        let info = CartridgeInfo::from_data(&data).unwrap();
+        assert!(info.validate(&data, HeaderStrictness::Warn).is_ok());
+        assert!(matches!(
+            info.validate(&data, HeaderStrictness::Error),
+            Err(Error::InvalidHeader(why)) if why.starts_with("invalid cartridge global checksum")
+        ));
+        data[0x0134] = b'B';
+        let info = CartridgeInfo::from_data(&data).unwrap();
+        assert!(info.validate(&data, HeaderStrictness::Warn).is_ok());
+        assert!(matches!(
+            info.validate(&data, HeaderStrictness::Error),
+            Err(Error::InvalidHeader(why)) if why.starts_with("invalid cartridge header checksum")
+        ));
+    }
+
+    #[test]
+    fn test_cartridge_type_display() {
+        assert_eq!(CartridgeType::from_byte(0x00).to_string(), "ROM");
+        assert_eq!(
+            CartridgeType::from_byte(0x10).to_string(),
+            "MBC3+TIMER+RAM+BATTERY"
+        );
+        assert_eq!(
+            CartridgeType::from_byte(0x1E).to_string(),
+            "MBC5+RUMBLE+RAM+BATTERY"
+        );
+        assert_eq!(CartridgeType::from_byte(0x42).to_string(), "unknown (42)");
+        assert!(CartridgeType::from_byte(0x13).has_battery());
+        assert!(!CartridgeType::from_byte(0x12).has_battery());
+    }
+}
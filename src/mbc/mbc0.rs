@@ -1,7 +1,10 @@
-///! Used by small games who can fit in the 32 KB of ROM and the GameBoy's 8 KB
-///! of external RAM (optional).
+//! Used by small games who can fit in the 32 KB of ROM and the GameBoy's 8 KB
+//! of external RAM (optional).
 
-use super::{CartridgeHeader, MBC};
+use crate::error::{Error, Result};
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::{CartridgeHeader, MBC, import_ram};
 
 pub const ROM_SIZE: usize = 0x10000;
 /// 8 KB RAM (optional).
@@ -18,12 +21,17 @@ pub struct MBC0 {
     rom: [u8; ROM_SIZE],
     /// Optional external RAM mapped at `0xA000..=0xBFFF`.
     eram: Option<[u8; ERAM_SIZE]>,
+    /// Is the external RAM battery-buffered ?
+    battery: bool,
 }
 
 impl MBC0 {
-    pub fn from_data(data: Vec<u8>) -> Result<MBC0, &'static str> {
+    pub fn from_data(data: Vec<u8>) -> Result<MBC0> {
         if data.is_empty() || data.len() > ROM_SIZE {
-            return Err("invalid ROM size for MBC0");
+            return Err(Error::InvalidHeader(format!(
+                "invalid ROM size of {} bytes for MBC0",
+                data.len()
+            )));
         }
 
         let mut rom = [0x00; ROM_SIZE];
@@ -33,11 +41,14 @@ impl MBC0 {
             0 => None,
             ERAM_SIZE => Some([0x00; ERAM_SIZE]),
             n => {
-                error!("MBC0 : invalid external RAM size of {} bytes", n);
-                return Err("MBC0 supports either 0kB or 8kB of external RAM");
+                return Err(Error::InvalidHeader(format!(
+                    "MBC0 supports either 0kB or 8kB of external RAM, not {} bytes",
+                    n
+                )));
             }
         };
-        Ok(MBC0 { rom, eram })
+        let battery = data[CartridgeHeader::MPC_TYPE.address()] == 0x09;
+        Ok(MBC0 { rom, eram, battery })
     }
 }
 
@@ -45,6 +56,9 @@ impl MBC for MBC0 {
     fn rom_read(&self, address: u16) -> u8 {
         self.rom[address as usize]
     }
+    fn rom_bank(&self, address: u16) -> usize {
+        (address as usize) >> 14
+    }
     fn ram_read(&self, address: u16) -> u8 {
         if let Some(eram) = self.eram {
             let eram_address = (address as usize) & 0x1FFF;
@@ -60,13 +74,41 @@ impl MBC for MBC0 {
             eram[(address as usize) & 0x1FFF] = value;
         }
     }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+    fn export_save_data(&self) -> Vec<u8> {
+        self.eram.map_or(Vec::new(), |eram| eram.to_vec())
+    }
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()> {
+        match self.eram {
+            Some(ref mut eram) => import_ram(eram, data),
+            None => import_ram(&mut [], data),
+        }
+    }
+}
+
+impl SaveState for MBC0 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        if let Some(ref eram) = self.eram {
+            writer.write_data(eram);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        match self.eram {
+            Some(ref mut eram) => reader.read_data(eram),
+            None => Ok(()),
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
     use crate::mbc::{CartridgeHeader, MBC};
 
-    use super::{MBC0, ROM_SIZE};
+    use super::{ERAM_SIZE, MBC0, ROM_SIZE};
 
     fn make_rom(ram_size_byte: u8) -> Vec<u8> {
         let mut data = vec![0x00; ROM_SIZE];
@@ -180,4 +222,24 @@ mod test {
         assert_eq!(mbc.ram_read(0xA000), 0x11);
         assert_eq!(mbc.ram_read(0xBFFF), 0x22);
     }
+
+    // battery-buffered RAM
+
+    #[test]
+    fn test_mbc0_save_data() {
+        assert!(!make_mbc0_with_eram().has_battery());
+        let mut data = make_rom(0x02);
+        data[CartridgeHeader::MPC_TYPE.address()] = 0x09; // ROM+RAM+BATTERY
+        let mut mbc = MBC0::from_data(data).unwrap();
+        assert!(mbc.has_battery());
+        mbc.ram_write(0xA123, 0x42);
+        let save = mbc.export_save_data();
+        assert_eq!(save.len(), ERAM_SIZE);
+
+        let mut mbc = make_mbc0_with_eram();
+        assert!(mbc.import_save_data(&save[1..]).is_err());
+        assert!(mbc.import_save_data(&save).is_ok());
+        assert_eq!(mbc.ram_read(0xA123), 0x42);
+        assert!(make_mbc0_no_eram().export_save_data().is_empty());
+    }
 }
@@ -1,76 +1,141 @@
-///! Can address up to 125 ROM banks of 16KB each (i.e. 2MB of ROM at most) and
-///! supports 0, 2, 8 or 32 KB of RAM (eventually battery-buffered).
-///!
-///! See: https://gbdev.io/pandocs/MBC1.html
-
-use crate::ResultStr;
-
-use super::{CartridgeHeader, MBC};
+//! Can address up to 128 ROM banks of 16KB each (i.e. 2MB of ROM at most) and
+//! supports 0, 2, 8 or 32 KB of RAM (eventually battery-buffered).
+//!
+//! Banks 0x20, 0x40 and 0x60 cannot be mapped in the 0x4000...0x7FFF range,
+//! but can be in the 0x0000...0x3FFF range in advanced banking mode.
+//!
+//! The MBC1M multicart variant wires the secondary bank register to bits 4-5
+//! of the ROM bank instead of bits 5-6, each 256KB quarter of the ROM holding
+//! a different game.
+//!
+//! See: https://gbdev.io/pandocs/MBC1.html
+
+use crate::error::{Error, Result};
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::{CartridgeHeader, MBC, import_ram};
 
 pub const ROM_SIZE: usize = 0x200000; // 2 MB: 128 banks × 16 KiB
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// The logo bitmap found at 0x0104...0x0133 in the header of every cartridge.
+const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
 
 pub struct MBC1 {
     rom: Vec<u8>,
     ram: Vec<u8>,
-    /// The current ROM bank to use when reading in the 0x4000...0x7FFF range.
-    ///
-    /// Possible values are in 0x00...0x7F with the exception of 0x20, 0x40 and
-    /// 0x60 which explains the limit of 125 banks (including ROM bank 0x00).
-    rom_bank: usize,
-    ram_bank: usize,
+    /// 0x2000...0x3FFF : the lower 5 bits of the ROM bank to use when reading
+    /// in the 0x4000...0x7FFF range (0x01...0x1F, writing 0x00 selects 0x01).
+    bank1: usize,
+    /// 0x4000...0x5FFF : the secondary 2-bit register, used as the upper
+    /// bits of the ROM bank number and, in advanced banking mode, as the RAM
+    /// bank number.
+    bank2: usize,
     ram_enabled: bool,
     /// Value:
     /// - false: "simple" (default): 0000–3FFF and A000–BFFF are locked to bank 0 of ROM and SRAM respectively
     /// - true: "advanced": 0000–3FFF and A000-BFFF can be bank-switched via the 4000–5FFF register
     ram_mode: bool,
+    /// Is the cartridge an MBC1M multicart ?
+    multicart: bool,
+    /// Is the external RAM battery-buffered ?
+    battery: bool,
 }
 
 impl MBC1 {
-    pub fn from_data(data: Vec<u8>) -> ResultStr<MBC1> {
+    pub fn from_data(data: Vec<u8>) -> Result<MBC1> {
         if data.len() > ROM_SIZE {
-            return Err("ROM size too big for MBC1");
+            return Err(Error::InvalidHeader(format!(
+                "ROM size of {} bytes too big for MBC1",
+                data.len()
+            )));
         }
 
-        let ram_size = match data[CartridgeHeader::MPC_TYPE.address()] {
-            // RAM
-            0x02 => CartridgeHeader::ram_size(&data),
-            // RAM+BATTERY
-            0x03 => {
-                // TODO: state loading
-                CartridgeHeader::ram_size(&data)
-            }
+        let mbc_type = data[CartridgeHeader::MPC_TYPE.address()];
+        let ram_size = match mbc_type {
+            // RAM, RAM+BATTERY
+            0x02 | 0x03 => CartridgeHeader::ram_size(&data),
             _ => 0,
         };
 
+        let multicart = MBC1::is_multicart(&data);
+        if multicart {
+            info!("MBC1M multicart detected.");
+        }
+
         Ok(MBC1 {
             rom: data,
             ram: vec![0x00; ram_size],
-            rom_bank: 0x01,
-            ram_bank: 0x00,
+            bank1: 0x01,
+            bank2: 0x00,
             ram_enabled: false,
             ram_mode: false,
+            multicart,
+            battery: mbc_type == 0x03,
         })
     }
+
+    /// Detect an MBC1M multicart : a 1MB ROM with the header of another game
+    /// (and so the logo) at the start of its second 256KB quarter (bank 0x10).
+    fn is_multicart(data: &[u8]) -> bool {
+        let logo = 0x10 * ROM_BANK_SIZE + 0x0104;
+        data.len() == 0x40 * ROM_BANK_SIZE
+            && data[logo..logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+    }
+
+    /// The ROM bank number bits selected by the secondary register.
+    fn bank2_rom_bits(&self) -> usize {
+        if self.multicart {
+            self.bank2 << 4
+        } else {
+            self.bank2 << 5
+        }
+    }
+
+    /// Get the index in 'ram' of the given address in the current RAM bank.
+    fn ram_index(&self, address: u16) -> usize {
+        let ram_bank = if self.ram_mode { self.bank2 } else { 0x00 };
+        (ram_bank * RAM_BANK_SIZE + ((address as usize) & 0x1FFF)) % self.ram.len()
+    }
 }
 
 impl MBC for MBC1 {
     fn rom_read(&self, address: u16) -> u8 {
-        // ROM bank 00
-        if address < 0x4000 {
-            self.rom[address as usize]
+        self.rom[self.rom_bank(address) * ROM_BANK_SIZE + ((address as usize) & 0x3FFF)]
+    }
+
+    /// The bank number wraps around the actual ROM size.
+    fn rom_bank(&self, address: u16) -> usize {
+        // ROM bank 00, or 00/20/40/60 in advanced banking mode
+        let bank = if address < 0x4000 {
+            if self.ram_mode {
+                self.bank2_rom_bits()
+            } else {
+                0x00
+            }
         }
         // ROM bank 01-7F
         else {
-            self.rom[self.rom_bank * 0x4000 + ((address as usize) & 0x3FFF)]
-        }
+            let bank1 = if self.multicart {
+                self.bank1 & 0x0F
+            } else {
+                self.bank1
+            };
+            self.bank2_rom_bits() | bank1
+        };
+        bank % (self.rom.len() / ROM_BANK_SIZE).max(1)
     }
 
     fn ram_read(&self, address: u16) -> u8 {
-        if !self.ram_enabled {
+        if !self.ram_enabled || self.ram.is_empty() {
             return 0x00;
         }
-        let ram_bank = if self.ram_mode { self.ram_bank } else { 0x00 };
-        self.ram[ram_bank * 0x2000 + ((address as usize) & 0x1FFF)]
+        self.ram[self.ram_index(address)]
     }
 
     fn rom_control(&mut self, address: u16, value: u8) {
@@ -81,36 +146,59 @@ impl MBC for MBC1 {
             }
             // ROM bank number : lower 5 bits
             0x2000..=0x3FFF => {
-                self.rom_bank = (self.rom_bank & 0x60)
-                    + match (value as usize) & 0x1F {
-                        0x0 => 0x1,
-                        n => n,
-                    };
+                self.bank1 = match (value as usize) & 0x1F {
+                    0x0 => 0x1,
+                    n => n,
+                };
             }
+            // RAM bank number or upper bits of the ROM bank number
             0x4000..=0x5FFF => {
-                let n = (value as usize) & 0x03;
-                if self.ram_mode {
-                    // RAM bank number
-                    self.ram_bank = n;
-                } else {
-                    // bits 6 and 7 of ROM bank number in ROM mode
-                    self.rom_bank = (self.rom_bank & 0x1F) | (n << 5);
-                }
+                self.bank2 = (value as usize) & 0x03;
             }
-            // ROM/RAM mode select
+            // banking mode select
             0x6000..=0x7FFF => {
-                self.ram_mode = value == 0x01;
+                self.ram_mode = value & 0x01 == 0x01;
             }
             _ => panic!("MBC1 : cannot write to ROM at {address:0>4X}"),
         }
     }
 
     fn ram_write(&mut self, address: u16, value: u8) {
-        if !self.ram_enabled {
+        if !self.ram_enabled || self.ram.is_empty() {
             return;
         }
-        let ram_bank = if self.ram_mode { self.ram_bank } else { 0x00 };
-        self.ram[ram_bank * 0x2000 + ((address as usize) & 0x1FFF)] = value;
+        let index = self.ram_index(address);
+        self.ram[index] = value;
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn export_save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()> {
+        import_ram(&mut self.ram, data)
+    }
+}
+
+impl SaveState for MBC1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.bank1);
+        writer.write_usize(self.bank2);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.ram_mode);
+        writer.write_data(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.bank1 = reader.read_usize(0x20)?.max(0x01);
+        self.bank2 = reader.read_usize(0x04)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.ram_mode = reader.read_bool()?;
+        reader.read_data(&mut self.ram)
     }
 }
 
@@ -118,7 +206,7 @@ impl MBC for MBC1 {
 mod test {
     use crate::mbc::{CartridgeHeader, MBC};
 
-    use super::{MBC1, ROM_SIZE};
+    use super::{MBC1, NINTENDO_LOGO, ROM_SIZE};
 
     // MBC1 cartridge type header values (0x0147).
     const MBC1_ROM_ONLY: u8 = 0x01;
@@ -306,4 +394,149 @@ mod test {
         mbc.ram_write(0xA000, 0xCC); // writes to bank 0 (ROM mode)
         assert_eq!(mbc.ram_read(0xA000), 0xCC);
     }
+
+    // large ROMs (1 MB and more)
+
+    #[test]
+    fn test_mbc1_rom_bank_upper_bits() {
+        // The 0x4000..=0x5FFF register provides bits 5-6 of the ROM bank.
+        let mut data = make_rom(MBC1_ROM_ONLY, 0x00);
+        data[0x21 * 0x4000] = 0x21;
+        data[0x7F * 0x4000] = 0x7F;
+        let mut mbc = MBC1::from_data(data).unwrap();
+        mbc.rom_control(0x2000, 0x01);
+        mbc.rom_control(0x4000, 0x01);
+        assert_eq!(mbc.rom_read(0x4000), 0x21);
+        mbc.rom_control(0x2000, 0x1F);
+        mbc.rom_control(0x4000, 0x03);
+        assert_eq!(mbc.rom_read(0x4000), 0x7F);
+    }
+
+    #[test]
+    fn test_mbc1_rom_bank_0x20_remaps_to_0x21() {
+        // The lower 5 bits being 0, bank 0x20 cannot be mapped at 0x4000.
+        let mut data = make_rom(MBC1_ROM_ONLY, 0x00);
+        data[0x20 * 0x4000] = 0x20;
+        data[0x21 * 0x4000] = 0x21;
+        let mut mbc = MBC1::from_data(data).unwrap();
+        mbc.rom_control(0x4000, 0x01);
+        mbc.rom_control(0x2000, 0x00);
+        assert_eq!(mbc.rom_read(0x4000), 0x21);
+    }
+
+    #[test]
+    fn test_mbc1_advanced_mode_bank0_remap() {
+        // In advanced mode, 0x0000..=0x3FFF maps bank 0x00/0x20/0x40/0x60.
+        let mut data = make_rom(MBC1_ROM_ONLY, 0x00);
+        data[0x0000] = 0x00;
+        data[0x40 * 0x4000] = 0x40;
+        let mut mbc = MBC1::from_data(data).unwrap();
+        mbc.rom_control(0x4000, 0x02);
+        assert_eq!(mbc.rom_read(0x0000), 0x00);
+        mbc.rom_control(0x6000, 0x01);
+        assert_eq!(mbc.rom_read(0x0000), 0x40);
+        mbc.rom_control(0x6000, 0x00);
+        assert_eq!(mbc.rom_read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_mbc1_rom_bank_wraps_around_rom_size() {
+        // 256 KB ROM : 16 banks, so bank 0x12 is bank 0x02.
+        let mut data = vec![0x00; 16 * 0x4000];
+        data[0x0147] = MBC1_ROM_ONLY;
+        data[0x02 * 0x4000] = 0x02;
+        let mut mbc = MBC1::from_data(data).unwrap();
+        mbc.rom_control(0x2000, 0x12);
+        assert_eq!(mbc.rom_read(0x4000), 0x02);
+        // the upper bits are ignored as well
+        mbc.rom_control(0x4000, 0x03);
+        mbc.rom_control(0x6000, 0x01);
+        assert_eq!(mbc.rom_read(0x4000), 0x02);
+        assert_eq!(mbc.rom_read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_mbc1_small_ram_wraps_around() {
+        let mut mbc = MBC1::from_data(make_rom(MBC1_RAM, 0x01)).unwrap(); // 2 KB RAM
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.ram_write(0xA000, 0x42);
+        assert_eq!(mbc.ram_read(0xA800), 0x42);
+    }
+
+    // MBC1M multicarts
+
+    fn make_multicart_rom() -> Vec<u8> {
+        let mut data = vec![0x00; 0x100000];
+        data[0x0147] = MBC1_ROM_ONLY;
+        for game in 0..4 {
+            let header = game * 0x40000 + 0x0104;
+            data[header..header + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+            // first byte of each bank : its number
+            for bank in 0..0x10 {
+                data[game * 0x40000 + bank * 0x4000] = (game * 0x10 + bank) as u8;
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_mbc1_multicart_detection() {
+        assert!(MBC1::from_data(make_multicart_rom()).unwrap().multicart);
+        // a regular 1 MB cartridge
+        let mut data = make_multicart_rom();
+        data[0x40104] = 0x00;
+        assert!(!MBC1::from_data(data).unwrap().multicart);
+        assert!(
+            !MBC1::from_data(make_rom(MBC1_ROM_ONLY, 0x00))
+                .unwrap()
+                .multicart
+        );
+    }
+
+    #[test]
+    fn test_mbc1_multicart_banking() {
+        let mut mbc = MBC1::from_data(make_multicart_rom()).unwrap();
+        // the secondary register selects the game (bits 4-5)
+        mbc.rom_control(0x4000, 0x02);
+        mbc.rom_control(0x2000, 0x03);
+        assert_eq!(mbc.rom_read(0x4000), 0x23);
+        // bit 4 of the primary register is ignored
+        mbc.rom_control(0x2000, 0x13);
+        assert_eq!(mbc.rom_read(0x4000), 0x23);
+        // bank 0 of the game can be mapped at 0x4000
+        mbc.rom_control(0x2000, 0x10);
+        assert_eq!(mbc.rom_read(0x4000), 0x20);
+        // advanced mode maps the game's first bank at 0x0000
+        assert_eq!(mbc.rom_read(0x0000), 0x00);
+        mbc.rom_control(0x6000, 0x01);
+        assert_eq!(mbc.rom_read(0x0000), 0x20);
+    }
+
+    // battery-buffered RAM
+
+    #[test]
+    fn test_mbc1_save_data() {
+        assert!(
+            !MBC1::from_data(make_rom(MBC1_RAM, 0x03))
+                .unwrap()
+                .has_battery()
+        );
+        let mut mbc = MBC1::from_data(make_rom(MBC1_RAM_BATTERY, 0x03)).unwrap();
+        assert!(mbc.has_battery());
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x6000, 0x01);
+        mbc.rom_control(0x4000, 0x03);
+        mbc.ram_write(0xBFFF, 0x42);
+        let data = mbc.export_save_data();
+        assert_eq!(data.len(), 0x8000);
+        assert_eq!(data[0x7FFF], 0x42);
+
+        let mut mbc = MBC1::from_data(make_rom(MBC1_RAM_BATTERY, 0x03)).unwrap();
+        assert!(mbc.import_save_data(&data[..0x2000]).is_err());
+        assert!(mbc.import_save_data(&data).is_ok());
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x6000, 0x01);
+        mbc.rom_control(0x4000, 0x03);
+        assert_eq!(mbc.ram_read(0xBFFF), 0x42);
+    }
 }
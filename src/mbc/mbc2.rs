@@ -0,0 +1,287 @@
+//! Can address up to 16 ROM banks of 16KB each (i.e. 256KB of ROM at most) and
+//! includes 512×4 bits of RAM (eventually battery-buffered).
+//!
+//! See: https://gbdev.io/pandocs/MBC2.html
+
+use crate::error::{Error, Result};
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::{CartridgeHeader, MBC, import_ram};
+
+pub const ROM_SIZE: usize = 0x40000; // 256 KB: 16 banks × 16 KiB
+const ROM_BANK_SIZE: usize = 0x4000;
+/// The built-in RAM holds 512 half-bytes, echoed across 0xA000...0xBFFF.
+const RAM_SIZE: usize = 0x200;
+
+pub struct MBC2 {
+    rom: Vec<u8>,
+    /// Only the lower 4 bits of each byte are used.
+    ram: [u8; RAM_SIZE],
+    /// The current ROM bank to use when reading in the 0x4000...0x7FFF range
+    /// (0x01...0x0F).
+    rom_bank: usize,
+    ram_enabled: bool,
+    /// Is the RAM battery-buffered ?
+    battery: bool,
+}
+
+impl MBC2 {
+    pub fn from_data(data: Vec<u8>) -> Result<MBC2> {
+        if data.len() > ROM_SIZE {
+            return Err(Error::InvalidHeader(format!(
+                "ROM size of {} bytes too big for MBC2",
+                data.len()
+            )));
+        }
+
+        let battery = data[CartridgeHeader::MPC_TYPE.address()] == 0x06;
+        Ok(MBC2 {
+            rom: data,
+            ram: [0x00; RAM_SIZE],
+            rom_bank: 0x01,
+            ram_enabled: false,
+            battery,
+        })
+    }
+}
+
+impl MBC for MBC2 {
+    fn rom_read(&self, address: u16) -> u8 {
+        self.rom[self.rom_bank(address) * ROM_BANK_SIZE + ((address as usize) & 0x3FFF)]
+    }
+    fn rom_bank(&self, address: u16) -> usize {
+        // ROM bank 00
+        if address < 0x4000 {
+            0x00
+        }
+        // ROM bank 01-0F, wrapping around the actual ROM size
+        else {
+            self.rom_bank % (self.rom.len() / ROM_BANK_SIZE).max(1)
+        }
+    }
+
+    fn ram_read(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // the upper 4 bits are undefined and read as 1s
+        0xF0 | self.ram[(address as usize) & (RAM_SIZE - 1)]
+    }
+
+    fn rom_control(&mut self, address: u16, value: u8) {
+        match address {
+            // bit 8 of the address selects the register
+            0x0000..=0x3FFF => {
+                if address & 0x0100 == 0x0000 {
+                    // external RAM switch
+                    self.ram_enabled = value & 0x0F == 0x0A;
+                } else {
+                    // ROM bank number
+                    self.rom_bank = match (value as usize) & 0x0F {
+                        0x0 => 0x1,
+                        n => n,
+                    };
+                }
+            }
+            // unused
+            0x4000..=0x7FFF => {}
+            _ => panic!("MBC2 : cannot write to ROM at {address:0>4X}"),
+        }
+    }
+
+    fn ram_write(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        self.ram[(address as usize) & (RAM_SIZE - 1)] = value & 0x0F;
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    /// One byte per 4-bit RAM cell.
+    fn export_save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()> {
+        import_ram(&mut self.ram, data)?;
+        for cell in self.ram.iter_mut() {
+            *cell &= 0x0F;
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for MBC2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.rom_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.rom_bank = reader.read_usize(0x10)?.max(0x01);
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_bytes(&mut self.ram)?;
+        for cell in self.ram.iter_mut() {
+            *cell &= 0x0F;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::mbc::MBC;
+
+    use super::{MBC2, ROM_SIZE};
+
+    // MBC2 cartridge type header values (0x0147).
+    const MBC2_ROM_ONLY: u8 = 0x05;
+    const MBC2_BATTERY: u8 = 0x06;
+
+    fn make_rom(mbc_type: u8) -> Vec<u8> {
+        let mut data = vec![0x00; ROM_SIZE];
+        data[0x0147] = mbc_type;
+        data
+    }
+
+    fn make_mbc2_ram_enabled() -> MBC2 {
+        let mut mbc = MBC2::from_data(make_rom(MBC2_BATTERY)).unwrap();
+        mbc.rom_control(0x0000, 0x0A);
+        mbc
+    }
+
+    // from_data
+
+    #[test]
+    fn test_mbc2_init_success() {
+        assert!(MBC2::from_data(make_rom(MBC2_ROM_ONLY)).is_ok());
+        assert!(MBC2::from_data(make_rom(MBC2_BATTERY)).is_ok());
+    }
+
+    #[test]
+    fn test_mbc2_init_error_rom_too_large() {
+        assert!(MBC2::from_data(vec![0x00u8; ROM_SIZE + 1]).is_err());
+    }
+
+    // ROM bank switching (0x0000..=0x3FFF, address bit 8 set)
+
+    #[test]
+    fn test_mbc2_rom_bank_select() {
+        let mut data = make_rom(MBC2_ROM_ONLY);
+        data[0x4000] = 0x11; // bank 1
+        data[0x3C000] = 0xFF; // bank 15
+        let mut mbc = MBC2::from_data(data).unwrap();
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+        mbc.rom_control(0x2100, 0x0F);
+        assert_eq!(mbc.rom_read(0x4000), 0xFF);
+    }
+
+    #[test]
+    fn test_mbc2_rom_bank_zero_remaps_to_one() {
+        let mut data = make_rom(MBC2_ROM_ONLY);
+        data[0x4000] = 0x11;
+        let mut mbc = MBC2::from_data(data).unwrap();
+        mbc.rom_control(0x0100, 0x00);
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+    }
+
+    #[test]
+    fn test_mbc2_rom_bank_uses_lower_4_bits_of_value() {
+        let mut data = make_rom(MBC2_ROM_ONLY);
+        data[0x8000] = 0x22; // bank 2
+        let mut mbc = MBC2::from_data(data).unwrap();
+        mbc.rom_control(0x0100, 0xF2);
+        assert_eq!(mbc.rom_read(0x4000), 0x22);
+    }
+
+    #[test]
+    fn test_mbc2_rom_bank_register_needs_address_bit_8() {
+        // with bit 8 cleared, the write goes to the RAM enable register
+        let mut data = make_rom(MBC2_ROM_ONLY);
+        data[0x4000] = 0x11;
+        data[0x8000] = 0x22;
+        let mut mbc = MBC2::from_data(data).unwrap();
+        mbc.rom_control(0x2000, 0x02);
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+    }
+
+    // RAM enable / disable (0x0000..=0x3FFF, address bit 8 cleared)
+
+    #[test]
+    fn test_mbc2_ram_disabled_by_default() {
+        let mut mbc = MBC2::from_data(make_rom(MBC2_BATTERY)).unwrap();
+        mbc.ram_write(0xA000, 0x0A); // disabled — must be a no-op
+        assert_eq!(mbc.ram_read(0xA000), 0xFF);
+        mbc.rom_control(0x0000, 0x0A);
+        assert_eq!(mbc.ram_read(0xA000), 0xF0);
+    }
+
+    #[test]
+    fn test_mbc2_ram_enable_register_needs_address_bit_8_cleared() {
+        let mut mbc = MBC2::from_data(make_rom(MBC2_BATTERY)).unwrap();
+        mbc.rom_control(0x0100, 0x0A);
+        mbc.ram_write(0xA000, 0x05);
+        assert_eq!(mbc.ram_read(0xA000), 0xFF);
+        mbc.rom_control(0x3E00, 0x0A);
+        mbc.ram_write(0xA000, 0x05);
+        assert_eq!(mbc.ram_read(0xA000), 0xF5);
+    }
+
+    #[test]
+    fn test_mbc2_ram_disable_after_enable() {
+        let mut mbc = make_mbc2_ram_enabled();
+        mbc.ram_write(0xA000, 0x05);
+        mbc.rom_control(0x0000, 0x00);
+        assert_eq!(mbc.ram_read(0xA000), 0xFF);
+    }
+
+    // 512×4 bits RAM
+
+    #[test]
+    fn test_mbc2_ram_upper_nibble_reads_as_ones() {
+        let mut mbc = make_mbc2_ram_enabled();
+        mbc.ram_write(0xA000, 0x00);
+        assert_eq!(mbc.ram_read(0xA000), 0xF0);
+        mbc.ram_write(0xA1FF, 0xAB);
+        assert_eq!(mbc.ram_read(0xA1FF), 0xFB);
+    }
+
+    #[test]
+    fn test_mbc2_ram_echoed_across_range() {
+        let mut mbc = make_mbc2_ram_enabled();
+        mbc.ram_write(0xA042, 0x07);
+        for address in [0xA242, 0xA442, 0xB042, 0xBE42] {
+            assert_eq!(mbc.ram_read(address), 0xF7, "echo at {address:#06x}");
+        }
+        mbc.ram_write(0xBFFF, 0x03);
+        assert_eq!(mbc.ram_read(0xA1FF), 0xF3);
+    }
+
+    // battery-buffered RAM
+
+    #[test]
+    fn test_mbc2_save_data() {
+        assert!(
+            !MBC2::from_data(make_rom(MBC2_ROM_ONLY))
+                .unwrap()
+                .has_battery()
+        );
+        let mut mbc = make_mbc2_ram_enabled();
+        assert!(mbc.has_battery());
+        mbc.ram_write(0xA1FF, 0x0C);
+        let mut data = mbc.export_save_data();
+        assert_eq!(data.len(), 0x200);
+        assert_eq!(data[0x1FF], 0x0C);
+
+        data[0x000] = 0xA5;
+        let mut mbc = make_mbc2_ram_enabled();
+        assert!(mbc.import_save_data(&data[1..]).is_err());
+        assert!(mbc.import_save_data(&data).is_ok());
+        assert_eq!(mbc.ram_read(0xA1FF), 0xFC);
+        assert_eq!(mbc.ram_read(0xA000), 0xF5);
+    }
+}
@@ -0,0 +1,706 @@
+//! Can address up to 128 ROM banks of 16KB each (i.e. 2MB of ROM at most),
+//! 4 RAM banks of 8KB each and, on some cartridges, a battery-buffered
+//! Real Time Clock (RTC).
+//!
+//! See: https://gbdev.io/pandocs/MBC3.html
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, Result};
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::{CartridgeHeader, MBC, import_ram};
+
+pub const ROM_SIZE: usize = 0x200000; // 2 MB: 128 banks × 16 KiB
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+/// The size of the RTC state appended to the save data, in the format used by
+/// most emulators : the running then the latched registers (0x08-0x0C) as
+/// little-endian 32-bit values, followed by the 64-bit UNIX timestamp of the
+/// save.
+const RTC_SAVE_SIZE: usize = 48;
+/// Older variant of the RTC save format, with a 32-bit timestamp.
+const RTC_SAVE_SIZE_32: usize = 44;
+
+/// The source of the wall-clock time counted by the RTC.
+///
+/// Can be replaced to control the flow of time, typically in tests.
+pub trait TimeSource {
+    /// Return the current time, in seconds since an arbitrary epoch.
+    fn now(&self) -> u64;
+}
+
+/// The host system clock.
+pub struct SystemTimeSource;
+
+impl TimeSource for SystemTimeSource {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// The RTC registers, selected by writing 0x08-0x0C to 0x4000-0x5FFF.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+struct RtcRegisters {
+    /// 0x08 : seconds (0-59).
+    seconds: u8,
+    /// 0x09 : minutes (0-59).
+    minutes: u8,
+    /// 0x0A : hours (0-23).
+    hours: u8,
+    /// 0x0B and bit 0 of 0x0C : day counter (0-511).
+    days: u16,
+    /// Bit 6 of 0x0C : is the clock stopped ?
+    halt: bool,
+    /// Bit 7 of 0x0C : did the day counter overflow ? Stays set until
+    /// explicitly cleared.
+    carry: bool,
+}
+
+impl RtcRegisters {
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => self.days as u8,
+            0x0C => ((self.carry as u8) << 7) | ((self.halt as u8) << 6) | ((self.days >> 8) as u8),
+            _ => unreachable!("MBC3 : invalid RTC register {:0>2X}", register),
+        }
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        match register {
+            0x08 => self.seconds = value & 0x3F,
+            0x09 => self.minutes = value & 0x3F,
+            0x0A => self.hours = value & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | value as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((value & 0x01) as u16) << 8);
+                self.halt = value & 0x40 == 0x40;
+                self.carry = value & 0x80 == 0x80;
+            }
+            _ => unreachable!("MBC3 : invalid RTC register {:0>2X}", register),
+        }
+    }
+
+    fn export(&self, data: &mut Vec<u8>) {
+        for register in 0x08..=0x0C {
+            data.extend_from_slice(&(self.read(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn import(data: &[u8]) -> RtcRegisters {
+        let mut registers = RtcRegisters::default();
+        for (i, register) in (0x08..=0x0C).enumerate() {
+            registers.write(register, data[i * 4]);
+        }
+        registers
+    }
+
+    /// Advance the clock by the given number of seconds.
+    fn tick(&mut self, seconds: u64) {
+        let total = self.seconds as u64 + seconds;
+        self.seconds = (total % 60) as u8;
+        let total = self.minutes as u64 + total / 60;
+        self.minutes = (total % 60) as u8;
+        let total = self.hours as u64 + total / 60;
+        self.hours = (total % 24) as u8;
+        let days = self.days as u64 + total / 24;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+}
+
+/// The MBC3 Real Time Clock, counting the wall-clock time given by its
+/// 'TimeSource'.
+struct Rtc {
+    time_source: Box<dyn TimeSource + Send>,
+    /// The time (as given by the time source) the clock was last updated at.
+    last_update: u64,
+    /// The running clock.
+    registers: RtcRegisters,
+    /// The copy of the clock readable by the CPU, updated by the latch sequence.
+    latched: RtcRegisters,
+    /// The last value written to 0x6000-0x7FFF : writing 0x00 then 0x01
+    /// latches the clock.
+    latch_value: u8,
+}
+
+impl Rtc {
+    fn new(time_source: Box<dyn TimeSource + Send>) -> Rtc {
+        let last_update = time_source.now();
+        Rtc {
+            time_source,
+            last_update,
+            registers: RtcRegisters::default(),
+            latched: RtcRegisters::default(),
+            latch_value: 0xFF,
+        }
+    }
+
+    /// Bring the running clock up to date with the time source.
+    fn update(&mut self) {
+        let now = self.time_source.now();
+        if !self.registers.halt {
+            self.registers.tick(now.saturating_sub(self.last_update));
+        }
+        self.last_update = now;
+    }
+
+    fn latch(&mut self, value: u8) {
+        if self.latch_value == 0x00 && value == 0x01 {
+            self.update();
+            self.latched = self.registers;
+        }
+        self.latch_value = value;
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        self.registers.write(register, value);
+    }
+
+    fn export(&self, data: &mut Vec<u8>) {
+        let now = self.time_source.now();
+        let mut registers = self.registers;
+        if !registers.halt {
+            registers.tick(now.saturating_sub(self.last_update));
+        }
+        registers.export(data);
+        self.latched.export(data);
+        data.extend_from_slice(&now.to_le_bytes());
+    }
+
+    /// Restore the clock from its saved state. The time elapsed since the
+    /// save will be counted at the next update.
+    fn import(&mut self, data: &[u8]) {
+        self.registers = RtcRegisters::import(&data[0..20]);
+        self.latched = RtcRegisters::import(&data[20..40]);
+        let mut timestamp = [0x00; 8];
+        timestamp[..data.len() - 40].copy_from_slice(&data[40..]);
+        self.last_update = u64::from_le_bytes(timestamp);
+    }
+}
+
+/// The clock is saved as of the time of the save, and keeps counting from
+/// the time of the load.
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let mut registers = self.registers;
+        if !registers.halt {
+            registers.tick(self.time_source.now().saturating_sub(self.last_update));
+        }
+        for register in 0x08..=0x0C {
+            writer.write_u8(registers.read(register));
+        }
+        for register in 0x08..=0x0C {
+            writer.write_u8(self.latched.read(register));
+        }
+        writer.write_u8(self.latch_value);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for register in 0x08..=0x0C {
+            self.registers.write(register, reader.read_u8()?);
+        }
+        for register in 0x08..=0x0C {
+            self.latched.write(register, reader.read_u8()?);
+        }
+        self.latch_value = reader.read_u8()?;
+        self.last_update = self.time_source.now();
+        Ok(())
+    }
+}
+
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    /// The current ROM bank to use when reading in the 0x4000...0x7FFF range
+    /// (0x01...0x7F).
+    rom_bank: usize,
+    /// The value written to 0x4000-0x5FFF : a RAM bank (0x00-0x03) or an RTC
+    /// register (0x08-0x0C) to map in the 0xA000...0xBFFF range.
+    ram_bank: u8,
+    /// Enables both the external RAM and the RTC registers.
+    ram_enabled: bool,
+    /// The Real Time Clock, if present on the cartridge.
+    rtc: Option<Rtc>,
+    /// Are the external RAM and RTC battery-buffered ?
+    battery: bool,
+}
+
+impl MBC3 {
+    /// Create an MBC3 using the given time source for its RTC.
+    pub fn with_time_source(
+        data: Vec<u8>,
+        time_source: Box<dyn TimeSource + Send>,
+    ) -> Result<MBC3> {
+        if data.len() > ROM_SIZE {
+            return Err(Error::InvalidHeader(format!(
+                "ROM size of {} bytes too big for MBC3",
+                data.len()
+            )));
+        }
+
+        let mbc_type = data[CartridgeHeader::MPC_TYPE.address()];
+        let (ram_size, has_rtc) = match mbc_type {
+            // TIMER+BATTERY
+            0x0F => (0, true),
+            // TIMER+RAM+BATTERY
+            0x10 => (CartridgeHeader::ram_size(&data), true),
+            // RAM, RAM+BATTERY
+            0x12 | 0x13 => (CartridgeHeader::ram_size(&data), false),
+            _ => (0, false),
+        };
+
+        Ok(MBC3 {
+            rom: data,
+            ram: vec![0x00; ram_size],
+            rom_bank: 0x01,
+            ram_bank: 0x00,
+            ram_enabled: false,
+            rtc: if has_rtc {
+                Some(Rtc::new(time_source))
+            } else {
+                None
+            },
+            battery: matches!(mbc_type, 0x0F | 0x10 | 0x13),
+        })
+    }
+
+    /// Get the index in 'ram' of the given address in the current RAM bank,
+    /// if mapped.
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let index = self.ram_bank as usize * RAM_BANK_SIZE + (address as usize & 0x1FFF);
+        if index < self.ram.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+impl MBC for MBC3 {
+    fn rom_read(&self, address: u16) -> u8 {
+        self.rom[self.rom_bank(address) * ROM_BANK_SIZE + ((address as usize) & 0x3FFF)]
+    }
+    fn rom_bank(&self, address: u16) -> usize {
+        // ROM bank 00
+        if address < 0x4000 {
+            0x00
+        }
+        // ROM bank 01-7F, wrapping around the actual ROM size
+        else {
+            self.rom_bank % (self.rom.len() / ROM_BANK_SIZE).max(1)
+        }
+    }
+
+    fn ram_read(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_bank {
+            0x00..=0x03 => self
+                .ram_index(address)
+                .map_or(0xFF, |index| self.ram[index]),
+            0x08..=0x0C => match self.rtc {
+                Some(ref rtc) => rtc.latched.read(self.ram_bank),
+                None => 0xFF,
+            },
+            _ => 0xFF,
+        }
+    }
+
+    fn rom_control(&mut self, address: u16, value: u8) {
+        match address {
+            // external RAM and RTC registers switch
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            // ROM bank number (7 bits)
+            0x2000..=0x3FFF => {
+                self.rom_bank = match (value as usize) & 0x7F {
+                    0x0 => 0x1,
+                    n => n,
+                };
+            }
+            // RAM bank number or RTC register select
+            0x4000..=0x5FFF => {
+                self.ram_bank = value;
+            }
+            // latch clock data
+            0x6000..=0x7FFF => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.latch(value);
+                }
+            }
+            _ => panic!("MBC3 : cannot write to ROM at {address:0>4X}"),
+        }
+    }
+
+    fn ram_write(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_bank {
+            0x00..=0x03 => {
+                if let Some(index) = self.ram_index(address) {
+                    self.ram[index] = value;
+                }
+            }
+            0x08..=0x0C => {
+                if let Some(ref mut rtc) = self.rtc {
+                    rtc.write(self.ram_bank, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn export_save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(ref rtc) = self.rtc {
+            rtc.export(&mut data);
+        }
+        data
+    }
+
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()> {
+        let (ram_data, rtc_data) = data.split_at(self.ram.len().min(data.len()));
+        import_ram(&mut self.ram, ram_data)?;
+        match (self.rtc.as_mut(), rtc_data.len()) {
+            // no RTC state saved : keep the clock running from now on
+            (_, 0) => Ok(()),
+            (Some(rtc), RTC_SAVE_SIZE | RTC_SAVE_SIZE_32) => {
+                rtc.import(rtc_data);
+                Ok(())
+            }
+            (_, size) => Err(Error::BadSaveData(format!(
+                "invalid RTC save data size of {} bytes",
+                size
+            ))),
+        }
+    }
+}
+
+impl SaveState for MBC3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_data(&self.ram);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.rom_bank = reader.read_usize(0x80)?.max(0x01);
+        self.ram_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_data(&mut self.ram)?;
+        match self.rtc {
+            Some(ref mut rtc) => rtc.load_state(reader),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use crate::error::Result;
+    use crate::mbc::{CartridgeHeader, MBC};
+    use crate::state::{SaveState, StateReader, StateWriter};
+
+    use super::{MBC3, ROM_SIZE, RTC_SAVE_SIZE, SystemTimeSource, TimeSource};
+
+    // MBC3 cartridge type header values (0x0147).
+    const MBC3_TIMER_RAM_BATTERY: u8 = 0x10;
+    const MBC3_ROM_ONLY: u8 = 0x11;
+    const MBC3_RAM_BATTERY: u8 = 0x13;
+
+    /// Time source controlled by the tests.
+    struct TestTimeSource(Arc<AtomicU64>);
+
+    impl TimeSource for TestTimeSource {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Create an MBC3 using the host system clock for its RTC.
+    fn from_data(data: Vec<u8>) -> Result<MBC3> {
+        MBC3::with_time_source(data, Box::new(SystemTimeSource))
+    }
+
+    fn make_rom(mbc_type: u8, ram_size_byte: u8) -> Vec<u8> {
+        let mut data = vec![0x00; ROM_SIZE];
+        data[0x0147] = mbc_type;
+        data[CartridgeHeader::RAM_Size.address()] = ram_size_byte;
+        data
+    }
+
+    /// Return an MBC3 with a timer, 32KB of RAM and the handle to its clock.
+    fn make_mbc3_with_rtc() -> (MBC3, Arc<AtomicU64>) {
+        let time = Arc::new(AtomicU64::new(1_000_000));
+        let time_source = Box::new(TestTimeSource(time.clone()));
+        let mut mbc =
+            MBC3::with_time_source(make_rom(MBC3_TIMER_RAM_BATTERY, 0x03), time_source).unwrap();
+        mbc.rom_control(0x0000, 0x0A);
+        (mbc, time)
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.rom_control(0x6000, 0x00);
+        mbc.rom_control(0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.rom_control(0x4000, register);
+        mbc.ram_read(0xA000)
+    }
+
+    fn write_rtc(mbc: &mut MBC3, register: u8, value: u8) {
+        mbc.rom_control(0x4000, register);
+        mbc.ram_write(0xA000, value);
+    }
+
+    // from_data
+
+    #[test]
+    fn test_mbc3_init() {
+        assert!(from_data(make_rom(MBC3_ROM_ONLY, 0x00)).is_ok());
+        assert!(from_data(make_rom(MBC3_RAM_BATTERY, 0x03)).is_ok());
+        assert!(from_data(vec![0x00; ROM_SIZE + 1]).is_err());
+    }
+
+    // ROM bank switching
+
+    #[test]
+    fn test_mbc3_rom_bank_select() {
+        let mut data = make_rom(MBC3_ROM_ONLY, 0x00);
+        data[0x4000] = 0x11; // bank 1
+        data[0x7F * 0x4000] = 0x7F; // bank 127
+        let mut mbc = from_data(data).unwrap();
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+        mbc.rom_control(0x2000, 0x7F);
+        assert_eq!(mbc.rom_read(0x4000), 0x7F);
+        // bank 0 selects bank 1
+        mbc.rom_control(0x2000, 0x00);
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+    }
+
+    #[test]
+    fn test_mbc3_rom_bank_wraps_around_rom_size() {
+        let mut data = vec![0x00; 4 * 0x4000];
+        data[0x0147] = MBC3_ROM_ONLY;
+        data[0x4000] = 0x11;
+        let mut mbc = from_data(data).unwrap();
+        mbc.rom_control(0x2000, 0x05);
+        assert_eq!(mbc.rom_read(0x4000), 0x11);
+    }
+
+    // RAM banks
+
+    #[test]
+    fn test_mbc3_ram_banks() {
+        let mut mbc = from_data(make_rom(MBC3_RAM_BATTERY, 0x03)).unwrap();
+        mbc.ram_write(0xA000, 0x42); // disabled — must be a no-op
+        assert_eq!(mbc.ram_read(0xA000), 0xFF);
+        mbc.rom_control(0x0000, 0x0A);
+        for bank in 0..4 {
+            mbc.rom_control(0x4000, bank);
+            mbc.ram_write(0xBFFF, 0x10 + bank);
+        }
+        for bank in 0..4 {
+            mbc.rom_control(0x4000, bank);
+            assert_eq!(mbc.ram_read(0xBFFF), 0x10 + bank);
+        }
+    }
+
+    #[test]
+    fn test_mbc3_no_rtc_without_timer() {
+        let mut mbc = from_data(make_rom(MBC3_RAM_BATTERY, 0x03)).unwrap();
+        mbc.rom_control(0x0000, 0x0A);
+        write_rtc(&mut mbc, 0x08, 0x12);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0xFF);
+    }
+
+    // RTC
+
+    #[test]
+    fn test_mbc3_rtc_counts_time_when_latched() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        // 1 day, 2 hours, 3 minutes and 4 seconds
+        time.fetch_add(86_400 + 2 * 3_600 + 3 * 60 + 4, Ordering::SeqCst);
+        // the latched values do not change until the next latch
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 4);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 3);
+        assert_eq!(read_rtc(&mut mbc, 0x0A), 2);
+        assert_eq!(read_rtc(&mut mbc, 0x0B), 1);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x00);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_latch_sequence() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        time.fetch_add(10, Ordering::SeqCst);
+        // writing 0x01 without a preceding 0x00 does not latch
+        mbc.rom_control(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        mbc.rom_control(0x6000, 0x00);
+        mbc.rom_control(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 10);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_halt() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        write_rtc(&mut mbc, 0x0C, 0x40);
+        time.fetch_add(100, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x40);
+        // the time spent halted is not counted once resumed
+        write_rtc(&mut mbc, 0x0C, 0x00);
+        time.fetch_add(5, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 5);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_write_registers() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        write_rtc(&mut mbc, 0x08, 59);
+        write_rtc(&mut mbc, 0x09, 59);
+        write_rtc(&mut mbc, 0x0A, 23);
+        write_rtc(&mut mbc, 0x0B, 0xFF);
+        write_rtc(&mut mbc, 0x0C, 0x00);
+        time.fetch_add(1, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0A), 0);
+        assert_eq!(read_rtc(&mut mbc, 0x0B), 0x00);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x01);
+    }
+
+    #[test]
+    fn test_mbc3_rtc_day_counter_carry() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        time.fetch_add(512 * 86_400 + 1, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 1);
+        assert_eq!(read_rtc(&mut mbc, 0x0B), 0x00);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x80);
+        // the carry stays set until cleared
+        time.fetch_add(86_400, Ordering::SeqCst);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x80);
+        write_rtc(&mut mbc, 0x0C, 0x00);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x0C), 0x00);
+    }
+
+    // battery-buffered RAM and RTC
+
+    #[test]
+    fn test_mbc3_save_data_without_rtc() {
+        let mut mbc = from_data(make_rom(MBC3_RAM_BATTERY, 0x03)).unwrap();
+        assert!(mbc.has_battery());
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x4000, 0x02);
+        mbc.ram_write(0xA000, 0x42);
+        let data = mbc.export_save_data();
+        assert_eq!(data.len(), 0x8000);
+
+        let mut mbc = from_data(make_rom(MBC3_RAM_BATTERY, 0x03)).unwrap();
+        assert!(
+            mbc.import_save_data(&[0x00; 0x8000 + RTC_SAVE_SIZE])
+                .is_err()
+        );
+        assert!(mbc.import_save_data(&data).is_ok());
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x4000, 0x02);
+        assert_eq!(mbc.ram_read(0xA000), 0x42);
+    }
+
+    #[test]
+    fn test_mbc3_save_data_with_rtc() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        mbc.rom_control(0x4000, 0x00);
+        mbc.ram_write(0xA000, 0x42);
+        write_rtc(&mut mbc, 0x0A, 5);
+        latch(&mut mbc);
+        time.fetch_add(30, Ordering::SeqCst);
+        let data = mbc.export_save_data();
+        assert_eq!(data.len(), 0x8000 + RTC_SAVE_SIZE);
+
+        // the time elapsed since the save is counted once loaded
+        time.fetch_add(60, Ordering::SeqCst);
+        let time_source = Box::new(TestTimeSource(time.clone()));
+        let mut mbc =
+            MBC3::with_time_source(make_rom(MBC3_TIMER_RAM_BATTERY, 0x03), time_source).unwrap();
+        assert!(mbc.import_save_data(&data).is_ok());
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x4000, 0x00);
+        assert_eq!(mbc.ram_read(0xA000), 0x42);
+        // the latched registers are restored as is
+        assert_eq!(read_rtc(&mut mbc, 0x0A), 5);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 30);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 1);
+        assert_eq!(read_rtc(&mut mbc, 0x0A), 5);
+    }
+
+    #[test]
+    fn test_mbc3_save_state() {
+        let (mut mbc, time) = make_mbc3_with_rtc();
+        mbc.rom_control(0x2000, 0x05);
+        mbc.rom_control(0x4000, 0x02);
+        mbc.ram_write(0xA123, 0x42);
+        write_rtc(&mut mbc, 0x09, 10);
+        latch(&mut mbc);
+        time.fetch_add(30, Ordering::SeqCst);
+        mbc.rom_control(0x4000, 0x02);
+        let mut writer = StateWriter::new();
+        mbc.save_state(&mut writer);
+        let data = writer.into_data();
+
+        // unlike the save data, the time elapsed since the save is not counted
+        time.fetch_add(60, Ordering::SeqCst);
+        let time_source = Box::new(TestTimeSource(time.clone()));
+        let mut data_rom = make_rom(MBC3_TIMER_RAM_BATTERY, 0x03);
+        data_rom[0x5 * 0x4000] = 0x55;
+        let mut mbc = MBC3::with_time_source(data_rom, time_source).unwrap();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert!(mbc.load_state(&mut reader).is_ok());
+        assert!(reader.finish().is_ok());
+        assert_eq!(mbc.rom_read(0x4000), 0x55);
+        assert_eq!(mbc.ram_read(0xA123), 0x42);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 10);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+        latch(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc, 0x08), 30);
+        assert_eq!(read_rtc(&mut mbc, 0x09), 10);
+    }
+}
@@ -0,0 +1,292 @@
+//! Can address up to 512 ROM banks of 16KB each (i.e. 8MB of ROM at most) and
+//! 16 RAM banks of 8KB each. Some cartridges also feature a rumble motor,
+//! controlled through the RAM bank register.
+//!
+//! See: https://gbdev.io/pandocs/MBC5.html
+
+use crate::error::{Error, Result};
+use crate::state::{SaveState, StateReader, StateWriter};
+
+use super::{CartridgeHeader, MBC, RumbleCallback, import_ram};
+
+pub const ROM_SIZE: usize = 0x800000; // 8 MB: 512 banks × 16 KiB
+const ROM_BANK_SIZE: usize = 0x4000;
+const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    /// The current ROM bank to use when reading in the 0x4000...0x7FFF range
+    /// (0x000...0x1FF). Unlike the other MBCs, bank 0 can be selected.
+    rom_bank: usize,
+    /// The current RAM bank to use when reading in the 0xA000...0xBFFF range
+    /// (0x0...0xF, or 0x0...0x7 on rumble cartridges).
+    ram_bank: usize,
+    ram_enabled: bool,
+    /// Is the external RAM battery-buffered ?
+    battery: bool,
+    /// Does the cartridge have a rumble motor ?
+    has_rumble: bool,
+    /// Is the rumble motor currently on ?
+    rumble: bool,
+    rumble_callback: Option<RumbleCallback>,
+}
+
+impl MBC5 {
+    pub fn from_data(data: Vec<u8>) -> Result<MBC5> {
+        if data.len() > ROM_SIZE {
+            return Err(Error::InvalidHeader(format!(
+                "ROM size of {} bytes too big for MBC5",
+                data.len()
+            )));
+        }
+
+        let mbc_type = data[CartridgeHeader::MPC_TYPE.address()];
+        let ram_size = match mbc_type {
+            // RAM, RAM+BATTERY, RUMBLE+RAM, RUMBLE+RAM+BATTERY
+            0x1A | 0x1B | 0x1D | 0x1E => CartridgeHeader::ram_size(&data),
+            _ => 0,
+        };
+
+        Ok(MBC5 {
+            rom: data,
+            ram: vec![0x00; ram_size],
+            rom_bank: 0x001,
+            ram_bank: 0x0,
+            ram_enabled: false,
+            battery: matches!(mbc_type, 0x1B | 0x1E),
+            has_rumble: matches!(mbc_type, 0x1C..=0x1E),
+            rumble: false,
+            rumble_callback: None,
+        })
+    }
+
+    /// Get the index in 'ram' of the given address in the current RAM bank,
+    /// if mapped.
+    fn ram_index(&self, address: u16) -> Option<usize> {
+        let index = self.ram_bank * RAM_BANK_SIZE + (address as usize & 0x1FFF);
+        if index < self.ram.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn set_rumble(&mut self, rumble: bool) {
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            if let Some(ref mut callback) = self.rumble_callback {
+                callback(rumble);
+            }
+        }
+    }
+}
+
+impl MBC for MBC5 {
+    fn rom_read(&self, address: u16) -> u8 {
+        self.rom[self.rom_bank(address) * ROM_BANK_SIZE + ((address as usize) & 0x3FFF)]
+    }
+    fn rom_bank(&self, address: u16) -> usize {
+        // ROM bank 00
+        if address < 0x4000 {
+            0x00
+        }
+        // ROM bank 000-1FF, wrapping around the actual ROM size
+        else {
+            self.rom_bank % (self.rom.len() / ROM_BANK_SIZE).max(1)
+        }
+    }
+
+    fn ram_read(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram_index(address)
+            .map_or(0xFF, |index| self.ram[index])
+    }
+
+    fn rom_control(&mut self, address: u16, value: u8) {
+        match address {
+            // external RAM switch
+            0x0000..=0x1FFF => {
+                self.ram_enabled = value & 0x0F == 0x0A;
+            }
+            // lower 8 bits of the ROM bank number
+            0x2000..=0x2FFF => {
+                self.rom_bank = (self.rom_bank & 0x100) | value as usize;
+            }
+            // 9th bit of the ROM bank number
+            0x3000..=0x3FFF => {
+                self.rom_bank = (self.rom_bank & 0x0FF) | (((value & 0x01) as usize) << 8);
+            }
+            // RAM bank number, bit 3 driving the rumble motor if any
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.ram_bank = (value & 0x07) as usize;
+                    self.set_rumble(value & 0x08 == 0x08);
+                } else {
+                    self.ram_bank = (value & 0x0F) as usize;
+                }
+            }
+            // unused
+            0x6000..=0x7FFF => {}
+            _ => panic!("MBC5 : cannot write to ROM at {address:0>4X}"),
+        }
+    }
+
+    fn ram_write(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        if let Some(index) = self.ram_index(address) {
+            self.ram[index] = value;
+        }
+    }
+
+    fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    fn export_save_data(&self) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn import_save_data(&mut self, data: &[u8]) -> Result<()> {
+        import_ram(&mut self.ram, data)
+    }
+
+    fn set_rumble_callback(&mut self, callback: RumbleCallback) {
+        self.rumble_callback = Some(callback);
+    }
+}
+
+impl SaveState for MBC5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_usize(self.rom_bank);
+        writer.write_usize(self.ram_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.rumble);
+        writer.write_data(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.rom_bank = reader.read_usize(0x200)?;
+        self.ram_bank = reader.read_usize(0x10)?;
+        self.ram_enabled = reader.read_bool()?;
+        let rumble = reader.read_bool()?;
+        self.set_rumble(rumble && self.has_rumble);
+        reader.read_data(&mut self.ram)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::mbc::{CartridgeHeader, MBC};
+
+    use super::{MBC5, ROM_SIZE};
+
+    // MBC5 cartridge type header values (0x0147).
+    const MBC5_ROM_ONLY: u8 = 0x19;
+    const MBC5_RAM_BATTERY: u8 = 0x1B;
+    const MBC5_RUMBLE_RAM: u8 = 0x1D;
+
+    fn make_rom(mbc_type: u8, ram_size_byte: u8) -> Vec<u8> {
+        let mut data = vec![0x00; ROM_SIZE];
+        data[0x0147] = mbc_type;
+        data[CartridgeHeader::RAM_Size.address()] = ram_size_byte;
+        data
+    }
+
+    // from_data
+
+    #[test]
+    fn test_mbc5_init() {
+        assert!(MBC5::from_data(make_rom(MBC5_ROM_ONLY, 0x00)).is_ok());
+        assert!(MBC5::from_data(make_rom(MBC5_RAM_BATTERY, 0x04)).is_ok());
+        assert!(MBC5::from_data(vec![0x00; ROM_SIZE + 1]).is_err());
+    }
+
+    // ROM bank switching
+
+    #[test]
+    fn test_mbc5_rom_bank_9_bits() {
+        let mut data = make_rom(MBC5_ROM_ONLY, 0x00);
+        data[0x4000] = 0x01; // bank 1
+        data[0xFF * 0x4000] = 0xFF; // bank 255
+        data[0x100 * 0x4000] = 0x10; // bank 256
+        data[0x1FF * 0x4000 + 0x3FFF] = 0x1F; // bank 511
+        let mut mbc = MBC5::from_data(data).unwrap();
+        assert_eq!(mbc.rom_read(0x4000), 0x01);
+        mbc.rom_control(0x2000, 0xFF);
+        assert_eq!(mbc.rom_read(0x4000), 0xFF);
+        mbc.rom_control(0x3000, 0x01);
+        assert_eq!(mbc.rom_read(0x7FFF), 0x1F);
+        mbc.rom_control(0x2000, 0x00);
+        assert_eq!(mbc.rom_read(0x4000), 0x10);
+    }
+
+    #[test]
+    fn test_mbc5_rom_bank_0_selectable() {
+        let mut data = make_rom(MBC5_ROM_ONLY, 0x00);
+        data[0x0000] = 0xAB;
+        data[0x4000] = 0x01;
+        let mut mbc = MBC5::from_data(data).unwrap();
+        mbc.rom_control(0x2000, 0x00);
+        assert_eq!(mbc.rom_read(0x4000), 0xAB);
+    }
+
+    // RAM banks
+
+    #[test]
+    fn test_mbc5_ram_banks() {
+        let mut mbc = MBC5::from_data(make_rom(MBC5_RAM_BATTERY, 0x04)).unwrap();
+        mbc.ram_write(0xA000, 0x42); // disabled — must be a no-op
+        assert_eq!(mbc.ram_read(0xA000), 0xFF);
+        mbc.rom_control(0x0000, 0x0A);
+        for bank in 0..16 {
+            mbc.rom_control(0x4000, bank);
+            mbc.ram_write(0xA123, 0x20 + bank);
+        }
+        for bank in 0..16 {
+            mbc.rom_control(0x4000, bank);
+            assert_eq!(mbc.ram_read(0xA123), 0x20 + bank);
+        }
+        mbc.rom_control(0x0000, 0x00);
+        assert_eq!(mbc.ram_read(0xA123), 0xFF);
+    }
+
+    // rumble
+
+    #[test]
+    fn test_mbc5_rumble_callback() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut mbc = MBC5::from_data(make_rom(MBC5_RUMBLE_RAM, 0x03)).unwrap();
+        let callback_events = events.clone();
+        mbc.set_rumble_callback(Box::new(move |rumble| {
+            callback_events.lock().unwrap().push(rumble)
+        }));
+        mbc.rom_control(0x0000, 0x0A);
+        mbc.rom_control(0x4000, 0x0B);
+        mbc.rom_control(0x4000, 0x0A); // no change
+        mbc.rom_control(0x4000, 0x03);
+        assert_eq!(*events.lock().unwrap(), vec![true, false]);
+        // bit 3 does not select the RAM bank on rumble cartridges
+        mbc.ram_write(0xA000, 0x33);
+        mbc.rom_control(0x4000, 0x0B);
+        assert_eq!(mbc.ram_read(0xA000), 0x33);
+    }
+
+    #[test]
+    fn test_mbc5_no_rumble_without_motor() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut mbc = MBC5::from_data(make_rom(MBC5_RAM_BATTERY, 0x04)).unwrap();
+        let callback_events = events.clone();
+        mbc.set_rumble_callback(Box::new(move |rumble| {
+            callback_events.lock().unwrap().push(rumble)
+        }));
+        mbc.rom_control(0x4000, 0x08);
+        assert!(events.lock().unwrap().is_empty());
+    }
+}
@@ -12,3 +12,20 @@ pub trait Memory {
         self.write_byte(address + 1, ((word & 0xFF00) >> 8) as u8);
     }
 }
+
+/// An access of the CPU to the memory, as logged for the debugger watchpoints.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAccess {
+    /// The byte at the given address was read.
+    Read(u16),
+    /// The given byte was written at the given address.
+    Write(u16, u8),
+}
+
+impl MemoryAccess {
+    pub fn address(&self) -> u16 {
+        match *self {
+            MemoryAccess::Read(address) | MemoryAccess::Write(address, _) => address,
+        }
+    }
+}
@@ -1,26 +1,91 @@
+use std::cmp;
+
+use crate::apu::{Apu, DEFAULT_SAMPLE_RATE};
 use crate::bios::GB_BIOS;
 use crate::cpu::CycleType;
-use crate::gpu::{Gpu, RGB};
+use crate::error::{Error, Result};
+use crate::gpu::{Gpu, RGB, V_BLANK_CYCLES};
 use crate::irq::{Interrupt, IrqHandler};
 use crate::joypad::{Joypad, JoypadKey};
-use crate::mbc::MBC;
-use crate::memory::Memory;
+use crate::mbc::{CartridgeHeader, MBC};
+use crate::memory::{Memory, MemoryAccess};
+use crate::model::HardwareModel;
 use crate::serial::{Serial, SerialCallback};
+use crate::state::{SaveState, StateReader, StateWriter};
 
+use self::dma::OamDma;
+use self::hdma::{HDMA_BLOCK_CYCLES, HDMA_BLOCK_SIZE, Hdma};
 use self::timers::Timers;
 
+mod dma;
+mod hdma;
 mod timers;
 
-const WRAM_SIZE: usize = 0x2000;
+/// 8 banks of 4K of working RAM (only banks 0 and 1 are used in Classic mode).
+const WRAM_SIZE: usize = 0x8000;
+const WRAM_BANK_SIZE: usize = 0x1000;
 const ZRAM_SIZE: usize = 0x0080;
 
+/// The cartridge header range (title, flags and checksums) identifying the
+/// cartridge a save state was made with.
+const STATE_CARTRIDGE_ID: std::ops::Range<u16> = 0x0134..0x0150;
+
 pub trait MemoryManagementUnit {
+    /// Advance the simulation by the given amount of CPU clock cycles and
+    /// return the number of clock cycles spent at the normal speed (the CPU
+    /// clock cycles being twice as short in CGB double speed mode).
     fn step(&mut self, ticks: CycleType) -> CycleType;
     fn interrupt_enable(&self) -> u8;
     fn interrupt_flag(&self) -> u8;
     fn set_interrupt_flag(&mut self, flag: u8);
+    /// Called on STOP : if a speed switch was prepared through the KEY1
+    /// register (CGB mode only), perform it and return true.
+    fn switch_speed(&mut self) -> bool;
+    /// Set the I/O registers as left by the boot ROM of the given model.
+    fn post_bios(&mut self, model: HardwareModel);
+    /// The number of frames completed since the start of the emulation.
+    fn frame_count(&self) -> u64;
+    /// Pause or resume the logging of the memory accesses, so that the reads
+    /// of the debugging tools are not mistaken for the CPU ones.
+    fn pause_memory_accesses(&mut self, paused: bool);
 }
 
+/// The I/O registers values common to all models after the boot ROM execution.
+///
+/// The APU must be powered on first for the other sound registers to be
+/// writable.
+const POST_BIOS_IO_REGISTERS: [(u16, u8); 29] = [
+    (0xFF26, 0xF1), // NR52
+    (0xFF05, 0x00), // TIMA
+    (0xFF06, 0x00), // TMA
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF40, 0x91), // LCDC
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF45, 0x00), // LYC
+    (0xFF47, 0xFC), // BGP
+    (0xFF48, 0xFF), // OBP0
+    (0xFF49, 0xFF), // OBP1
+];
+
 /// The Game Boy (Color)'s Memory Management Unit, interfacing between
 /// its CPU and the different memory components (RAM, ROM banks...).
 ///
@@ -30,26 +95,53 @@ pub trait MemoryManagementUnit {
 /// becoming the central hub of the hardware. References may be used in the
 /// future instead.
 pub struct MMU {
+    /// The emulated hardware model.
+    model: HardwareModel,
+    /// If true, the Game Boy Color functions are enabled.
+    cgb_mode: bool,
     /// Internal flag to handle the BIOS loading.
     in_bios: bool,
     /// The BIOS file to execute when starting the emulation.
     bios: &'static [u8],
     /// Timers.
     timers: Timers,
+    /// OAM DMA transfer.
+    oam_dma: OamDma,
+    /// Is the CGB double speed mode enabled ?
+    double_speed: bool,
+    /// KEY1 bit 0 : should the speed be switched on the next STOP instruction ?
+    speed_switch_prepared: bool,
+    /// VRAM DMA transfers (CGB mode only).
+    hdma: Hdma,
+    /// Clock cycles during which the CPU is halted by a VRAM DMA transfer.
+    hdma_stall_cycles: CycleType,
     /// GPU.
     gpu: Gpu,
+    /// APU.
+    apu: Apu,
     /// The MBC interfacing with the cartridge ROM and (optionally) RAM banks.
     mbc: Box<dyn MBC + 'static>,
+    /// Was the cartridge external RAM written to since the last save ?
+    save_data_modified: bool,
     /// The joypad controller.
     joypad: Joypad,
     /// The serial port.
     serial: Serial,
     /// Interrupt Request handler.
     irq_handler: MachineIrqHandler,
-    /// 8K of internal working RAM.
-    wram: [u8; WRAM_SIZE],
+    /// 8K (32K in CGB mode) of internal working RAM.
+    wram: Box<[u8; WRAM_SIZE]>,
+    /// The working RAM bank mapped at 0xD000-0xDFFF (1-7, selected with the
+    /// SVBK register in CGB mode).
+    wram_bank: usize,
     ///'Zero-page' RAM of 128 bytes.
     zram: [u8; ZRAM_SIZE],
+    /// The memory accesses of the CPU since they were last taken, if logged.
+    memory_accesses: Option<Vec<MemoryAccess>>,
+    /// If true, the memory accesses are not logged for the time being.
+    memory_accesses_paused: bool,
+    /// If true, the LY register always reads as 0x90 (see 'stub_ly').
+    ly_stubbed: bool,
 }
 
 /// MMU sub-component passed around to throw interrupt requests from various
@@ -77,26 +169,72 @@ impl IrqHandler for MachineIrqHandler {
 }
 
 impl MMU {
+    /// Create a new 'MMU' emulating the given hardware model.
+    ///
+    /// The Game Boy Color functions are enabled if both the model and the
+    /// cartridge support them.
     pub fn new(
         mbc: Box<dyn MBC>,
-        cgb_mode: bool,
+        model: HardwareModel,
         skip_bios: bool,
         serial_callback: Option<SerialCallback>,
     ) -> MMU {
+        let cgb_mode = model.cgb_mode(mbc.rom_read(CartridgeHeader::CGB_Flag.address() as u16));
+        if model.is_cgb() && !cgb_mode {
+            info!("MMU : running the cartridge in DMG compatibility mode");
+        }
         MMU {
+            model,
+            cgb_mode,
             in_bios: !skip_bios,
             bios: &GB_BIOS,
             timers: Timers::default(),
+            oam_dma: OamDma::default(),
+            double_speed: false,
+            speed_switch_prepared: false,
+            hdma: Hdma::default(),
+            hdma_stall_cycles: 0,
             gpu: Gpu::new(cgb_mode),
+            apu: Apu::new(DEFAULT_SAMPLE_RATE),
             mbc,
+            save_data_modified: false,
             joypad: Joypad::default(),
             serial: Serial::new(serial_callback),
             irq_handler: MachineIrqHandler::new(),
-            wram: [0x0; WRAM_SIZE],
+            wram: Box::new([0x0; WRAM_SIZE]),
+            wram_bank: 1,
             zram: [0x0; ZRAM_SIZE],
+            memory_accesses: None,
+            memory_accesses_paused: false,
+            ly_stubbed: false,
+        }
+    }
+
+    pub fn model(&self) -> HardwareModel {
+        self.model
+    }
+    /// Are the Game Boy Color functions enabled ?
+    pub fn cgb_mode(&self) -> bool {
+        self.cgb_mode
+    }
+
+    /// If the cartridge RAM is battery-buffered, return its content to save
+    /// (see 'MBC::export_save_data') and clear the modified flag.
+    pub fn save_data(&mut self) -> Option<Vec<u8>> {
+        if self.mbc.has_battery() {
+            self.save_data_modified = false;
+            Some(self.mbc.export_save_data())
+        } else {
+            None
         }
     }
 
+    /// Was the battery-buffered cartridge RAM written to since the last call
+    /// to 'save_data' ?
+    pub fn save_data_modified(&self) -> bool {
+        self.save_data_modified && self.mbc.has_battery()
+    }
+
     pub fn key_down(&mut self, key: &JoypadKey) {
         self.joypad.key_down(key, &mut self.irq_handler);
     }
@@ -115,13 +253,121 @@ impl MMU {
             None
         }
     }
+
+    /// The cartridge ROM bank currently mapped at the given ROM address
+    /// (0x0000-0x7FFF).
+    pub fn rom_bank(&self, address: u16) -> usize {
+        self.mbc.rom_bank(address)
+    }
+
+    /// If enabled, the LY register (0xFF44) always reads as 0x90, the start of
+    /// the V-Blank period : the Gameboy Doctor reference logs are recorded
+    /// this way, so that the programs waiting for V-Blank do not depend on
+    /// the exact PPU timings.
+    pub fn stub_ly(&mut self, stubbed: bool) {
+        self.ly_stubbed = stubbed;
+    }
+
+    /// Enable or disable the logging of the memory accesses of the CPU.
+    pub fn log_memory_accesses(&mut self, enabled: bool) {
+        match (enabled, self.memory_accesses.is_some()) {
+            (true, false) => self.memory_accesses = Some(Vec::new()),
+            (false, true) => self.memory_accesses = None,
+            _ => {}
+        }
+    }
+
+    /// Take the memory accesses logged since the last call, oldest first.
+    pub fn take_memory_accesses(&mut self) -> Vec<MemoryAccess> {
+        match self.memory_accesses {
+            Some(ref mut accesses) => std::mem::take(accesses),
+            None => Vec::new(),
+        }
+    }
+
+    /// Return all the audio samples produced since the last call, interleaved
+    /// as left/right pairs in the [-1.0, 1.0] range.
+    pub fn audio_samples(&mut self) -> Vec<f32> {
+        self.apu.take_samples()
+    }
+
+    /// Set the rate (in Hz) at which the audio samples are produced.
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    /// Advance the OAM DMA transfer, if any, by the given amount of CPU clock
+    /// cycles.
+    fn step_oam_dma(&mut self, ticks: CycleType) {
+        let source = self.oam_dma.source();
+        for offset in self.oam_dma.step(ticks) {
+            let byte = self.dma_read_byte(source.wrapping_add(offset));
+            self.gpu.write_byte(0xFE00 + offset, byte);
+        }
+    }
+
+    fn write_hdma_register(&mut self, address: u16, byte: u8) {
+        if self.hdma.write_register(address, byte) {
+            // General Purpose DMA: copy everything at once
+            while self.copy_hdma_block() {}
+        }
+    }
+
+    /// Copy the next 16-byte block of the VRAM DMA transfer in progress, if any.
+    fn copy_hdma_block(&mut self) -> bool {
+        match self.hdma.next_block() {
+            Some((source, destination)) => {
+                for offset in 0..HDMA_BLOCK_SIZE {
+                    let byte = self.dma_read_byte(source.wrapping_add(offset));
+                    self.gpu.write_byte(destination + offset, byte);
+                }
+                self.hdma_stall_cycles += HDMA_BLOCK_CYCLES;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get the index in 'wram' of the given working RAM (or echo RAM) address.
+    fn wram_index(&self, address: usize) -> usize {
+        match address & 0x1FFF {
+            a @ 0x0000..=0x0FFF => a,
+            a => self.wram_bank * WRAM_BANK_SIZE + (a & 0x0FFF),
+        }
+    }
+
+    /// Read a byte as seen by the DMA controllers, bypassing the CPU bus
+    /// restrictions.
+    fn dma_read_byte(&mut self, address: u16) -> u8 {
+        let a = address as usize;
+        match a {
+            0x0000..=0x7FFF => self.mbc.rom_read(address),
+            0x8000..=0x9FFF => self.gpu.read_byte(address),
+            0xA000..=0xBFFF => self.mbc.ram_read(address),
+            // working ram, echoed up to 0xFFFF
+            0xC000..=0xFFFF => self.wram[self.wram_index(a)],
+            _ => unreachable!("MMU.dma_read_byte(address={:0>4X}) overflow", address),
+        }
+    }
 }
 
 impl MemoryManagementUnit for MMU {
     fn step(&mut self, ticks: CycleType) -> CycleType {
-        self.timers.cycle(ticks, &mut self.irq_handler);
-        let gpu_ticks = ticks; // TODO: DMA
-        self.gpu.step(gpu_ticks, &mut self.irq_handler);
+        // the CPU is halted during the VRAM DMA transfers, whose duration does
+        // not depend on the speed mode
+        let stall_ticks = std::mem::take(&mut self.hdma_stall_cycles);
+        // the timers and the OAM DMA are clocked by the CPU
+        let speed_factor = if self.double_speed { 2 } else { 1 };
+        let cpu_ticks = ticks + stall_ticks * speed_factor;
+        self.timers.cycle(cpu_ticks, &mut self.irq_handler);
+        self.step_oam_dma(cpu_ticks);
+        // while the GPU and the APU keep their normal speed
+        let gpu_ticks = ticks / speed_factor + stall_ticks;
+        let hblank_entered = self.gpu.step(gpu_ticks, &mut self.irq_handler);
+        if hblank_entered && self.hdma.hblank_active() {
+            self.copy_hdma_block();
+        }
+        self.apu.step(gpu_ticks);
         gpu_ticks
     }
 
@@ -134,14 +380,140 @@ impl MemoryManagementUnit for MMU {
     fn set_interrupt_flag(&mut self, flag: u8) {
         self.irq_handler.if_reg = flag;
     }
+    fn switch_speed(&mut self) -> bool {
+        if !self.speed_switch_prepared {
+            return false;
+        }
+        self.speed_switch_prepared = false;
+        self.double_speed = !self.double_speed;
+        info!(
+            "MMU : switching to {} speed mode",
+            if self.double_speed {
+                "double"
+            } else {
+                "normal"
+            }
+        );
+        true
+    }
+    fn post_bios(&mut self, model: HardwareModel) {
+        use crate::model::HardwareModel::*;
+
+        for &(address, value) in POST_BIOS_IO_REGISTERS.iter() {
+            self.write_byte(address, value);
+        }
+        // the DIV value and the current scanline depend on the boot ROM
+        // duration ; the SGB and CGB ones (varying with the cartridge) are
+        // approximations
+        let (divider, ly, line_clock) = match model {
+            DMG0 => (0x18, 0x91, 0),
+            DMG | MGB => (0xAB, 153, V_BLANK_CYCLES - 4),
+            SGB | SGB2 => (0xD8, 153, V_BLANK_CYCLES - 4),
+            CGB | AGB => (0x1E, 153, V_BLANK_CYCLES - 4),
+        };
+        self.timers.set_divider(divider);
+        self.gpu.post_bios(ly, line_clock);
+        // the SGB boot ROM does not play the boot sound : channel 1 is left
+        // disabled, which turning its DAC off does
+        if matches!(model, SGB | SGB2) {
+            self.write_byte(0xFF12, 0x00);
+            self.write_byte(0xFF12, 0xF3);
+        }
+        // the CGB boot ROM initializes the background palettes to white
+        if self.cgb_mode {
+            self.write_byte(0xFF68, 0x80);
+            for _ in 0..32 {
+                self.write_byte(0xFF69, 0xFF);
+                self.write_byte(0xFF69, 0x7F);
+            }
+        }
+    }
+
+    fn frame_count(&self) -> u64 {
+        self.gpu.frame_count()
+    }
+    fn pause_memory_accesses(&mut self, paused: bool) {
+        self.memory_accesses_paused = paused;
+    }
+}
+
+impl SaveState for MMU {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for address in STATE_CARTRIDGE_ID {
+            writer.write_u8(self.mbc.rom_read(address));
+        }
+        writer.write_u8(self.model as u8);
+        writer.write_bool(self.cgb_mode);
+        writer.write_bool(self.in_bios);
+        self.timers.save_state(writer);
+        self.oam_dma.save_state(writer);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_prepared);
+        self.hdma.save_state(writer);
+        writer.write_u64(self.hdma_stall_cycles);
+        self.gpu.save_state(writer);
+        self.apu.save_state(writer);
+        self.mbc.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+        writer.write_u8(self.irq_handler.ie_reg);
+        writer.write_u8(self.irq_handler.if_reg);
+        writer.write_bytes(&self.wram[..]);
+        writer.write_usize(self.wram_bank);
+        writer.write_bytes(&self.zram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for address in STATE_CARTRIDGE_ID {
+            if reader.read_u8()? != self.mbc.rom_read(address) {
+                return Err(Error::BadSaveState(
+                    "the save state was made with another cartridge".into(),
+                ));
+            }
+        }
+        // the GPU and the MMU layouts depend on the hardware model
+        if reader.read_u8()? != self.model as u8 || reader.read_bool()? != self.cgb_mode {
+            return Err(Error::BadSaveState(
+                "the save state was made on another hardware model".into(),
+            ));
+        }
+        self.in_bios = reader.read_bool()?;
+        self.timers.load_state(reader)?;
+        self.oam_dma.load_state(reader)?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_prepared = reader.read_bool()?;
+        self.hdma.load_state(reader)?;
+        self.hdma_stall_cycles = reader.read_u64()?;
+        self.gpu.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.mbc.load_state(reader)?;
+        // the battery-buffered RAM must be saved to match the restored one
+        self.save_data_modified = true;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.irq_handler.ie_reg = reader.read_u8()?;
+        self.irq_handler.if_reg = reader.read_u8()?;
+        reader.read_bytes(&mut self.wram[..])?;
+        self.wram_bank = reader.read_usize(WRAM_SIZE / WRAM_BANK_SIZE)?;
+        reader.read_bytes(&mut self.zram)
+    }
 }
 
 // MMU implements the Memory trait to provide transparent interfacing
 // with the CPU.
 impl Memory for MMU {
     fn read_byte(&mut self, address: u16) -> u8 {
+        if let Some(ref mut accesses) = self.memory_accesses
+            && !self.memory_accesses_paused
+        {
+            accesses.push(MemoryAccess::Read(address));
+        }
         let a = address as usize;
         match a {
+            0xFF80..=0xFFFE => self.zram[a & 0x7F],
+            // during an OAM DMA transfer, the CPU cannot access the memory
+            // buses : only HRAM, the I/O registers and IE remain accessible
+            0x0000..=0xFEFF if self.oam_dma.active() => 0xFF,
             // BIOS mode
             _ if self.in_bios => match address {
                 v if v < 0x100 => self.bios[a],
@@ -162,8 +534,8 @@ impl Memory for MMU {
             0x8000..=0x9FFF => self.gpu.read_byte(address),
             // cartridge external RAM
             0xA000..=0xBFFF => self.mbc.ram_read(address),
-            // working ram and its echo (TODO: RAM bank switch for GBC)
-            0xC000..=0xFDFF => self.wram[a & 0x1FFF],
+            // working ram and its echo
+            0xC000..=0xFDFF => self.wram[self.wram_index(a)],
             // GPU : Object Attribute Memory
             0xFE00..=0xFE9F => self.gpu.read_byte(address),
             // not usable
@@ -178,12 +550,24 @@ impl Memory for MMU {
             0xFF02 => self.serial.read_control(),
             // Interrupt Flag Register
             0xFF0F => self.irq_handler.if_reg,
+            // APU registers and wave pattern RAM
+            0xFF10..=0xFF3F => self.apu.read_byte(address),
+            // OAM DMA Transfer
+            0xFF46 => self.oam_dma.read_register(),
+            // VRAM DMA Transfers (CGB mode)
+            0xFF51..=0xFF55 if self.cgb_mode => self.hdma.read_register(address),
+            // KEY1 - Prepare Speed Switch (CGB mode)
+            0xFF4D if self.cgb_mode => {
+                0x7E | ((self.double_speed as u8) << 7) | self.speed_switch_prepared as u8
+            }
+            // LY, stubbed for the Gameboy Doctor traces
+            0xFF44 if self.ly_stubbed => 0x90,
             // GPU registers
             0xFF40..=0xFF4F => self.gpu.read_byte(address),
             // GPU registers (CGB mode)
             0xFF68..=0xFF6B => self.gpu.read_byte(address),
-            // Zero-page RAM
-            0xFF80..=0xFFFE => self.zram[a & 0x7F],
+            // SVBK - WRAM Bank (CGB mode)
+            0xFF70 if self.cgb_mode => 0xF8 | self.wram_bank as u8,
             // Interrupt Enable Register
             0xFFFF => self.irq_handler.ie_reg,
             _ => 0,
@@ -191,13 +575,23 @@ impl Memory for MMU {
     }
 
     fn write_byte(&mut self, address: u16, byte: u8) {
+        if let Some(ref mut accesses) = self.memory_accesses
+            && !self.memory_accesses_paused
+        {
+            accesses.push(MemoryAccess::Write(address, byte));
+        }
         let a = address as usize;
         match a {
+            0xFF80..=0xFFFE => self.zram[a & 0x7F] = byte,
+            0x0000..=0xFEFF if self.oam_dma.active() => (),
             // cartridge ROM
             0x0000..=0x7FFF => self.mbc.rom_control(address, byte),
             0x8000..=0x9FFF => self.gpu.write_byte(address, byte),
-            0xA000..=0xBFFF => self.mbc.ram_write(address, byte),
-            0xC000..=0xFDFF => self.wram[a & 0x1FFF] = byte,
+            0xA000..=0xBFFF => {
+                self.mbc.ram_write(address, byte);
+                self.save_data_modified = true;
+            }
+            0xC000..=0xFDFF => self.wram[self.wram_index(a)] = byte,
             0xFE00..=0xFE9F => self.gpu.write_byte(address, byte),
             0xFEA0..=0xFEFF => {}
             0xFF00 => self.joypad.write_byte(address, byte),
@@ -205,11 +599,319 @@ impl Memory for MMU {
             0xFF02 => self.serial.write_control(byte),
             0xFF04..=0xFF07 => self.timers.write_byte(address, byte),
             0xFF0F => self.irq_handler.if_reg = byte,
+            0xFF10..=0xFF3F => self.apu.write_byte(address, byte),
+            0xFF46 => self.oam_dma.write_register(byte),
+            0xFF51..=0xFF55 if self.cgb_mode => self.write_hdma_register(address, byte),
+            0xFF4D if self.cgb_mode => self.speed_switch_prepared = byte & 0x01 == 0x01,
             0xFF40..=0xFF4F => self.gpu.write_byte(address, byte),
             0xFF68..=0xFF6B => self.gpu.write_byte(address, byte),
-            0xFF80..=0xFFFE => self.zram[a & 0x7F] = byte,
+            // selecting bank 0 selects bank 1
+            0xFF70 if self.cgb_mode => self.wram_bank = cmp::max(byte as usize & 0x07, 1),
             0xFFFF => self.irq_handler.ie_reg = byte,
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::error::Result;
+    use crate::mbc::MBC;
+    use crate::memory::Memory;
+    use crate::model::HardwareModel;
+    use crate::state::{SaveState, StateReader, StateWriter};
+
+    use super::{MMU, MemoryManagementUnit};
+
+    /// Battery-buffered cartridge filled with zeros, except for its CGB flag.
+    struct TestMBC {
+        cgb_flag: u8,
+    }
+
+    impl MBC for TestMBC {
+        fn rom_read(&self, address: u16) -> u8 {
+            match address {
+                0x0143 => self.cgb_flag,
+                _ => 0x00,
+            }
+        }
+        fn rom_bank(&self, address: u16) -> usize {
+            (address as usize) >> 14
+        }
+        fn ram_read(&self, _: u16) -> u8 {
+            0xFF
+        }
+        fn rom_control(&mut self, _: u16, _: u8) {}
+        fn ram_write(&mut self, _: u16, _: u8) {}
+        fn has_battery(&self) -> bool {
+            true
+        }
+        fn export_save_data(&self) -> Vec<u8> {
+            vec![0xFF; 0x2000]
+        }
+        fn import_save_data(&mut self, _: &[u8]) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SaveState for TestMBC {
+        fn save_state(&self, _: &mut StateWriter) {}
+        fn load_state(&mut self, _: &mut StateReader) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn make_mmu() -> MMU {
+        MMU::new(
+            Box::new(TestMBC { cgb_flag: 0x00 }),
+            HardwareModel::DMG,
+            true,
+            None,
+        )
+    }
+
+    fn make_cgb_mmu() -> MMU {
+        MMU::new(
+            Box::new(TestMBC { cgb_flag: 0x80 }),
+            HardwareModel::CGB,
+            true,
+            None,
+        )
+    }
+
+    fn start_hdma(mmu: &mut MMU, source: u16, destination: u16, control: u8) {
+        mmu.write_byte(0xFF51, (source >> 8) as u8);
+        mmu.write_byte(0xFF52, source as u8);
+        mmu.write_byte(0xFF53, (destination >> 8) as u8);
+        mmu.write_byte(0xFF54, destination as u8);
+        mmu.write_byte(0xFF55, control);
+    }
+
+    #[test]
+    fn test_oam_dma_transfer() {
+        let mut mmu = make_mmu();
+        for i in 0..0xA0 {
+            mmu.write_byte(0xC100 + i, i as u8 + 1);
+        }
+        mmu.write_byte(0xFF46, 0xC1);
+        mmu.step(4 * 0x50);
+        assert_eq!(mmu.gpu.read_byte(0xFE4F), 0x50);
+        assert_eq!(mmu.gpu.read_byte(0xFE50), 0x00);
+        mmu.step(4 * 0x50);
+        for i in 0..0xA0 {
+            assert_eq!(mmu.read_byte(0xFE00 + i), i as u8 + 1);
+        }
+    }
+
+    #[test]
+    fn test_oam_dma_cpu_restricted_to_hram() {
+        let mut mmu = make_mmu();
+        mmu.write_byte(0xC000, 0x42);
+        mmu.write_byte(0xFF46, 0xC0);
+        assert_eq!(mmu.read_byte(0xC000), 0xFF);
+        mmu.write_byte(0xC000, 0x24);
+        mmu.write_byte(0xFF80, 0x24);
+        assert_eq!(mmu.read_byte(0xFF80), 0x24);
+        mmu.step(640);
+        assert_eq!(mmu.read_byte(0xC000), 0x42);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC0);
+    }
+
+    #[test]
+    fn test_ly_stub() {
+        let mut mmu = make_mmu();
+        mmu.write_byte(0xFF40, 0x80);
+        for _ in 0..456 * 10 / 4 {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xFF44), 10);
+        mmu.stub_ly(true);
+        assert_eq!(mmu.read_byte(0xFF44), 0x90);
+        for _ in 0..456 / 4 {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xFF44), 0x90);
+        mmu.stub_ly(false);
+        assert_eq!(mmu.read_byte(0xFF44), 11);
+    }
+
+    #[test]
+    fn test_oam_dma_io_registers_accessible() {
+        let mut mmu = make_mmu();
+        mmu.write_byte(0xFF46, 0xC0);
+        assert_eq!(mmu.read_byte(0xFF46), 0xC0);
+        mmu.write_byte(0xFFFF, 0x1F);
+        assert_eq!(mmu.read_byte(0xFFFF), 0x1F);
+        mmu.write_byte(0xFF06, 0x42);
+        assert_eq!(mmu.read_byte(0xFF06), 0x42);
+        assert_eq!(mmu.read_byte(0xFE00), 0xFF);
+        assert_eq!(mmu.read_byte(0x8000), 0xFF);
+    }
+
+    #[test]
+    fn test_hdma_general_purpose_halts_cpu() {
+        let mut mmu = make_cgb_mmu();
+        for i in 0..0x20 {
+            mmu.write_byte(0xC000 + i, i as u8 + 1);
+        }
+        start_hdma(&mut mmu, 0xC000, 0x8000, 0x01);
+        for i in 0..0x20 {
+            assert_eq!(mmu.read_byte(0x8000 + i), i as u8 + 1);
+        }
+        assert_eq!(mmu.read_byte(0xFF55), 0xFF);
+        assert_eq!(mmu.step(4), 4 + 2 * 32);
+        assert_eq!(mmu.step(4), 4);
+    }
+
+    #[test]
+    fn test_hdma_hblank() {
+        let mut mmu = make_cgb_mmu();
+        for i in 0..0x30 {
+            mmu.write_byte(0xC000 + i, 0xAA);
+        }
+        mmu.write_byte(0xFF40, 0x80);
+        start_hdma(&mut mmu, 0xC000, 0x8000, 0x82);
+        assert_eq!(mmu.read_byte(0x8000), 0x00);
+        let mut blocks_copied = vec![];
+        for _ in 0..3 {
+            // one full scanline
+            for _ in 0..(456 / 4) {
+                mmu.step(4);
+            }
+            blocks_copied.push(mmu.read_byte(0xFF55));
+        }
+        assert_eq!(blocks_copied, vec![0x01, 0x00, 0xFF]);
+        assert_eq!(mmu.read_byte(0x802F), 0xAA);
+        assert_eq!(mmu.read_byte(0x8030), 0x00);
+    }
+
+    #[test]
+    fn test_hdma_hblank_cancel() {
+        let mut mmu = make_cgb_mmu();
+        mmu.write_byte(0xFF40, 0x80);
+        start_hdma(&mut mmu, 0xC000, 0x8000, 0x82);
+        mmu.write_byte(0xFF55, 0x00);
+        assert_eq!(mmu.read_byte(0xFF55), 0x82);
+        for _ in 0..(456 / 4) {
+            mmu.step(4);
+        }
+        assert_eq!(mmu.read_byte(0xFF55), 0x82);
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut mmu = make_cgb_mmu();
+        assert_eq!(mmu.read_byte(0xFF70), 0xF9);
+        mmu.write_byte(0xC000, 0x10);
+        mmu.write_byte(0xD000, 0x11);
+        for bank in 2..8 {
+            mmu.write_byte(0xFF70, bank);
+            mmu.write_byte(0xD000, 0x10 + bank);
+        }
+        // bank 0 selects bank 1
+        mmu.write_byte(0xFF70, 0x00);
+        assert_eq!(mmu.read_byte(0xFF70), 0xF9);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+        mmu.write_byte(0xFF70, 0x05);
+        assert_eq!(mmu.read_byte(0xC000), 0x10);
+        assert_eq!(mmu.read_byte(0xD000), 0x15);
+        // echo RAM
+        assert_eq!(mmu.read_byte(0xE000), 0x10);
+        assert_eq!(mmu.read_byte(0xF000), 0x15);
+    }
+
+    #[test]
+    fn test_wram_no_banking_in_classic_mode() {
+        let mut mmu = make_mmu();
+        mmu.write_byte(0xD000, 0x11);
+        mmu.write_byte(0xFF70, 0x02);
+        assert_eq!(mmu.read_byte(0xD000), 0x11);
+        assert_eq!(mmu.read_byte(0xF000), 0x11);
+    }
+
+    #[test]
+    fn test_double_speed_switch() {
+        let mut mmu = make_cgb_mmu();
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7E);
+        assert!(!mmu.switch_speed());
+        mmu.write_byte(0xFF4D, 0x01);
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7F);
+        assert!(mmu.switch_speed());
+        assert_eq!(mmu.read_byte(0xFF4D), 0xFE);
+        // the timers follow the CPU while the GPU keeps its normal speed
+        mmu.write_byte(0xFF04, 0x00);
+        mmu.write_byte(0xFF40, 0x80);
+        assert_eq!(mmu.step(256), 128);
+        assert_eq!(mmu.read_byte(0xFF04), 0x01);
+        assert_eq!(mmu.gpu.read_byte(0xFF44), 0x00);
+        // back to normal speed
+        mmu.write_byte(0xFF4D, 0x01);
+        assert!(mmu.switch_speed());
+        assert_eq!(mmu.read_byte(0xFF4D), 0x7E);
+        assert_eq!(mmu.step(256), 256);
+        assert_eq!(mmu.read_byte(0xFF04), 0x02);
+    }
+
+    #[test]
+    fn test_no_speed_switch_in_classic_mode() {
+        let mut mmu = make_mmu();
+        mmu.write_byte(0xFF4D, 0x01);
+        assert!(!mmu.switch_speed());
+        assert_eq!(mmu.step(256), 256);
+    }
+
+    #[test]
+    fn test_cgb_mode_selection() {
+        let make = |cgb_flag, model| MMU::new(Box::new(TestMBC { cgb_flag }), model, true, None);
+        assert!(make(0x80, HardwareModel::CGB).cgb_mode());
+        assert!(make(0xC0, HardwareModel::AGB).cgb_mode());
+        assert!(!make(0x00, HardwareModel::CGB).cgb_mode());
+        assert!(!make(0xC0, HardwareModel::DMG).cgb_mode());
+    }
+
+    #[test]
+    fn test_post_bios_io_registers() {
+        let mut mmu = make_mmu();
+        mmu.post_bios(HardwareModel::DMG);
+        assert_eq!(mmu.read_byte(0xFF04), 0xAB);
+        assert_eq!(mmu.read_byte(0xFF40), 0x91);
+        assert_eq!(mmu.read_byte(0xFF47), 0xFC);
+        assert_eq!(mmu.read_byte(0xFF26), 0xF1);
+        // LY wraps around to 0 right after the boot ROM returns
+        assert_eq!(mmu.read_byte(0xFF44), 153);
+        mmu.step(4);
+        assert_eq!(mmu.read_byte(0xFF44), 0);
+
+        let mut mmu = make_mmu();
+        mmu.post_bios(HardwareModel::DMG0);
+        assert_eq!(mmu.read_byte(0xFF04), 0x18);
+        assert_eq!(mmu.read_byte(0xFF44), 0x91);
+        assert_eq!(mmu.read_byte(0xFF41) & 0x03, 0x01);
+
+        let mut mmu = make_mmu();
+        mmu.post_bios(HardwareModel::SGB);
+        assert_eq!(mmu.read_byte(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn test_post_bios_cgb_palettes() {
+        let mut mmu = make_cgb_mmu();
+        mmu.post_bios(HardwareModel::CGB);
+        mmu.write_byte(0xFF68, 0x3E);
+        assert_eq!(mmu.read_byte(0xFF69), 0xFF);
+        mmu.write_byte(0xFF68, 0x3F);
+        assert_eq!(mmu.read_byte(0xFF69), 0x7F);
+    }
+
+    #[test]
+    fn test_save_data_modified() {
+        let mut mmu = make_mmu();
+        assert!(!mmu.save_data_modified());
+        mmu.write_byte(0xC000, 0x42);
+        assert!(!mmu.save_data_modified());
+        mmu.write_byte(0xA000, 0x42);
+        assert!(mmu.save_data_modified());
+        assert_eq!(mmu.save_data().map(|data| data.len()), Some(0x2000));
+        assert!(!mmu.save_data_modified());
+    }
+}
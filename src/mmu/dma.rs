@@ -0,0 +1,126 @@
+use crate::cpu::CycleType;
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/// The number of bytes copied by an OAM DMA transfer.
+pub const OAM_DMA_LENGTH: u16 = 0xA0;
+
+/// The number of CPU clock cycles needed to copy one byte.
+const CLOCKS_PER_BYTE: CycleType = 4;
+
+/// High-level structure replicating the Game Boy (Color)'s OAM DMA transfer,
+/// started by writing to register 0xFF46.
+///
+/// The written value specifies the source address divided by 0x100 : the 160
+/// bytes from XX00-XX9F are then copied to the Object Attribute Memory
+/// (FE00-FE9F), at the rate of one byte per machine cycle (640 clocks in
+/// total).
+///
+/// The transfer is clocked by the CPU, meaning it goes twice as fast in CGB
+/// double speed mode.
+///
+/// See the corresponding Pandoc page: https://gbdev.io/pandocs/OAM_DMA_Transfer.html
+#[derive(Default)]
+pub struct OamDma {
+    /// 0xFF46 DMA Transfer and Start Address (DMA).
+    register: u8,
+    /// Is a transfer currently running ?
+    active: bool,
+    /// The number of bytes already copied in the current transfer.
+    progress: u16,
+    /// Clock cycles spent since the last byte copy.
+    clock: CycleType,
+}
+
+impl OamDma {
+    pub fn read_register(&self) -> u8 {
+        self.register
+    }
+
+    /// Start (or restart) a transfer from the given source page.
+    pub fn write_register(&mut self, byte: u8) {
+        self.register = byte;
+        self.active = true;
+        self.progress = 0;
+        self.clock = 0;
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// The address of the first byte of the transfer.
+    pub fn source(&self) -> u16 {
+        (self.register as u16) << 8
+    }
+
+    /// Advance the transfer by the given amount of CPU clock cycles and
+    /// return the range of byte offsets (relative to the source and to OAM)
+    /// that must be copied.
+    pub fn step(&mut self, ticks: CycleType) -> std::ops::Range<u16> {
+        let start = self.progress;
+        if !self.active {
+            return start..start;
+        }
+        self.clock += ticks;
+        let bytes = (self.clock / CLOCKS_PER_BYTE).min((OAM_DMA_LENGTH - start) as CycleType);
+        self.clock -= bytes * CLOCKS_PER_BYTE;
+        self.progress += bytes as u16;
+        if self.progress == OAM_DMA_LENGTH {
+            self.active = false;
+        }
+        start..self.progress
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_bool(self.active);
+        writer.write_usize(self.progress as usize);
+        writer.write_u64(self.clock);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.register = reader.read_u8()?;
+        self.active = reader.read_bool()?;
+        self.progress = reader.read_usize(OAM_DMA_LENGTH as usize + 1)? as u16;
+        self.clock = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{OAM_DMA_LENGTH, OamDma};
+
+    #[test]
+    fn test_oam_dma_timing() {
+        let mut dma = OamDma::default();
+        assert!(!dma.active());
+        assert_eq!(dma.step(640), 0..0);
+
+        dma.write_register(0xC1);
+        assert!(dma.active());
+        assert_eq!(dma.read_register(), 0xC1);
+        assert_eq!(dma.source(), 0xC100);
+        assert_eq!(dma.step(2), 0..0);
+        assert_eq!(dma.step(6), 0..2);
+        assert_eq!(dma.step(4 * 100), 2..102);
+        assert!(dma.active());
+        // the remaining clock cycles are not carried over past the end
+        assert_eq!(dma.step(640), 102..OAM_DMA_LENGTH);
+        assert!(!dma.active());
+        assert_eq!(dma.step(640), OAM_DMA_LENGTH..OAM_DMA_LENGTH);
+    }
+
+    #[test]
+    fn test_oam_dma_restart() {
+        let mut dma = OamDma::default();
+        dma.write_register(0xC1);
+        assert_eq!(dma.step(40), 0..10);
+        dma.write_register(0x80);
+        assert_eq!(dma.source(), 0x8000);
+        assert_eq!(dma.step(40), 0..10);
+    }
+}
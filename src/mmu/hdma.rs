@@ -0,0 +1,176 @@
+use crate::cpu::CycleType;
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
+/// The number of bytes copied at once, and at each H-Blank in H-Blank mode.
+pub const HDMA_BLOCK_SIZE: u16 = 0x10;
+
+/// The number of clock cycles during which the CPU is halted for each block
+/// copied.
+pub const HDMA_BLOCK_CYCLES: CycleType = 32;
+
+/// High-level structure replicating the Game Boy Color's VRAM DMA transfers,
+/// controlled by the registers 0xFF51 to 0xFF55 (HDMA1-HDMA5).
+///
+/// A transfer copies a multiple of 16 bytes from ROM or RAM to VRAM, either
+/// all at once (General Purpose DMA) or 16 bytes at each H-Blank (H-Blank DMA).
+///
+/// See the corresponding Pandoc page: https://gbdev.io/pandocs/CGB_Registers.html#lcd-vram-dma-transfers
+pub struct Hdma {
+    /// The source address (0000-7FF0 or A000-DFF0), the lower 4 bits being
+    /// ignored.
+    source: u16,
+    /// The destination address in VRAM (8000-9FF0), the lower 4 bits being
+    /// ignored.
+    destination: u16,
+    /// 0xFF55 HDMA5 bits 6-0 : the number of 16-byte blocks left to transfer,
+    /// minus 1.
+    blocks: u8,
+    /// Is a transfer in progress ?
+    active: bool,
+    /// Is the transfer in progress an H-Blank DMA ?
+    hblank_mode: bool,
+}
+
+impl Default for Hdma {
+    fn default() -> Self {
+        Self {
+            source: 0x0000,
+            destination: 0x8000,
+            blocks: 0x7F,
+            active: false,
+            hblank_mode: false,
+        }
+    }
+}
+
+impl Hdma {
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            // HDMA1-HDMA4 are write-only
+            0xFF51..=0xFF54 => 0xFF,
+            // bit 7 is cleared while a transfer is active
+            0xFF55 => ((!self.active as u8) << 7) | self.blocks,
+            _ => unreachable!(
+                "mmu::Hdma.read_register(address={:0>4X}) read overflow",
+                address
+            ),
+        }
+    }
+
+    /// Write to the given HDMA register.
+    ///
+    /// Return true if a General Purpose DMA transfer must be performed
+    /// immediately.
+    pub fn write_register(&mut self, address: u16, byte: u8) -> bool {
+        match address {
+            0xFF51 => self.source = (self.source & 0x00FF) | ((byte as u16) << 8),
+            0xFF52 => self.source = (self.source & 0xFF00) | (byte & 0xF0) as u16,
+            0xFF53 => {
+                self.destination =
+                    0x8000 | (self.destination & 0x00FF) | (((byte & 0x1F) as u16) << 8)
+            }
+            0xFF54 => self.destination = (self.destination & 0xFF00) | (byte & 0xF0) as u16,
+            0xFF55 => {
+                // writing with bit 7 cleared during an H-Blank DMA cancels it
+                if self.active && self.hblank_mode && byte & 0x80 == 0x00 {
+                    self.active = false;
+                    return false;
+                }
+                self.blocks = byte & 0x7F;
+                self.active = true;
+                self.hblank_mode = byte & 0x80 == 0x80;
+                return !self.hblank_mode;
+            }
+            _ => unreachable!(
+                "mmu::Hdma.write_register(address={:0>4X}) write overflow",
+                address
+            ),
+        }
+        false
+    }
+
+    /// Is an H-Blank DMA transfer waiting for the next H-Blank ?
+    pub fn hblank_active(&self) -> bool {
+        self.active && self.hblank_mode
+    }
+
+    /// If a transfer is active, return the source and destination addresses
+    /// of the next 16-byte block to copy and advance the transfer.
+    pub fn next_block(&mut self) -> Option<(u16, u16)> {
+        if !self.active {
+            return None;
+        }
+        let addresses = (self.source, self.destination);
+        self.source = self.source.wrapping_add(HDMA_BLOCK_SIZE);
+        self.destination = 0x8000 | (self.destination.wrapping_add(HDMA_BLOCK_SIZE) & 0x1FF0);
+        // the length wraps around to 0x7F once the last block is copied
+        self.blocks = self.blocks.wrapping_sub(1) & 0x7F;
+        if self.blocks == 0x7F {
+            self.active = false;
+        }
+        Some(addresses)
+    }
+}
+
+impl SaveState for Hdma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.source);
+        writer.write_u16(self.destination);
+        writer.write_u8(self.blocks);
+        writer.write_bool(self.active);
+        writer.write_bool(self.hblank_mode);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.source = reader.read_u16()?;
+        self.destination = reader.read_u16()?;
+        self.blocks = reader.read_u8()?;
+        self.active = reader.read_bool()?;
+        self.hblank_mode = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Hdma;
+
+    fn make_hdma(source: u16, destination: u16) -> Hdma {
+        let mut hdma = Hdma::default();
+        hdma.write_register(0xFF51, (source >> 8) as u8);
+        hdma.write_register(0xFF52, source as u8);
+        hdma.write_register(0xFF53, (destination >> 8) as u8);
+        hdma.write_register(0xFF54, destination as u8);
+        hdma
+    }
+
+    #[test]
+    fn test_hdma_general_purpose() {
+        let mut hdma = make_hdma(0xC12F, 0x8105);
+        assert_eq!(hdma.read_register(0xFF55), 0xFF);
+        assert!(hdma.write_register(0xFF55, 0x01));
+        assert!(!hdma.hblank_active());
+        assert_eq!(hdma.read_register(0xFF55), 0x01);
+        assert_eq!(hdma.next_block(), Some((0xC120, 0x8100)));
+        assert_eq!(hdma.next_block(), Some((0xC130, 0x8110)));
+        assert_eq!(hdma.next_block(), None);
+        assert_eq!(hdma.read_register(0xFF55), 0xFF);
+    }
+
+    #[test]
+    fn test_hdma_hblank_and_cancel() {
+        let mut hdma = make_hdma(0x4000, 0x9FF0);
+        assert!(!hdma.write_register(0xFF55, 0x82));
+        assert!(hdma.hblank_active());
+        assert_eq!(hdma.read_register(0xFF55), 0x02);
+        // the destination wraps around in VRAM
+        assert_eq!(hdma.next_block(), Some((0x4000, 0x9FF0)));
+        assert_eq!(hdma.next_block(), Some((0x4010, 0x8000)));
+        assert_eq!(hdma.read_register(0xFF55), 0x00);
+        hdma.write_register(0xFF55, 0x00);
+        assert!(!hdma.hblank_active());
+        assert_eq!(hdma.read_register(0xFF55), 0x80);
+        assert_eq!(hdma.next_block(), None);
+    }
+}
@@ -1,7 +1,9 @@
 use crate::{
     cpu::CycleType,
+    error::Result,
     irq::{Interrupt, IrqHandler},
     memory::Memory,
+    state::{SaveState, StateReader, StateWriter},
 };
 
 /// High-level structure replicating the Game Boy (Color)'s Timer and Divider registers behavior.
@@ -88,13 +90,7 @@ impl Memory for Timers {
             0xFF07 => {
                 if self.control & 0x03 != (byte & 0x03) {
                     self.modulo_clock.reset();
-                    self.modulo_clock.set_period(match byte & 0x03 {
-                        0x00 => 1024,
-                        0x01 => 16,
-                        0x02 => 64,
-                        0x03 => 256,
-                        _ => unreachable!("mmu::Timers.write_byte(address={:0>4X} byte={:0>2X}) timer control error", address, byte),
-                    });
+                    self.modulo_clock.set_period(modulo_period(byte));
                     self.counter = self.modulo;
                 }
                 self.control = byte;
@@ -108,6 +104,12 @@ impl Memory for Timers {
 }
 
 impl Timers {
+    /// Set the Divider Register, as left by the boot ROM.
+    pub fn set_divider(&mut self, value: u8) {
+        self.divider = value;
+        self.divider_clock.reset();
+    }
+
     pub fn cycle(&mut self, ticks: CycleType, irq_handler: &mut dyn IrqHandler) {
         // increment`divider` every 256 cycles (4194304Hz / 16384Hz)
         self.divider = self
@@ -128,6 +130,39 @@ impl Timers {
     }
 }
 
+impl SaveState for Timers {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.divider);
+        writer.write_u64(self.divider_clock.counter);
+        writer.write_u8(self.counter);
+        writer.write_u8(self.modulo);
+        writer.write_u64(self.modulo_clock.counter);
+        writer.write_u8(self.control);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.divider = reader.read_u8()?;
+        self.divider_clock.counter = reader.read_u64()?;
+        self.counter = reader.read_u8()?;
+        self.modulo = reader.read_u8()?;
+        self.modulo_clock.counter = reader.read_u64()?;
+        self.control = reader.read_u8()?;
+        self.modulo_clock.set_period(modulo_period(self.control));
+        Ok(())
+    }
+}
+
+/// The period, in CPU clock cycles, of the Timer Counter selected by the
+/// given Timer Control value.
+fn modulo_period(control: u8) -> CycleType {
+    match control & 0x03 {
+        0x00 => 1024,
+        0x01 => 16,
+        0x02 => 64,
+        _ => 256,
+    }
+}
+
 /// Increments its internal counter by 1 every `period` cycles.
 struct TimerClock {
     period: CycleType,
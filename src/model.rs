@@ -0,0 +1,110 @@
+//! The different Game Boy hardware models that can be emulated.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// A Game Boy hardware model.
+///
+/// The Game Boy Color features (color palettes, VRAM and WRAM banking,
+/// double speed...) are only available on the CGB and AGB models, and only
+/// if the cartridge supports them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HardwareModel {
+    /// Original Game Boy, with its early boot ROM revision.
+    DMG0,
+    /// Original Game Boy.
+    DMG,
+    /// Game Boy Pocket.
+    MGB,
+    /// Super Game Boy.
+    SGB,
+    /// Super Game Boy 2.
+    SGB2,
+    /// Game Boy Color.
+    CGB,
+    /// Game Boy Advance, running Game Boy (Color) cartridges.
+    AGB,
+}
+
+impl HardwareModel {
+    /// Return the default model for a cartridge with the given CGB flag
+    /// (header byte 0x143) : bit 7 is set if the cartridge supports the
+    /// CGB functions (0x80 for compatible and 0xC0 for CGB-only cartridges).
+    pub fn from_cgb_flag(cgb_flag: u8) -> HardwareModel {
+        if cgb_flag & 0x80 == 0x80 {
+            HardwareModel::CGB
+        } else {
+            HardwareModel::DMG
+        }
+    }
+
+    /// Does this model have the Game Boy Color hardware ?
+    pub fn is_cgb(&self) -> bool {
+        matches!(*self, HardwareModel::CGB | HardwareModel::AGB)
+    }
+
+    /// Should the Game Boy Color features be enabled when running a cartridge
+    /// with the given CGB flag ? Otherwise, a CGB model runs the cartridge in
+    /// its DMG compatibility mode.
+    pub fn cgb_mode(&self, cgb_flag: u8) -> bool {
+        self.is_cgb() && cgb_flag & 0x80 == 0x80
+    }
+}
+
+impl FromStr for HardwareModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HardwareModel, String> {
+        match s.to_ascii_uppercase().as_str() {
+            "DMG0" => Ok(HardwareModel::DMG0),
+            "DMG" => Ok(HardwareModel::DMG),
+            "MGB" => Ok(HardwareModel::MGB),
+            "SGB" => Ok(HardwareModel::SGB),
+            "SGB2" => Ok(HardwareModel::SGB2),
+            "CGB" => Ok(HardwareModel::CGB),
+            "AGB" => Ok(HardwareModel::AGB),
+            _ => Err(format!("unknown hardware model \"{}\"", s)),
+        }
+    }
+}
+
+impl fmt::Display for HardwareModel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HardwareModel::{self, *};
+
+    #[test]
+    fn test_model_from_cgb_flag() {
+        assert_eq!(HardwareModel::from_cgb_flag(0x00), DMG);
+        assert_eq!(HardwareModel::from_cgb_flag(0x80), CGB);
+        assert_eq!(HardwareModel::from_cgb_flag(0xC0), CGB);
+    }
+
+    #[test]
+    fn test_model_cgb_mode() {
+        assert!(CGB.cgb_mode(0x80));
+        assert!(AGB.cgb_mode(0xC0));
+        // DMG compatibility mode
+        assert!(!CGB.cgb_mode(0x00));
+        assert!(!DMG.cgb_mode(0xC0));
+        assert!(!MGB.cgb_mode(0x80));
+        assert!(!SGB2.cgb_mode(0x80));
+    }
+
+    #[test]
+    fn test_model_from_str() {
+        assert_eq!("dmg".parse::<HardwareModel>(), Ok(DMG));
+        assert_eq!("DMG0".parse::<HardwareModel>(), Ok(DMG0));
+        assert_eq!("MGB".parse::<HardwareModel>(), Ok(MGB));
+        assert_eq!("sgb".parse::<HardwareModel>(), Ok(SGB));
+        assert_eq!("Sgb2".parse::<HardwareModel>(), Ok(SGB2));
+        assert_eq!("Cgb".parse::<HardwareModel>(), Ok(CGB));
+        assert_eq!("agb".parse::<HardwareModel>(), Ok(AGB));
+        assert!("gbx".parse::<HardwareModel>().is_err());
+    }
+}
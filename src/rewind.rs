@@ -0,0 +1,316 @@
+//! Rewinding : a ring buffer of save states (see the 'state' module), taken
+//! at regular intervals and restored backwards.
+//!
+//! Only the newest save state is stored as is. Each older one is stored as a
+//! delta against the save state following it : since consecutive save states
+//! mostly share the same content, these deltas are much smaller. Dropping the
+//! oldest save state once the buffer is full is thus free, while restoring a
+//! save state only needs to decode the delta against the one just restored.
+//!
+//! The buffer is full once it holds the maximum number of save states, or
+//! once they take up more than the maximum memory size.
+
+use std::collections::VecDeque;
+
+/// A ring buffer of save states, holding a fixed number of them at most.
+pub struct RewindBuffer {
+    /// The maximum number of save states kept.
+    capacity: usize,
+    /// The maximum memory used by the save states, in bytes. The newest save
+    /// state is always kept.
+    max_memory: usize,
+    /// The memory currently used by the save states, in bytes.
+    memory_size: usize,
+    /// The older save states, oldest first, each one encoded as a delta
+    /// against the following one.
+    deltas: VecDeque<Vec<u8>>,
+    /// The newest save state.
+    latest: Option<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    /// Create an empty buffer keeping up to the given number of save states.
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            max_memory: usize::MAX,
+            memory_size: 0,
+            deltas: VecDeque::with_capacity(capacity),
+            latest: None,
+        }
+    }
+
+    /// Limit the memory used by the save states to the given size in bytes,
+    /// dropping the oldest ones beyond it.
+    pub fn with_max_memory(mut self, max_memory: usize) -> RewindBuffer {
+        self.max_memory = max_memory;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn max_memory(&self) -> usize {
+        self.max_memory
+    }
+
+    /// The number of save states in the buffer.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// The memory used by the stored save states, in bytes.
+    pub fn memory_size(&self) -> usize {
+        self.memory_size
+    }
+
+    /// Add the given save state as the newest one, dropping the oldest ones
+    /// while the buffer is full.
+    pub fn push(&mut self, state: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if let Some(latest) = self.latest.take() {
+            // room for the delta of the previous newest save state, if any
+            while !self.deltas.is_empty() && self.deltas.len() + 1 >= self.capacity {
+                self.drop_oldest();
+            }
+            if self.capacity > 1 {
+                let delta = encode_delta(&state, &latest);
+                self.memory_size = self.memory_size - latest.len() + delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.memory_size -= latest.len();
+            }
+        }
+        self.memory_size += state.len();
+        self.latest = Some(state);
+        while self.memory_size > self.max_memory && !self.deltas.is_empty() {
+            self.drop_oldest();
+        }
+    }
+
+    /// Remove and return the newest save state, if any.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let latest = self.latest.take()?;
+        self.memory_size -= latest.len();
+        if let Some(delta) = self.deltas.pop_back() {
+            let state = decode_delta(&latest, &delta);
+            self.memory_size = self.memory_size - delta.len() + state.len();
+            self.latest = Some(state);
+        }
+        Some(latest)
+    }
+
+    pub fn clear(&mut self) {
+        self.deltas.clear();
+        self.latest = None;
+        self.memory_size = 0;
+    }
+
+    fn drop_oldest(&mut self) {
+        if let Some(delta) = self.deltas.pop_front() {
+            self.memory_size -= delta.len();
+        }
+    }
+}
+
+/// Encode the given data as a delta against the given base.
+///
+/// The delta starts with the size of the data, followed by a sequence of
+/// (unchanged byte count, changed byte count, changed bytes) runs. The base
+/// is considered padded with zeros if shorter than the data.
+fn encode_delta(base: &[u8], data: &[u8]) -> Vec<u8> {
+    let base_byte = |index: usize| base.get(index).copied().unwrap_or(0x00);
+    let mut delta = Vec::new();
+    write_varint(&mut delta, data.len());
+    let mut position = 0;
+    while position < data.len() {
+        let unchanged = (position..data.len())
+            .position(|index| data[index] != base_byte(index))
+            .unwrap_or(data.len() - position);
+        position += unchanged;
+        if position == data.len() {
+            break;
+        }
+        let changed = (position..data.len())
+            .position(|index| data[index] == base_byte(index))
+            .unwrap_or(data.len() - position);
+        write_varint(&mut delta, unchanged);
+        write_varint(&mut delta, changed);
+        delta.extend_from_slice(&data[position..position + changed]);
+        position += changed;
+    }
+    delta
+}
+
+/// Decode the data encoded by 'encode_delta' against the same base.
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let size = read_varint(delta, &mut position);
+    let mut data = base.to_vec();
+    data.resize(size, 0x00);
+    let mut index = 0;
+    while position < delta.len() {
+        index += read_varint(delta, &mut position);
+        let changed = read_varint(delta, &mut position);
+        data[index..index + changed].copy_from_slice(&delta[position..position + changed]);
+        position += changed;
+        index += changed;
+    }
+    data
+}
+
+/// Write the given value as an LEB128 variable-length integer.
+fn write_varint(data: &mut Vec<u8>, value: usize) {
+    let mut value = value;
+    while value >= 0x80 {
+        data.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// Read an LEB128 variable-length integer at the given position, and move
+/// the position past it.
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0x00 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RewindBuffer, decode_delta, encode_delta};
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut data = base.clone();
+        data[0] = 0xFF;
+        data[500..700].fill(0x42);
+        data[999] = 0x00;
+        let delta = encode_delta(&base, &data);
+        assert!(delta.len() < 220);
+        assert_eq!(decode_delta(&base, &delta), data);
+        // identical data
+        let delta = encode_delta(&base, &base);
+        assert!(delta.len() < 4);
+        assert_eq!(decode_delta(&base, &delta), base);
+        // different sizes
+        assert_eq!(
+            decode_delta(&base, &encode_delta(&base, &data[..10])),
+            data[..10]
+        );
+        assert_eq!(
+            decode_delta(&base[..10], &encode_delta(&base[..10], &data)),
+            data
+        );
+    }
+
+    #[test]
+    fn test_rewind_buffer() {
+        let states: Vec<Vec<u8>> = (0..10u8)
+            .map(|i| {
+                let mut state = vec![0x55; 300];
+                state[i as usize * 10..i as usize * 10 + 5].fill(i);
+                state
+            })
+            .collect();
+        let mut buffer = RewindBuffer::new(4);
+        assert!(buffer.pop().is_none());
+        for state in states.iter() {
+            buffer.push(state.clone());
+        }
+        assert_eq!(buffer.len(), 4);
+        assert!(buffer.memory_size() < 2 * 300);
+        for state in states.iter().rev().take(4) {
+            assert_eq!(buffer.pop().as_ref(), Some(state));
+        }
+        assert!(buffer.is_empty());
+        assert!(buffer.pop().is_none());
+
+        buffer.push(states[0].clone());
+        buffer.push(states[1].clone());
+        assert_eq!(buffer.pop().as_ref(), Some(&states[1]));
+        buffer.push(states[2].clone());
+        assert_eq!(buffer.pop().as_ref(), Some(&states[2]));
+        assert_eq!(buffer.pop().as_ref(), Some(&states[0]));
+        buffer.push(states[3].clone());
+        buffer.clear();
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_rewind_buffer_max_memory() {
+        let states: Vec<Vec<u8>> = (0..10u8)
+            .map(|i| {
+                let mut state = vec![0x55; 300];
+                state[i as usize * 10..i as usize * 10 + 5].fill(i);
+                state
+            })
+            .collect();
+        let mut buffer = RewindBuffer::new(10).with_max_memory(350);
+        for state in states.iter() {
+            buffer.push(state.clone());
+            assert!(buffer.memory_size() <= 350);
+        }
+        let len = buffer.len();
+        assert!((2..10).contains(&len));
+        for state in states.iter().rev().take(len) {
+            assert_eq!(buffer.pop().as_ref(), Some(state));
+            assert!(buffer.memory_size() <= 350);
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.memory_size(), 0);
+
+        // the newest save state is always kept
+        let mut buffer = RewindBuffer::new(10).with_max_memory(100);
+        buffer.push(states[0].clone());
+        buffer.push(states[1].clone());
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.memory_size(), 300);
+        buffer.push(states[2].clone());
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.memory_size(), 300);
+        assert_eq!(buffer.pop().as_ref(), Some(&states[2]));
+    }
+
+    #[test]
+    fn test_rewind_buffer_small_capacity() {
+        for capacity in 1..=3 {
+            let mut buffer = RewindBuffer::new(capacity);
+            for i in 0..10u8 {
+                buffer.push(vec![i; 16]);
+                assert!(buffer.len() <= capacity);
+            }
+            assert_eq!(buffer.len(), capacity);
+            for i in (10 - capacity as u8..10).rev() {
+                assert_eq!(buffer.pop(), Some(vec![i; 16]));
+            }
+            assert!(buffer.is_empty());
+            assert_eq!(buffer.memory_size(), 0);
+        }
+    }
+
+    #[test]
+    fn test_rewind_buffer_disabled() {
+        let mut buffer = RewindBuffer::new(0);
+        buffer.push(vec![0x00; 16]);
+        assert!(buffer.is_empty());
+        assert!(buffer.pop().is_none());
+    }
+}
@@ -1,3 +1,6 @@
+use crate::error::Result;
+use crate::state::{SaveState, StateReader, StateWriter};
+
 pub type SerialCallback = Box<dyn FnMut(u8)>;
 
 // TODO: serial INT
@@ -33,3 +36,17 @@ impl Serial {
         }
     }
 }
+
+impl SaveState for Serial {
+    /// The callback is not part of the state.
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.data = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! Save states : snapshots of the whole emulated machine.
+//!
+//! A save state is a binary blob starting with a magic number and a format
+//! version, followed by the state of every component (CPU, MMU, GPU, APU,
+//! timers, cartridge...) serialized in a fixed order. Integers are stored in
+//! little-endian.
+//!
+//! The caches derived from the raw hardware state (decoded tiles, palette
+//! colors...) are not stored but rebuilt on load.
+
+use crate::error::{Error, Result};
+
+/// The magic number at the start of every save state.
+pub const STATE_MAGIC: [u8; 4] = *b"RBCS";
+
+/// The version of the save state format, to increment on any change to the
+/// serialized data.
+pub const STATE_VERSION: u16 = 1;
+
+/// A component of the machine whose state can be saved and restored.
+pub trait SaveState {
+    /// Append the state of the component to the given writer.
+    fn save_state(&self, writer: &mut StateWriter);
+    /// Restore the state of the component, as saved by 'save_state', from
+    /// the given reader.
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()>;
+}
+
+/// Serializes the state of the machine components.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Create a writer starting with the save state header.
+    pub fn new() -> StateWriter {
+        let mut writer = StateWriter::default();
+        writer.write_bytes(&STATE_MAGIC);
+        writer.write_u16(STATE_VERSION);
+        writer
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+    /// Write an index or a size, which must fit in 32 bits.
+    pub fn write_usize(&mut self, value: usize) {
+        debug_assert!(value <= u32::MAX as usize);
+        self.write_u32(value as u32);
+    }
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    /// Write a fixed-size block of bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+    /// Write a variable-size block of bytes, prefixed by its size.
+    pub fn write_data(&mut self, bytes: &[u8]) {
+        self.write_usize(bytes.len());
+        self.write_bytes(bytes);
+    }
+
+    /// Return the serialized state.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Deserializes the state of the machine components.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Create a reader over the given save state, checking its header.
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>> {
+        let mut reader = StateReader { data, position: 0 };
+        let mut magic = [0x00; 4];
+        reader.read_bytes(&mut magic)?;
+        if magic != STATE_MAGIC {
+            return Err(Error::BadSaveState("not a save state".into()));
+        }
+        let version = reader.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(Error::BadSaveState(format!(
+                "unsupported version {} (expected {})",
+                version, STATE_VERSION
+            )));
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.position < length {
+            return Err(Error::BadSaveState("unexpected end of data".into()));
+        }
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_u16(&mut self) -> Result<u16> {
+        let mut bytes = [0x00; 2];
+        self.read_bytes(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0x00; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0x00; 8];
+        self.read_bytes(&mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+    pub fn read_bool(&mut self) -> Result<bool> {
+        match self.read_u8()? {
+            0x00 => Ok(false),
+            0x01 => Ok(true),
+            byte => Err(Error::BadSaveState(format!(
+                "invalid boolean value {:0>2X}",
+                byte
+            ))),
+        }
+    }
+    /// Read an index or a size, which must be lower than the given bound.
+    pub fn read_usize(&mut self, bound: usize) -> Result<usize> {
+        let value = self.read_u32()? as usize;
+        if value >= bound {
+            return Err(Error::BadSaveState(format!(
+                "value {} out of range (0-{})",
+                value,
+                bound - 1
+            )));
+        }
+        Ok(value)
+    }
+    pub fn read_f32(&mut self) -> Result<f32> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    /// Read a fixed-size block of bytes, filling the given buffer.
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<()> {
+        bytes.copy_from_slice(self.take(bytes.len())?);
+        Ok(())
+    }
+    /// Read a variable-size block of bytes written by 'write_data', checking
+    /// that its size matches the given buffer.
+    pub fn read_data(&mut self, bytes: &mut [u8]) -> Result<()> {
+        let length = self.read_u32()? as usize;
+        if length != bytes.len() {
+            return Err(Error::BadSaveState(format!(
+                "{} bytes of data for a {}-byte memory",
+                length,
+                bytes.len()
+            )));
+        }
+        self.read_bytes(bytes)
+    }
+
+    /// Check that the whole save state was read.
+    pub fn finish(self) -> Result<()> {
+        if self.position != self.data.len() {
+            return Err(Error::BadSaveState(format!(
+                "{} unexpected trailing bytes",
+                self.data.len() - self.position
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{STATE_MAGIC, StateReader, StateWriter};
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_u16(0x3456);
+        writer.write_u32(0x789A_BCDE);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bool(true);
+        writer.write_usize(42);
+        writer.write_f32(-0.5);
+        writer.write_bytes(&[0xAA, 0xBB]);
+        writer.write_data(&[0xCC; 3]);
+        let data = writer.into_data();
+        assert_eq!(data[0..4], STATE_MAGIC);
+
+        let mut reader = StateReader::new(&data).unwrap();
+        assert_eq!(reader.read_u8().unwrap(), 0x12);
+        assert_eq!(reader.read_u16().unwrap(), 0x3456);
+        assert_eq!(reader.read_u32().unwrap(), 0x789A_BCDE);
+        assert_eq!(reader.read_u64().unwrap(), 0x0123_4567_89AB_CDEF);
+        assert!(reader.read_bool().unwrap());
+        assert!(reader.read_usize(42).is_err());
+        let mut reader = StateReader::new(&data).unwrap();
+        reader.read_bytes(&mut [0x00; 16]).unwrap();
+        assert_eq!(reader.read_usize(43).unwrap(), 42);
+        assert_eq!(reader.read_f32().unwrap(), -0.5);
+        let mut bytes = [0x00; 2];
+        reader.read_bytes(&mut bytes).unwrap();
+        assert_eq!(bytes, [0xAA, 0xBB]);
+        assert!(reader.read_data(&mut [0x00; 2]).is_err());
+    }
+
+    #[test]
+    fn test_state_invalid_data() {
+        assert!(StateReader::new(b"RBC").is_err());
+        assert!(StateReader::new(b"XXXX\x01\x00").is_err());
+        assert!(StateReader::new(b"RBCS\xFF\x00").is_err());
+        let data = StateWriter::new().into_data();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert!(reader.read_u8().is_err());
+
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x02);
+        let data = writer.into_data();
+        let mut reader = StateReader::new(&data).unwrap();
+        assert!(reader.read_bool().is_err());
+        assert!(StateReader::new(&data).unwrap().finish().is_err());
+    }
+}
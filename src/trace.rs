@@ -0,0 +1,519 @@
+//! CPU trace logs : one line per instruction executed by the CPU, describing
+//! its state just before the execution.
+//!
+//! The 'Doctor' format follows the layout of the Gameboy Doctor reference
+//! logs, so that a trace can be diffed line by line against a known-good
+//! log :
+//!
+//! A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//!
+//! Tracing can be restricted to a part of the execution with start and stop
+//! triggers, on the program counter or the frame number.
+//!
+//! 'diff_trace' runs the machine alongside a reference trace log, until the
+//! first mismatching line.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::cpu::{CPU_CLOCK_SPEED, Cpu, CycleType, Registers};
+use crate::disasm::{Instruction, disassemble};
+use crate::error::{Error, Result};
+use crate::memory::Memory;
+use crate::mmu::{MMU, MemoryManagementUnit};
+
+/// The number of CPU steps without any trace line (e.g. while halted) after
+/// which 'diff_trace' considers that the CPU stopped executing instructions.
+const MAX_STEPS_WITHOUT_LINE: usize = CPU_CLOCK_SPEED as usize;
+
+/// The layout of the trace lines.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The opcode, the registers, the disassembled instruction and the
+    /// machine cycles it took. The interrupts dispatched are also logged.
+    Disassembly,
+    /// The registers and the 4 bytes at PC, as in the Gameboy Doctor logs.
+    Doctor,
+}
+
+impl TraceFormat {
+    /// Get the format from its (case-insensitive) name.
+    pub fn from_name(name: &str) -> Option<TraceFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "disassembly" => Some(TraceFormat::Disassembly),
+            "doctor" => Some(TraceFormat::Doctor),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TraceFormat::Disassembly => "disassembly",
+            TraceFormat::Doctor => "doctor",
+        }
+    }
+
+    /// Format the state of the CPU about to execute the instruction at PC,
+    /// without the line ending.
+    pub fn format_line<M: Memory>(self, regs: &Registers, mem: &mut M) -> String {
+        let pc = regs.pc;
+        match self {
+            TraceFormat::Disassembly => format!(
+                "OP={:0>2X} PC={:0>4X} AF={:0>4X} BC={:0>4X} DE={:0>4X} HL={:0>4X} SP={:0>4X} {:<16}",
+                mem.read_byte(pc),
+                pc,
+                regs.af(),
+                regs.bc(),
+                regs.de(),
+                regs.hl(),
+                regs.sp,
+                disassemble(mem, pc).text,
+            ),
+            TraceFormat::Doctor => format!(
+                "A:{:0>2X} F:{:0>2X} B:{:0>2X} C:{:0>2X} D:{:0>2X} E:{:0>2X} H:{:0>2X} L:{:0>2X} \
+                 SP:{:0>4X} PC:{:0>4X} PCMEM:{:0>2X},{:0>2X},{:0>2X},{:0>2X}",
+                regs.a,
+                regs.f,
+                regs.b,
+                regs.c,
+                regs.d,
+                regs.e,
+                regs.h,
+                regs.l,
+                regs.sp,
+                pc,
+                mem.read_byte(pc),
+                mem.read_byte(pc.wrapping_add(1)),
+                mem.read_byte(pc.wrapping_add(2)),
+                mem.read_byte(pc.wrapping_add(3)),
+            ),
+        }
+    }
+}
+
+impl fmt::Display for TraceFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Starts or stops the tracing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceTrigger {
+    /// When the CPU is about to execute the instruction at the given address.
+    Address(u16),
+    /// When the given frame (as counted by
+    /// 'MemoryManagementUnit::frame_count') is reached.
+    Frame(u64),
+}
+
+impl TraceTrigger {
+    /// Parse a trigger written as "pc:ADDRESS" (in hexadecimal) or
+    /// "frame:FRAME".
+    pub fn parse(text: &str) -> Option<TraceTrigger> {
+        let (kind, value) = text.trim().split_once(':')?;
+        match kind.to_ascii_lowercase().as_str() {
+            "pc" => {
+                let value = value.trim_start_matches("0x").trim_start_matches('$');
+                u16::from_str_radix(value, 16)
+                    .ok()
+                    .map(TraceTrigger::Address)
+            }
+            "frame" => value.parse().ok().map(TraceTrigger::Frame),
+            _ => None,
+        }
+    }
+
+    fn is_met(self, pc: u16, frame: u64) -> bool {
+        match self {
+            TraceTrigger::Address(address) => pc == address,
+            TraceTrigger::Frame(trigger_frame) => frame >= trigger_frame,
+        }
+    }
+}
+
+impl fmt::Display for TraceTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceTrigger::Address(address) => write!(f, "pc:{:0>4X}", address),
+            TraceTrigger::Frame(frame) => write!(f, "frame:{}", frame),
+        }
+    }
+}
+
+/// Where the tracer is in the execution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TraceState {
+    /// Waiting for the start trigger.
+    Waiting,
+    Tracing,
+    /// The stop trigger was met.
+    Stopped,
+}
+
+/// Writes the trace log of the CPU (see 'Cpu::set_tracer').
+pub struct Tracer {
+    format: TraceFormat,
+    output: Box<dyn Write>,
+    start: Option<TraceTrigger>,
+    stop: Option<TraceTrigger>,
+    /// The runtime switch : while disabled, the tracer ignores everything.
+    enabled: bool,
+    state: TraceState,
+    /// Is the line of the instruction being executed waiting for its cycles ?
+    line_pending: bool,
+}
+
+impl Tracer {
+    /// Create an enabled tracer writing to the given output, tracing from
+    /// the start of the execution.
+    pub fn new(format: TraceFormat, output: Box<dyn Write>) -> Tracer {
+        Tracer {
+            format,
+            output,
+            start: None,
+            stop: None,
+            enabled: true,
+            state: TraceState::Waiting,
+            line_pending: false,
+        }
+    }
+
+    /// Create an enabled tracer writing to the given file.
+    pub fn create(format: TraceFormat, path: &Path) -> Result<Tracer> {
+        let file = File::create(path).map_err(|why| Error::io(path, why))?;
+        Ok(Tracer::new(format, Box::new(BufWriter::new(file))))
+    }
+
+    /// Only start tracing when the given trigger is met.
+    pub fn start_at(mut self, trigger: TraceTrigger) -> Tracer {
+        self.start = Some(trigger);
+        self
+    }
+
+    /// Stop tracing for good when the given trigger is met.
+    pub fn stop_at(mut self, trigger: TraceTrigger) -> Tracer {
+        self.stop = Some(trigger);
+        self
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.end_line();
+            self.flush();
+        }
+        self.enabled = enabled;
+    }
+
+    /// Was the stop trigger met ?
+    pub fn is_stopped(&self) -> bool {
+        self.state == TraceState::Stopped
+    }
+
+    /// Write the buffered trace lines, if any.
+    pub fn flush(&mut self) {
+        if let Err(why) = self.output.flush() {
+            error!("cannot write the CPU trace : {}", why);
+        }
+    }
+
+    /// Trace the CPU about to execute the instruction at PC.
+    pub fn trace_instruction<M>(&mut self, regs: &Registers, mem: &mut M)
+    where
+        M: Memory + MemoryManagementUnit,
+    {
+        if !self.enabled {
+            return;
+        }
+        self.end_line();
+        let (pc, frame) = (regs.pc, mem.frame_count());
+        if self.state == TraceState::Waiting
+            && self.start.is_none_or(|trigger| trigger.is_met(pc, frame))
+        {
+            self.state = TraceState::Tracing;
+        }
+        if self.state != TraceState::Tracing {
+            return;
+        }
+        if self.stop.is_some_and(|trigger| trigger.is_met(pc, frame)) {
+            self.state = TraceState::Stopped;
+            self.flush();
+            return;
+        }
+        // the bytes read for the line are not accessed by the CPU
+        mem.pause_memory_accesses(true);
+        let line = self.format.format_line(regs, mem);
+        mem.pause_memory_accesses(false);
+        match self.format {
+            TraceFormat::Disassembly => {
+                self.write(format_args!("{}", line));
+                self.line_pending = true;
+            }
+            TraceFormat::Doctor => self.write(format_args!("{}\n", line)),
+        }
+    }
+
+    /// Trace the machine cycles spent by the instruction just executed.
+    pub fn trace_cycles(&mut self, cycles: CycleType) {
+        if self.line_pending {
+            self.line_pending = false;
+            self.write(format_args!(" CY={}\n", cycles));
+        }
+    }
+
+    /// Trace the dispatch of the given interrupts.
+    pub fn trace_interrupt(&mut self, interrupts: u8) {
+        if self.enabled
+            && self.state == TraceState::Tracing
+            && self.format == TraceFormat::Disassembly
+        {
+            self.end_line();
+            self.write(format_args!("INT {:0>2X}\n", interrupts));
+        }
+    }
+
+    /// End the pending line, if an instruction did not complete (e.g. when
+    /// the tracing was switched off in the middle of it).
+    fn end_line(&mut self) {
+        if self.line_pending {
+            self.line_pending = false;
+            self.write(format_args!("\n"));
+        }
+    }
+
+    fn write(&mut self, line: fmt::Arguments) {
+        if let Err(why) = self.output.write_fmt(line) {
+            error!("cannot write the CPU trace, stopping it : {}", why);
+            self.state = TraceState::Stopped;
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        self.end_line();
+        self.flush();
+    }
+}
+
+/// A line of a trace log, along with the instruction it traces.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceLine {
+    /// The line number, starting from 1.
+    pub number: usize,
+    pub text: String,
+    /// The instruction traced, None for a dispatched interrupt.
+    pub instruction: Option<Instruction>,
+    /// The cartridge ROM bank of the instruction, if it is in the ROM.
+    pub bank: Option<usize>,
+}
+
+/// The first line of a trace log differing from the reference log.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceMismatch {
+    /// The line number, starting from 1.
+    pub number: usize,
+    /// The line of the reference log.
+    pub expected: String,
+    /// The line traced instead, None if the CPU stopped executing
+    /// instructions.
+    pub actual: Option<TraceLine>,
+    /// The lines traced before, identical in both logs, oldest first.
+    pub before: Vec<TraceLine>,
+    /// The lines traced after the mismatching one.
+    pub after: Vec<TraceLine>,
+}
+
+impl TraceMismatch {
+    /// The names of the fields which differ, e.g. "F" or "PCMEM" (or
+    /// "instruction" for the disassembled instruction).
+    pub fn mismatching_fields(&self) -> Vec<String> {
+        let expected: Vec<&str> = self.expected.split_whitespace().collect();
+        let actual: Vec<&str> = match self.actual {
+            Some(ref line) => line.text.split_whitespace().collect(),
+            None => Vec::new(),
+        };
+        let mut fields: Vec<String> = Vec::new();
+        for index in 0..expected.len().max(actual.len()) {
+            let (expected, actual) = (expected.get(index), actual.get(index));
+            if expected == actual {
+                continue;
+            }
+            let field = expected.or(actual).unwrap();
+            let name = match field.find([':', '=']) {
+                Some(end) => &field[..end],
+                None => "instruction",
+            };
+            if !fields.iter().any(|field| field == name) {
+                fields.push(name.into());
+            }
+        }
+        fields
+    }
+}
+
+/// A trace output which can still be read once given to the CPU.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(data)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the CPU until the next trace line.
+struct TraceRunner<'a> {
+    cpu: &'a mut Cpu<MMU>,
+    output: SharedBuffer,
+    /// The number of lines traced.
+    lines: usize,
+}
+
+impl TraceRunner<'_> {
+    /// Return None if the CPU stopped executing instructions.
+    fn next_line(&mut self) -> Option<TraceLine> {
+        for _ in 0..MAX_STEPS_WITHOUT_LINE {
+            let pc = self.cpu.regs.pc;
+            let bank = (pc < 0x8000).then(|| self.cpu.mem.rom_bank(pc));
+            let instruction = disassemble(&mut self.cpu.mem, pc);
+            self.cpu.step();
+
+            let mut output = self.output.0.borrow_mut();
+            let Some(end) = output.iter().position(|&byte| byte == b'\n') else {
+                continue;
+            };
+            let text = String::from_utf8_lossy(&output[..end]).into_owned();
+            output.drain(..=end);
+            self.lines += 1;
+            let interrupt = text.starts_with("INT");
+            return Some(TraceLine {
+                number: self.lines,
+                text,
+                instruction: (!interrupt).then_some(instruction),
+                bank: bank.filter(|_| !interrupt),
+            });
+        }
+        None
+    }
+}
+
+/// Run the machine, traced in the given format, until its trace log differs
+/// from the given reference one. Return None if the whole reference log
+/// matched.
+///
+/// The mismatch comes with the given number of lines traced before and after
+/// it. NB : the tracer of the CPU, if any, is removed.
+pub fn diff_trace<R: BufRead>(
+    cpu: &mut Cpu<MMU>,
+    format: TraceFormat,
+    reference: R,
+    context: usize,
+) -> io::Result<Option<TraceMismatch>> {
+    let output = SharedBuffer::default();
+    cpu.set_tracer(Some(Tracer::new(format, Box::new(output.clone()))));
+    let mut runner = TraceRunner {
+        cpu,
+        output,
+        lines: 0,
+    };
+    let mut before: VecDeque<TraceLine> = VecDeque::with_capacity(context + 1);
+    let mut mismatch = None;
+    for (index, expected) in reference.lines().enumerate() {
+        let expected = expected?;
+        let expected = expected.trim_end();
+        let actual = runner.next_line();
+        if actual.as_ref().is_some_and(|line| line.text == expected) {
+            before.extend(actual);
+            if before.len() > context {
+                before.pop_front();
+            }
+            continue;
+        }
+        let after = if actual.is_some() {
+            (0..context).map_while(|_| runner.next_line()).collect()
+        } else {
+            Vec::new()
+        };
+        mismatch = Some(TraceMismatch {
+            number: index + 1,
+            expected: expected.into(),
+            actual,
+            before: before.into(),
+            after,
+        });
+        break;
+    }
+    runner.cpu.set_tracer(None);
+    Ok(mismatch)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TraceFormat, TraceLine, TraceMismatch, TraceTrigger};
+
+    #[test]
+    fn test_trace_trigger_parse() {
+        assert_eq!(
+            TraceTrigger::parse("pc:0150"),
+            Some(TraceTrigger::Address(0x0150))
+        );
+        assert_eq!(
+            TraceTrigger::parse("PC:$C000"),
+            Some(TraceTrigger::Address(0xC000))
+        );
+        assert_eq!(
+            TraceTrigger::parse("frame:60"),
+            Some(TraceTrigger::Frame(60))
+        );
+        assert_eq!(TraceTrigger::parse("frame:0x10"), None);
+        assert_eq!(TraceTrigger::parse("pc:10000"), None);
+        assert_eq!(TraceTrigger::parse("0150"), None);
+        assert_eq!(TraceTrigger::Address(0x150).to_string(), "pc:0150");
+        assert_eq!(TraceFormat::from_name("Doctor"), Some(TraceFormat::Doctor));
+        assert_eq!(TraceFormat::from_name("gbdoctor"), None);
+    }
+
+    #[test]
+    fn test_trace_mismatching_fields() {
+        let mut mismatch = TraceMismatch {
+            number: 1,
+            expected: "A:01 F:B0 B:00 SP:FFFE PC:0100 PCMEM:00,C3,13,02".into(),
+            actual: Some(TraceLine {
+                number: 1,
+                text: "A:01 F:80 B:00 SP:FFFE PC:0100 PCMEM:00,C3,13,FF".into(),
+                instruction: None,
+                bank: None,
+            }),
+            before: Vec::new(),
+            after: Vec::new(),
+        };
+        assert_eq!(mismatch.mismatching_fields(), ["F", "PCMEM"]);
+        mismatch.actual = None;
+        assert_eq!(mismatch.mismatching_fields().len(), 6);
+        mismatch.expected = "OP=00 PC=0100 NOP CY=1".into();
+        mismatch.actual = Some(TraceLine {
+            number: 1,
+            text: "OP=C3 PC=0100 JP $0213 CY=4".into(),
+            instruction: None,
+            bank: None,
+        });
+        assert_eq!(mismatch.mismatching_fields(), ["OP", "instruction", "CY"]);
+    }
+}
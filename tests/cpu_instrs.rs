@@ -5,6 +5,7 @@ use std::rc::Rc;
 use rustboylib::cpu::Cpu;
 use rustboylib::mbc;
 use rustboylib::mmu::MMU;
+use rustboylib::model::HardwareModel;
 use rustboylib::serial::SerialCallback;
 
 const CPU_STEPS_LIMIT: usize = 10_000_000; // TODO: detect test ending?
@@ -15,10 +16,11 @@ fn setup(rom_path: &str) -> (Cpu<MMU>, Rc<RefCell<String>>) {
     let serial_callback: SerialCallback =
         Box::new(move |data: u8| serial_output_mmu.borrow_mut().push(data as char));
 
-    let mbc = mbc::load_cartridge(Path::new(rom_path)).expect("test ROM loading error");
-    let mmu = MMU::new(mbc, false, true, Some(serial_callback));
+    let (_, mbc) = mbc::load_cartridge(Path::new(rom_path), mbc::HeaderStrictness::Error)
+        .expect("test ROM loading error");
+    let mmu = MMU::new(mbc, HardwareModel::DMG, true, Some(serial_callback));
     let mut cpu = Cpu::new(mmu);
-    cpu.post_bios();
+    cpu.post_bios(HardwareModel::DMG);
     (cpu, serial_output)
 }
 
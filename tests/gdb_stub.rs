@@ -0,0 +1,203 @@
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use rustboylib::cpu::Cpu;
+use rustboylib::debugger::Debugger;
+use rustboylib::gdb::GdbStub;
+use rustboylib::mbc;
+use rustboylib::mmu::MMU;
+use rustboylib::model::HardwareModel;
+
+/// 0100: NOP
+/// 0101: JP 0213
+const ROM_PATH: &str = "tests/cpu_instrs/01-special.gb";
+
+/// A scripted GDB client.
+struct Client {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Client {
+    fn connect(address: SocketAddr) -> Client {
+        let stream = TcpStream::connect(address).expect("cannot connect to the GDB stub");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream.set_nodelay(true).unwrap();
+        Client { stream, ack: true }
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0x00];
+        self.stream
+            .read_exact(&mut byte)
+            .expect("no reply from the GDB stub");
+        byte[0]
+    }
+
+    fn send(&mut self, packet: &str) {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:0>2x}", packet, checksum).unwrap();
+        if self.ack {
+            assert_eq!(
+                self.read_byte(),
+                b'+',
+                "packet \"{}\" not acknowledged",
+                packet
+            );
+        }
+    }
+
+    fn receive(&mut self) -> String {
+        assert_eq!(self.read_byte(), b'$');
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let checksum = [self.read_byte(), self.read_byte()];
+        let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap();
+        assert_eq!(
+            checksum,
+            reply.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        );
+        if self.ack {
+            self.stream.write_all(b"+").unwrap();
+        }
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn request(&mut self, packet: &str) -> String {
+        self.send(packet);
+        self.receive()
+    }
+}
+
+fn script(address: SocketAddr) {
+    let mut client = Client::connect(address);
+    assert!(
+        client
+            .request("qSupported:swbreak+")
+            .contains("PacketSize=")
+    );
+    assert_eq!(client.request("?"), "S05");
+
+    // post-BIOS registers : AF, BC, DE, HL, SP and PC
+    assert_eq!(client.request("g"), "b0011300d8004d01feff0001");
+    assert_eq!(client.request("m100,4"), "00c31302");
+    assert_eq!(client.request("p5"), "0001");
+    assert_eq!(client.request("p9"), "E01");
+
+    client.send("s");
+    assert_eq!(client.receive(), "S05");
+    assert_eq!(client.request("p5"), "0101");
+
+    assert_eq!(client.request("Z0,213,1"), "OK");
+    assert_eq!(client.request("Z2,c000,1"), "");
+    client.send("c");
+    assert_eq!(client.receive(), "S05");
+    assert_eq!(client.request("p5"), "1302");
+    assert_eq!(client.request("z0,213,1"), "OK");
+
+    assert_eq!(client.request("Mc000,3:abcdef"), "OK");
+    assert_eq!(client.request("mc000,3"), "abcdef");
+    assert_eq!(client.request("Mc000,2:ab"), "E01");
+    assert_eq!(client.request("P3=3412"), "OK");
+    assert_eq!(client.request("p3"), "3412");
+    // the lower bits of F are always cleared
+    assert_eq!(client.request("G0f01110022003300fdff1302"), "OK");
+    assert_eq!(client.request("g"), "0001110022003300fdff1302");
+    assert_eq!(client.request("P5=1302"), "OK");
+
+    assert_eq!(client.request("QStartNoAckMode"), "OK");
+    client.ack = false;
+    assert_eq!(client.request("qAttached"), "1");
+    assert_eq!(client.request("vMustReplyEmpty"), "");
+
+    // interrupt the free running execution
+    client.send("c");
+    thread::sleep(Duration::from_millis(50));
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.receive(), "S02");
+
+    assert_eq!(client.request("D"), "OK");
+}
+
+fn kill_script(address: SocketAddr) {
+    let mut client = Client::connect(address);
+    assert_eq!(client.request("Z0,213,1"), "OK");
+    client.send("c");
+    assert_eq!(client.receive(), "S05");
+    client.send("k");
+    // no reply : the stub closes the connection
+    assert_eq!(client.stream.read(&mut [0x00]).unwrap(), 0);
+}
+
+fn setup() -> (Cpu<MMU>, Debugger, GdbStub) {
+    let (_, mbc) = mbc::load_cartridge(Path::new(ROM_PATH), mbc::HeaderStrictness::Error)
+        .expect("test ROM loading error");
+    let mut cpu = Cpu::new(MMU::new(mbc, HardwareModel::DMG, true, None));
+    cpu.post_bios(HardwareModel::DMG);
+    let stub = GdbStub::bind("127.0.0.1:0").unwrap();
+    (cpu, Debugger::new(), stub)
+}
+
+#[test]
+fn test_gdb_stub_scripted_client() {
+    let (mut cpu, mut debugger, mut stub) = setup();
+    let address = stub.local_addr().unwrap();
+
+    let client = thread::spawn(move || script(address));
+    // the emulation loop, as run by the frontend, waiting for the client
+    let mut running = false;
+    let mut connected = false;
+    while !client.is_finished() {
+        stub.poll(&mut debugger, &mut cpu, &mut running);
+        connected |= stub.is_connected();
+        if running {
+            let (_, reason) = debugger.step(&mut cpu);
+            if reason.is_some() {
+                running = false;
+            }
+        }
+    }
+    client.join().unwrap();
+
+    assert!(connected);
+    // detached : the execution is resumed, without the client breakpoints
+    stub.poll(&mut debugger, &mut cpu, &mut running);
+    assert!(!stub.is_connected());
+    assert!(running);
+    assert_eq!(debugger.breakpoints().count(), 0);
+}
+
+#[test]
+fn test_gdb_stub_kill() {
+    let (mut cpu, mut debugger, mut stub) = setup();
+    let address = stub.local_addr().unwrap();
+
+    let client = thread::spawn(move || kill_script(address));
+    let mut running = false;
+    while !client.is_finished() {
+        stub.poll(&mut debugger, &mut cpu, &mut running);
+        if running {
+            let (_, reason) = debugger.step(&mut cpu);
+            if reason.is_some() {
+                running = false;
+            }
+        }
+    }
+    client.join().unwrap();
+
+    // killed : the execution stays stopped, without the client breakpoints
+    assert!(!stub.is_connected());
+    assert!(!running);
+    assert_eq!(cpu.regs.pc, 0x0213);
+    assert_eq!(debugger.breakpoints().count(), 0);
+}
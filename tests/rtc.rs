@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rustboylib::mbc::{self, HeaderStrictness, MBC, TimeSource};
+
+/// MBC3+TIMER+BATTERY.
+const MBC3_TIMER_BATTERY: u8 = 0x0F;
+
+/// Time source controlled by the test.
+struct TestTimeSource(Arc<AtomicU64>);
+
+impl TimeSource for TestTimeSource {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+fn make_rom() -> Vec<u8> {
+    let mut data = vec![0x00; 0x10000];
+    data[0x0147] = MBC3_TIMER_BATTERY;
+    data[0x0148] = 0x01; // 64KB
+    data
+}
+
+/// Latch the clock and read the given RTC register.
+fn read_rtc(mbc: &mut Box<dyn MBC + Send>, register: u8) -> u8 {
+    mbc.rom_control(0x6000, 0x00);
+    mbc.rom_control(0x6000, 0x01);
+    mbc.rom_control(0x4000, register);
+    mbc.ram_read(0xA000)
+}
+
+#[test]
+fn test_rtc_with_time_source() {
+    let time = Arc::new(AtomicU64::new(1_000_000));
+    let (info, mut mbc) = mbc::load_cartridge_from_bytes_with_time_source(
+        make_rom(),
+        HeaderStrictness::Warn,
+        Box::new(TestTimeSource(time.clone())),
+    )
+    .expect("test ROM loading error");
+    assert!(matches!(
+        info.cartridge_type,
+        mbc::CartridgeType::MBC3 { .. }
+    ));
+    mbc.rom_control(0x0000, 0x0A); // enable the RAM and RTC
+
+    assert_eq!(read_rtc(&mut mbc, 0x08), 0);
+    time.fetch_add(3 * 3600 + 2 * 60 + 15, Ordering::SeqCst);
+    assert_eq!(read_rtc(&mut mbc, 0x08), 15);
+    assert_eq!(read_rtc(&mut mbc, 0x09), 2);
+    assert_eq!(read_rtc(&mut mbc, 0x0A), 3);
+    time.fetch_add(2 * 86400, Ordering::SeqCst);
+    assert_eq!(read_rtc(&mut mbc, 0x0B), 2);
+}
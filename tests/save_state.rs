@@ -0,0 +1,91 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+
+use rustboylib::cpu::Cpu;
+use rustboylib::mbc;
+use rustboylib::mmu::MMU;
+use rustboylib::model::HardwareModel;
+use rustboylib::serial::SerialCallback;
+
+const ROM_PATH: &str = "tests/cpu_instrs/02-interrupts.gb";
+const OTHER_ROM_PATH: &str = "tests/cpu_instrs/01-special.gb";
+const CPU_STEPS: usize = 200_000;
+
+fn setup(rom_path: &str, model: HardwareModel) -> (Cpu<MMU>, Rc<RefCell<String>>) {
+    let serial_output = Rc::new(RefCell::new(String::new()));
+    let serial_output_mmu = serial_output.clone();
+    let serial_callback: SerialCallback =
+        Box::new(move |data: u8| serial_output_mmu.borrow_mut().push(data as char));
+
+    let (_, mbc) = mbc::load_cartridge(Path::new(rom_path), mbc::HeaderStrictness::Error)
+        .expect("test ROM loading error");
+    let mmu = MMU::new(mbc, model, true, Some(serial_callback));
+    let mut cpu = Cpu::new(mmu);
+    cpu.post_bios(model);
+    (cpu, serial_output)
+}
+
+fn run(cpu: &mut Cpu<MMU>, steps: usize) {
+    for _ in 0..steps {
+        cpu.step();
+    }
+}
+
+fn test_round_trip(model: HardwareModel) {
+    let (mut cpu, serial_output) = setup(ROM_PATH, model);
+    run(&mut cpu, CPU_STEPS);
+    let state = cpu.save_state();
+    let serial_at_save = serial_output.borrow().len();
+
+    run(&mut cpu, CPU_STEPS);
+    let expected_state = cpu.save_state();
+    let expected_serial = serial_output.borrow()[serial_at_save..].to_string();
+    let expected_frame = cpu.mem.frame_buffer();
+
+    // restore into the same machine
+    cpu.load_state(&state).unwrap();
+    assert_eq!(cpu.save_state(), state);
+    serial_output.borrow_mut().truncate(serial_at_save);
+    run(&mut cpu, CPU_STEPS);
+    assert!(cpu.save_state() == expected_state, "diverging emulation");
+    assert_eq!(serial_output.borrow()[serial_at_save..], expected_serial);
+    assert_eq!(cpu.mem.frame_buffer(), expected_frame);
+
+    // and into a freshly started one
+    let (mut cpu, _) = setup(ROM_PATH, model);
+    cpu.load_state(&state).unwrap();
+    run(&mut cpu, CPU_STEPS);
+    assert!(cpu.save_state() == expected_state, "diverging emulation");
+}
+
+#[test]
+fn test_save_state_round_trip_dmg() {
+    test_round_trip(HardwareModel::DMG);
+}
+
+#[test]
+fn test_save_state_round_trip_cgb() {
+    test_round_trip(HardwareModel::CGB);
+}
+
+#[test]
+fn test_save_state_rejected() {
+    let (mut cpu, _) = setup(ROM_PATH, HardwareModel::DMG);
+    run(&mut cpu, CPU_STEPS);
+    let state = cpu.save_state();
+
+    let (mut other_cpu, _) = setup(OTHER_ROM_PATH, HardwareModel::DMG);
+    assert!(other_cpu.load_state(&state).is_err());
+    let (mut cgb_cpu, _) = setup(ROM_PATH, HardwareModel::CGB);
+    assert!(cgb_cpu.load_state(&state).is_err());
+
+    // a failed load leaves the machine untouched
+    run(&mut cpu, CPU_STEPS);
+    let current_state = cpu.save_state();
+    assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+    let mut corrupted = state.clone();
+    corrupted[4] = 0xFF; // version
+    assert!(cpu.load_state(&corrupted).is_err());
+    assert!(cpu.save_state() == current_state);
+}
@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use rustboylib::cpu::Cpu;
+use rustboylib::mbc;
+use rustboylib::mmu::{MMU, MemoryManagementUnit};
+use rustboylib::model::HardwareModel;
+use rustboylib::trace::{TraceFormat, TraceTrigger, Tracer, diff_trace};
+
+/// 0100: NOP
+/// 0101: JP 0213
+const ROM_PATH: &str = "tests/cpu_instrs/01-special.gb";
+const CPU_STEPS: usize = 100_000;
+
+/// A trace output which can still be read once given to the CPU.
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl SharedOutput {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.borrow().clone())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(data)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn setup() -> Cpu<MMU> {
+    let (_, mbc) = mbc::load_cartridge(Path::new(ROM_PATH), mbc::HeaderStrictness::Error)
+        .expect("test ROM loading error");
+    let mut cpu = Cpu::new(MMU::new(mbc, HardwareModel::DMG, true, None));
+    cpu.post_bios(HardwareModel::DMG);
+    cpu
+}
+
+#[test]
+fn test_trace_doctor_format() {
+    let mut cpu = setup();
+    let output = SharedOutput::default();
+    cpu.set_tracer(Some(Tracer::new(
+        TraceFormat::Doctor,
+        Box::new(output.clone()),
+    )));
+    for _ in 0..3 {
+        cpu.step();
+    }
+    assert_eq!(
+        output.lines(),
+        [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,CE",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0213 PCMEM:21,00,40,C3",
+        ]
+    );
+}
+
+#[test]
+fn test_trace_disassembly_format() {
+    let mut cpu = setup();
+    let output = SharedOutput::default();
+    cpu.set_tracer(Some(Tracer::new(
+        TraceFormat::Disassembly,
+        Box::new(output.clone()),
+    )));
+    cpu.step();
+    cpu.step();
+    let lines = output.lines();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("OP=00 PC=0100 AF=01B0 BC=0013 DE=00D8 HL=014D SP=FFFE NOP"));
+    assert!(lines[0].ends_with(" CY=1"));
+    assert!(lines[1].contains("JP $0213"));
+    assert!(lines[1].ends_with(" CY=4"));
+}
+
+#[test]
+fn test_trace_triggers_and_switch() {
+    let mut cpu = setup();
+    let output = SharedOutput::default();
+    let tracer = Tracer::new(TraceFormat::Doctor, Box::new(output.clone()))
+        .start_at(TraceTrigger::Address(0x0213))
+        .stop_at(TraceTrigger::Frame(1));
+    cpu.set_tracer(Some(tracer));
+    while cpu.mem.frame_count() < 2 {
+        cpu.step();
+    }
+    let lines = output.lines();
+    assert!(lines[0].contains("PC:0213"));
+    assert!(cpu.tracer_mut().unwrap().is_stopped());
+    let traced = lines.len();
+
+    // switched off and on again : nothing more once stopped
+    cpu.tracer_mut().unwrap().set_enabled(false);
+    cpu.tracer_mut().unwrap().set_enabled(true);
+    for _ in 0..CPU_STEPS {
+        cpu.step();
+    }
+    assert_eq!(output.lines().len(), traced);
+
+    // switched off in the middle of the tracing
+    let mut cpu = setup();
+    let output = SharedOutput::default();
+    cpu.set_tracer(Some(Tracer::new(
+        TraceFormat::Doctor,
+        Box::new(output.clone()),
+    )));
+    cpu.step();
+    cpu.tracer_mut().unwrap().set_enabled(false);
+    for _ in 0..CPU_STEPS {
+        cpu.step();
+    }
+    assert_eq!(output.lines().len(), 1);
+    cpu.tracer_mut().unwrap().set_enabled(true);
+    cpu.step();
+    assert_eq!(output.lines().len(), 2);
+}
+
+/// Trace the given number of CPU steps from the start.
+fn reference_trace(format: TraceFormat, steps: usize) -> Vec<String> {
+    let mut cpu = setup();
+    let output = SharedOutput::default();
+    cpu.set_tracer(Some(Tracer::new(format, Box::new(output.clone()))));
+    for _ in 0..steps {
+        cpu.step();
+    }
+    cpu.set_tracer(None);
+    output.lines()
+}
+
+#[test]
+fn test_trace_diff() {
+    for format in [TraceFormat::Doctor, TraceFormat::Disassembly] {
+        let reference = reference_trace(format, CPU_STEPS);
+        let diff = diff_trace(&mut setup(), format, reference.join("\n").as_bytes(), 5).unwrap();
+        assert_eq!(diff, None);
+    }
+
+    let mut reference = reference_trace(TraceFormat::Doctor, CPU_STEPS);
+    let expected = reference[50_000].replace("SP:", "SP:0");
+    reference[50_000] = expected.clone();
+    let mismatch = diff_trace(
+        &mut setup(),
+        TraceFormat::Doctor,
+        reference.join("\r\n").as_bytes(),
+        5,
+    )
+    .unwrap()
+    .expect("no mismatch found");
+    assert_eq!(mismatch.number, 50_001);
+    assert_eq!(mismatch.expected, expected);
+    assert_eq!(mismatch.mismatching_fields(), ["SP"]);
+    let actual = mismatch.actual.as_ref().unwrap();
+    assert_eq!(actual.number, 50_001);
+    assert!(actual.instruction.is_some());
+    let numbers: Vec<usize> = mismatch
+        .before
+        .iter()
+        .chain(mismatch.after.iter())
+        .map(|line| line.number)
+        .collect();
+    assert_eq!(
+        numbers,
+        [
+            49_996, 49_997, 49_998, 49_999, 50_000, 50_002, 50_003, 50_004, 50_005, 50_006
+        ]
+    );
+    assert_eq!(mismatch.before[4].text, reference[49_999]);
+    assert_eq!(mismatch.after[0].text, reference[50_001]);
+}